@@ -0,0 +1,140 @@
+//! Disassembler. For code produced by `translate`, assembling the output
+//! again gives identical bytes.
+use crate::asm::parse::Operand;
+use crate::asm::{Op, Operation, Program, SymbolKind, OP_SIZE};
+use crate::rt::memory::DATA_BASE;
+use std::fmt::Write;
+
+fn take_32(buf: &[u8]) -> u32 {
+    buf.iter().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+/// Decode `code` into operations. Jumps use literal offsets. A trailing
+/// partial instruction decodes as `IGL`.
+pub fn disassemble(code: &[u8]) -> Vec<Operation> {
+    decode(code, &[], |_| None)
+}
+
+/// Decode the code of `program`, naming jump targets and native functions
+/// and tagging operations with its code labels
+pub fn disassemble_program(program: &Program) -> Vec<Operation> {
+    decode(&program.code, &program.natives, |offset| {
+        program.label_at(offset)
+    })
+}
+
+/// Natives missing from `natives` show as `#index`
+fn decode<'a>(
+    code: &[u8],
+    natives: &[String],
+    label: impl Fn(usize) -> Option<&'a str>,
+) -> Vec<Operation> {
+    code.chunks(OP_SIZE)
+        .enumerate()
+        .map(|(i, buf)| {
+            let offset = i * OP_SIZE;
+            let tag = label(offset).map(|s| s.to_owned());
+            if buf.len() < OP_SIZE {
+                return Operation {
+                    tag,
+                    code: Op::IGL,
+                    args: vec![],
+                    file: None,
+                    line: 0,
+                };
+            }
+            let code = Op::from(take_32(&buf[..2]) as u16);
+            let imm = take_32(&buf[4..]);
+            let mut registers = buf[2..].iter();
+            let args = code
+                .operands()
+                .iter()
+                .map(|kind| match kind {
+                    Operand::Register => format!("$r{}", registers.next().unwrap()),
+                    Operand::Immediate if code == Op::Set => (imm as i32).to_string(),
+                    Operand::Immediate => imm.to_string(),
+                    Operand::Name => match natives.get(imm as usize) {
+                        Some(name) => name.clone(),
+                        None => format!("#{}", imm),
+                    },
+                    Operand::Target => {
+                        let target = if code.is_relative_jump() {
+                            offset as i64 + imm as i32 as i64
+                        } else {
+                            imm as i64
+                        };
+                        match label(target as usize).filter(|_| target >= 0) {
+                            Some(name) => name.to_owned(),
+                            None if code.is_relative_jump() => (imm as i32).to_string(),
+                            None => imm.to_string(),
+                        }
+                    }
+                })
+                .collect();
+            Operation {
+                tag,
+                code,
+                args,
+                file: None,
+                line: 0,
+            }
+        })
+        .collect()
+}
+
+/// An assembly listing of `program`: data as `.byte` rows, then each
+/// operation with its offset and source location in a comment
+pub fn listing(program: &Program) -> String {
+    let mut out = String::new();
+    if !program.data.is_empty() {
+        out.push_str(".data\n");
+        let labels = program
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Data)
+            .collect::<Vec<_>>();
+        let mut row = vec![];
+        for (i, byte) in program.data.iter().enumerate() {
+            let addr = (DATA_BASE + i) as u64;
+            let names = labels
+                .iter()
+                .filter(|s| s.value == addr)
+                .collect::<Vec<_>>();
+            // each label starts a new row
+            if !names.is_empty() || row.len() == 16 {
+                flush_row(&mut out, &mut row);
+            }
+            for s in names {
+                writeln!(out, "{}:", s.name).unwrap();
+            }
+            row.push(byte.to_string());
+        }
+        flush_row(&mut out, &mut row);
+        let end = (DATA_BASE + program.data.len()) as u64;
+        for s in labels.iter().filter(|s| s.value == end) {
+            writeln!(out, "{}:", s.name).unwrap();
+        }
+        out.push_str(".code\n");
+    }
+    for (i, operation) in disassemble_program(program).iter().enumerate() {
+        let offset = i * OP_SIZE;
+        let label = match operation.tag {
+            Some(ref tag) => format!("{}:", tag),
+            None => String::new(),
+        };
+        let text = format!("{:<11} {}", label, operation);
+        write!(out, "{:<40}; {:#06x}", text, offset).unwrap();
+        if let Some(line) = program.line_at(offset) {
+            write!(out, "  {}", line).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn flush_row(out: &mut String, row: &mut Vec<String>) {
+    if !row.is_empty() {
+        writeln!(out, "            .byte {}", row.join(", ")).unwrap();
+        row.clear();
+    }
+}
@@ -1,22 +1,199 @@
+//! Assembler for the rok VM.
+//!
+//! Every instruction is `OP_SIZE` bytes: a big-endian `u16` opcode followed
+//! by six operand bytes. Register operands take one byte each, starting at
+//! byte 2. Jump targets and immediates are 32 bits in bytes 4..8, big-endian.
+//!
+//! `SET dest imm` sign-extends its 32-bit immediate into `dest`, and
+//! `SETHI dest imm` replaces the high 32 bits of `dest`. The assembler accepts
+//! any signed or unsigned 64-bit value for `SET` and emits a `SET`/`SETHI`
+//! pair when it does not fit in 32 signed bits.
+//!
+//! `SPAWN`, `SEND`, `RECV` and `PID` start and talk to processes, see
+//! `rt::sched`. `SYSCALL n` does I/O through the host, see `rt::sys`.
+//! `CALLN name` calls a native function registered with the runtime; its
+//! immediate indexes the program's table of native names.
+//!
+//! The operations starting with `T` work on tagged values, see `rt::value`.
+//! `TADD`, `TSUB`, `TMUL`, `TDIV` and `TMOD` take `a b dest` like `ADD`,
+//! `TNEG` takes `src dest` and `TEQ`, `TLT`, `TGT`, `TLTE` and `TGTE` set
+//! the condition like `EQ`. `TTEST a` sets it when `a` is truthy. `TBOX`
+//! and `TUNBOX` convert `src dest` between raw and tagged integers, and
+//! `TNUM addr dest` and `TSTR addr dest` make a number or a string from the
+//! string at `addr`. `TLIST dest` makes an empty list, `TPUSH list val`
+//! appends to it, `TGET list index dest` reads it and `TLEN val dest` gives
+//! the length of a list or string. `GC` collects the garbage among the
+//! objects they refer to, see `rt::heap`.
+//!
+//! Data from `.data` sections is kept apart from the code and placed in VM
+//! memory at `DATA_BASE` when the program is loaded.
 use crate::errors::Result;
-use std::process::id;
+use parse::{at_line, immediate, register};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{fmt, path};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+mod disasm;
+pub mod object;
+mod parse;
+
+pub use disasm::{disassemble, disassemble_program, listing};
+pub use parse::{parse, parse_file, Assembly};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Op {
     HLT,
     IGL,
     Add,
     Sub,
     Set,
+    SetHi,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+    Inc,
+    Dec,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
     Reg,
+    Jmp,
+    Jeq,
+    Jne,
+    Jmpr,
+    Jeqr,
+    Jner,
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+    Cmov,
+    Load8,
+    Load16,
+    Load32,
+    Load64,
+    Store8,
+    Store16,
+    Store32,
+    Store64,
+    Alloc,
+    Free,
+    Push,
+    Pop,
+    Call,
+    Ret,
+    Ldsp,
+    Ldfp,
+    Spawn,
+    Send,
+    Recv,
+    Pid,
+    Syscall,
+    Calln,
+    Tadd,
+    Tsub,
+    Tmul,
+    Tdiv,
+    Tmod,
+    Tneg,
+    Teq,
+    Tlt,
+    Tgt,
+    Tlte,
+    Tgte,
+    Ttest,
+    Tbox,
+    Tunbox,
+    Tnum,
+    Tstr,
+    Tlist,
+    Tpush,
+    Tget,
+    Tlen,
+    Gc,
 }
 impl Op {
-    fn to_parts(&self) -> [u8; 2] {
-        match *self {
+    fn to_parts(self) -> [u8; 2] {
+        match self {
             Op::HLT => [0, 0],
             Op::Add => [0, 1],
             Op::Sub => [0, 2],
             Op::Set => [0, 3],
+            Op::SetHi => [0, 16],
+            Op::Mul => [0, 4],
+            Op::Div => [0, 5],
+            Op::Mod => [0, 6],
+            Op::Neg => [0, 7],
+            Op::Inc => [0, 8],
+            Op::Dec => [0, 9],
+            Op::And => [0, 30],
+            Op::Or => [0, 31],
+            Op::Xor => [0, 32],
+            Op::Not => [0, 33],
+            Op::Shl => [0, 34],
+            Op::Shr => [0, 35],
+            Op::Jmp => [0, 10],
+            Op::Jeq => [0, 11],
+            Op::Jne => [0, 12],
+            Op::Jmpr => [0, 13],
+            Op::Jeqr => [0, 14],
+            Op::Jner => [0, 15],
+            Op::Eq => [0, 20],
+            Op::Neq => [0, 21],
+            Op::Gt => [0, 22],
+            Op::Lt => [0, 23],
+            Op::Gte => [0, 24],
+            Op::Lte => [0, 25],
+            Op::Cmov => [0, 26],
+            Op::Load8 => [0, 40],
+            Op::Load16 => [0, 41],
+            Op::Load32 => [0, 42],
+            Op::Load64 => [0, 43],
+            Op::Store8 => [0, 44],
+            Op::Store16 => [0, 45],
+            Op::Store32 => [0, 46],
+            Op::Store64 => [0, 47],
+            Op::Alloc => [0, 48],
+            Op::Free => [0, 49],
+            Op::Push => [0, 50],
+            Op::Pop => [0, 51],
+            Op::Call => [0, 52],
+            Op::Ret => [0, 53],
+            Op::Ldsp => [0, 54],
+            Op::Ldfp => [0, 55],
+            Op::Spawn => [0, 60],
+            Op::Send => [0, 61],
+            Op::Recv => [0, 62],
+            Op::Pid => [0, 63],
+            Op::Syscall => [0, 70],
+            Op::Calln => [0, 71],
+            Op::Tadd => [0, 80],
+            Op::Tsub => [0, 81],
+            Op::Tmul => [0, 82],
+            Op::Tdiv => [0, 83],
+            Op::Tmod => [0, 84],
+            Op::Tneg => [0, 85],
+            Op::Teq => [0, 86],
+            Op::Tlt => [0, 87],
+            Op::Tgt => [0, 88],
+            Op::Tlte => [0, 89],
+            Op::Tgte => [0, 90],
+            Op::Ttest => [0, 91],
+            Op::Tbox => [0, 92],
+            Op::Tunbox => [0, 93],
+            Op::Tnum => [0, 94],
+            Op::Tstr => [0, 95],
+            Op::Tlist => [0, 110],
+            Op::Tpush => [0, 111],
+            Op::Tget => [0, 112],
+            Op::Tlen => [0, 113],
+            Op::Gc => [0, 120],
             Op::Reg => [0, 100],
             _ => [0, 99],
         }
@@ -29,6 +206,75 @@ impl From<u16> for Op {
             1 => Op::Add,
             2 => Op::Sub,
             3 => Op::Set,
+            4 => Op::Mul,
+            5 => Op::Div,
+            6 => Op::Mod,
+            7 => Op::Neg,
+            8 => Op::Inc,
+            9 => Op::Dec,
+            10 => Op::Jmp,
+            11 => Op::Jeq,
+            12 => Op::Jne,
+            13 => Op::Jmpr,
+            14 => Op::Jeqr,
+            15 => Op::Jner,
+            16 => Op::SetHi,
+            20 => Op::Eq,
+            21 => Op::Neq,
+            22 => Op::Gt,
+            23 => Op::Lt,
+            24 => Op::Gte,
+            25 => Op::Lte,
+            26 => Op::Cmov,
+            30 => Op::And,
+            31 => Op::Or,
+            32 => Op::Xor,
+            33 => Op::Not,
+            34 => Op::Shl,
+            35 => Op::Shr,
+            40 => Op::Load8,
+            41 => Op::Load16,
+            42 => Op::Load32,
+            43 => Op::Load64,
+            44 => Op::Store8,
+            45 => Op::Store16,
+            46 => Op::Store32,
+            47 => Op::Store64,
+            48 => Op::Alloc,
+            49 => Op::Free,
+            50 => Op::Push,
+            51 => Op::Pop,
+            52 => Op::Call,
+            53 => Op::Ret,
+            54 => Op::Ldsp,
+            55 => Op::Ldfp,
+            60 => Op::Spawn,
+            61 => Op::Send,
+            62 => Op::Recv,
+            63 => Op::Pid,
+            70 => Op::Syscall,
+            71 => Op::Calln,
+            80 => Op::Tadd,
+            81 => Op::Tsub,
+            82 => Op::Tmul,
+            83 => Op::Tdiv,
+            84 => Op::Tmod,
+            85 => Op::Tneg,
+            86 => Op::Teq,
+            87 => Op::Tlt,
+            88 => Op::Tgt,
+            89 => Op::Tlte,
+            90 => Op::Tgte,
+            91 => Op::Ttest,
+            92 => Op::Tbox,
+            93 => Op::Tunbox,
+            94 => Op::Tnum,
+            95 => Op::Tstr,
+            110 => Op::Tlist,
+            111 => Op::Tpush,
+            112 => Op::Tget,
+            113 => Op::Tlen,
+            120 => Op::Gc,
             100 => Op::Reg,
             _ => Op::IGL,
         }
@@ -41,42 +287,330 @@ impl<'a> From<&'a str> for Op {
             "ADD" => Op::Add,
             "SUB" => Op::Sub,
             "SET" => Op::Set,
+            "SETHI" => Op::SetHi,
+            "MUL" => Op::Mul,
+            "DIV" => Op::Div,
+            "MOD" => Op::Mod,
+            "NEG" => Op::Neg,
+            "INC" => Op::Inc,
+            "DEC" => Op::Dec,
+            "AND" => Op::And,
+            "OR" => Op::Or,
+            "XOR" => Op::Xor,
+            "NOT" => Op::Not,
+            "SHL" => Op::Shl,
+            "SHR" => Op::Shr,
+            "JMP" => Op::Jmp,
+            "JEQ" => Op::Jeq,
+            "JNE" => Op::Jne,
+            "JMPR" => Op::Jmpr,
+            "JEQR" => Op::Jeqr,
+            "JNER" => Op::Jner,
+            "EQ" => Op::Eq,
+            "NEQ" => Op::Neq,
+            "GT" => Op::Gt,
+            "LT" => Op::Lt,
+            "GTE" => Op::Gte,
+            "LTE" => Op::Lte,
+            "CMOV" => Op::Cmov,
+            "LOAD8" => Op::Load8,
+            "LOAD16" => Op::Load16,
+            "LOAD32" => Op::Load32,
+            "LOAD64" => Op::Load64,
+            "STORE8" => Op::Store8,
+            "STORE16" => Op::Store16,
+            "STORE32" => Op::Store32,
+            "STORE64" => Op::Store64,
+            "ALLOC" => Op::Alloc,
+            "FREE" => Op::Free,
+            "PUSH" => Op::Push,
+            "POP" => Op::Pop,
+            "CALL" => Op::Call,
+            "RET" => Op::Ret,
+            "LDSP" => Op::Ldsp,
+            "LDFP" => Op::Ldfp,
+            "SPAWN" => Op::Spawn,
+            "SEND" => Op::Send,
+            "RECV" => Op::Recv,
+            "PID" => Op::Pid,
+            "SYSCALL" => Op::Syscall,
+            "CALLN" => Op::Calln,
+            "TADD" => Op::Tadd,
+            "TSUB" => Op::Tsub,
+            "TMUL" => Op::Tmul,
+            "TDIV" => Op::Tdiv,
+            "TMOD" => Op::Tmod,
+            "TNEG" => Op::Tneg,
+            "TEQ" => Op::Teq,
+            "TLT" => Op::Tlt,
+            "TGT" => Op::Tgt,
+            "TLTE" => Op::Tlte,
+            "TGTE" => Op::Tgte,
+            "TTEST" => Op::Ttest,
+            "TBOX" => Op::Tbox,
+            "TUNBOX" => Op::Tunbox,
+            "TNUM" => Op::Tnum,
+            "TSTR" => Op::Tstr,
+            "TLIST" => Op::Tlist,
+            "TPUSH" => Op::Tpush,
+            "TGET" => Op::Tget,
+            "TLEN" => Op::Tlen,
+            "GC" => Op::Gc,
             "REG" => Op::Reg,
             _ => Op::IGL,
         }
     }
 }
 
+/// Mnemonics are the upper-cased variant names
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+/// Size in bytes of every encoded instruction
+pub const OP_SIZE: usize = 8;
+
 #[derive(Clone, Debug)]
 pub struct Operation {
+    /// Label defined at this operation, e.g. `loop:`
     tag: Option<String>,
     code: Op,
     args: Vec<String>,
+    /// Source file, for operations from a file or an `.include`
+    file: Option<Rc<str>>,
+    /// Source line, starting at 1
+    line: usize,
 }
 
-pub fn parse(s: &str) -> Result<Vec<Operation>> {
-    let mut ops = vec![];
-    for line in s.trim().lines() {
-        let idents = line
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        if idents.is_empty() {
-            continue;
-        }
-        let op = Op::from(idents[0].as_str());
-        let args = if idents.len() > 1 {
-            idents[1..].to_vec()
-        } else {
-            vec![]
-        };
-        ops.push(Operation {
+impl Operation {
+    /// An operation built in code rather than parsed, e.g. by a compiler.
+    /// Operands are written as in assembly source: `r1`, `42`, `label`.
+    pub fn new(code: Op, args: Vec<String>) -> Self {
+        Self {
             tag: None,
-            code: op,
+            code,
             args,
-        });
+            file: None,
+            line: 0,
+        }
+    }
+
+    /// Define the label `tag` at this operation
+    pub fn with_tag<T: Into<String>>(mut self, tag: T) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn code(&self) -> Op {
+        self.code
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+}
+/// Assembly syntax, without the label
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// An assembled program
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub code: Vec<u8>,
+    /// Initial contents of memory from `DATA_BASE`
+    pub data: Vec<u8>,
+    /// Labels, code labels first, each kind sorted by value
+    pub symbols: Vec<Symbol>,
+    /// Source location of each operation, sorted by offset
+    pub lines: Vec<LineInfo>,
+    /// Names of the native functions called by `CALLN`, by index
+    pub natives: Vec<String>,
+}
+impl Program {
+    /// Code label at `offset`, if any
+    pub fn label_at(&self, offset: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|s| s.kind == SymbolKind::Code && s.value == offset as u64)
+            .map(|s| s.name.as_str())
+    }
+
+    /// Source location of the operation at `offset`, if known
+    pub fn line_at(&self, offset: usize) -> Option<&LineInfo> {
+        self.lines
+            .binary_search_by_key(&offset, |l| l.offset)
+            .ok()
+            .map(|i| &self.lines[i])
+    }
+}
+impl From<Vec<u8>> for Program {
+    fn from(code: Vec<u8>) -> Self {
+        Self {
+            code,
+            ..Self::default()
+        }
+    }
+}
+impl<'a> From<&'a [u8]> for Program {
+    fn from(code: &'a [u8]) -> Self {
+        code.to_vec().into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    /// Byte offset into the code
+    Code,
+    /// Address in VM memory
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineInfo {
+    pub offset: usize,
+    pub file: Option<String>,
+    pub line: usize,
+}
+impl fmt::Display for LineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// Parse and translate assembly source
+pub fn assemble(s: &str) -> Result<Program> {
+    translate(&parse(s)?)
+}
+
+/// Parse and translate an assembly file
+pub fn assemble_file<P: AsRef<path::Path>>(path: P) -> Result<Program> {
+    translate(&parse_file(path)?)
+}
+
+/// Whether `SET` needs a `SETHI` to load `val`
+fn is_wide(val: i64) -> bool {
+    val != val as i32 as i64
+}
+
+/// Encoded size in bytes of an operation. Labels used as immediates are
+/// addresses, which always fit a single `SET`.
+fn encoded_len(operation: &Operation) -> usize {
+    match operation.code {
+        Op::Set => match operation.args.get(1).map(|a| immediate(a)) {
+            Some(Ok(val)) if is_wide(val) => 2 * OP_SIZE,
+            _ => OP_SIZE,
+        },
+        _ => OP_SIZE,
+    }
+}
+
+struct Labels<'a> {
+    /// Code labels: name -> byte offset
+    code: HashMap<String, usize>,
+    /// Data labels: name -> address
+    data: &'a HashMap<String, usize>,
+    /// Native functions called by `CALLN`, in order of first use
+    natives: Vec<String>,
+}
+impl<'a> Labels<'a> {
+    /// First assembler pass: map each label to the byte offset of its operation
+    fn resolve(asm: &'a Assembly) -> Result<Self> {
+        let mut code = HashMap::new();
+        let mut natives = vec![];
+        let mut offset = 0;
+        for operation in &asm.ops {
+            if operation.code == Op::Calln && !natives.contains(&operation.args[0]) {
+                natives.push(operation.args[0].clone());
+            }
+            if let Some(ref tag) = operation.tag {
+                if code.insert(tag.clone(), offset).is_some() || asm.data_labels.contains_key(tag) {
+                    return Err(at_line(
+                        operation.file.as_deref(),
+                        operation.line,
+                        se!("duplicate label: {}", tag).into(),
+                    ));
+                }
+            }
+            offset += encoded_len(operation);
+        }
+        Ok(Self {
+            code,
+            data: &asm.data_labels,
+            natives,
+        })
+    }
+
+    fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = self
+            .code
+            .iter()
+            .map(|(name, &offset)| (SymbolKind::Code, name, offset))
+            .chain(
+                self.data
+                    .iter()
+                    .map(|(name, &addr)| (SymbolKind::Data, name, addr)),
+            )
+            .map(|(kind, name, value)| Symbol {
+                name: name.clone(),
+                kind,
+                value: value as u64,
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by(|a, b| (a.kind, a.value, &a.name).cmp(&(b.kind, b.value, &b.name)));
+        symbols
+    }
+
+    /// Resolve a jump operand, either a code label or a literal byte offset
+    fn jump_target(&self, arg: &str) -> Result<usize> {
+        match self.code.get(arg) {
+            Some(offset) => Ok(*offset),
+            None if self.data.contains_key(arg) => {
+                Err(se!("cannot jump to data label: {}", arg).into())
+            }
+            None => match immediate(arg) {
+                Ok(offset) => Ok(offset as usize),
+                Err(_) => Err(se!("undefined label: {}", arg).into()),
+            },
+        }
+    }
+
+    /// Resolve an immediate operand: a literal, or the address of a label
+    fn immediate(&self, arg: &str) -> Result<i64> {
+        match self.code.get(arg).or_else(|| self.data.get(arg)) {
+            Some(&addr) => Ok(addr as i64),
+            None => immediate(arg).map_err(|_| se!("undefined label: {}", arg).into()),
+        }
     }
-    Ok(ops)
+}
+
+fn pack_32(buf: &mut [u8], val: u32) {
+    buf[4] = (val >> 24) as u8;
+    buf[5] = (val >> 16) as u8;
+    buf[6] = (val >> 8) as u8;
+    buf[7] = val as u8;
 }
 
 fn pack(program: &mut Vec<u8>, new: &[u8], count: usize) {
@@ -86,63 +620,329 @@ fn pack(program: &mut Vec<u8>, new: &[u8], count: usize) {
     program.extend_from_slice(&ZERO[0..remainder]);
 }
 
-pub fn translate(ops: &[Operation]) -> Result<Vec<u8>> {
-    let mut prog = vec![];
-    let mut buf = vec![0; 8];
-    for operation in ops {
-        let code = &operation.code;
-        let code_buf = code.to_parts();
-        match code {
-            Op::HLT => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                pack(&mut prog, &buf, 2);
-            }
-            Op::Reg => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                pack(&mut prog, &buf, 2);
-            }
-            Op::Set => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                let dest = operation.args[0].parse::<u8>()?;
-                let val = operation.args[1].parse::<u32>()?;
-                buf[2] = dest;
-                buf[4] = (val >> 24) as u8;
-                buf[5] = (val >> 16) as u8;
-                buf[6] = (val >> 8) as u8;
-                buf[7] = val as u8;
-                pack(&mut prog, &buf, 8);
-            }
-            Op::Add => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                let a = operation.args[0].parse::<u8>()?;
-                let b = operation.args[1].parse::<u8>()?;
-                let c = operation.args[2].parse::<u8>()?;
-                buf[2] = a;
-                buf[3] = b;
-                buf[4] = c;
-                pack(&mut prog, &buf, 5);
-            }
-            Op::Sub => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                let a = operation.args[0].parse::<u8>()?;
-                let b = operation.args[1].parse::<u8>()?;
-                let c = operation.args[2].parse::<u8>()?;
-                buf[2] = a;
-                buf[3] = b;
-                buf[4] = c;
-                pack(&mut prog, &buf, 5);
+pub fn translate(asm: &Assembly) -> Result<Program> {
+    let labels = Labels::resolve(asm)?;
+    let mut code = vec![];
+    let mut lines = vec![];
+    for operation in &asm.ops {
+        lines.push(LineInfo {
+            offset: code.len(),
+            file: operation.file.as_deref().map(|f| f.to_owned()),
+            line: operation.line,
+        });
+        encode(operation, &labels, &mut code)
+            .map_err(|e| at_line(operation.file.as_deref(), operation.line, e))?;
+    }
+    Ok(Program {
+        code,
+        data: asm.data.clone(),
+        symbols: labels.symbols(),
+        lines,
+        natives: labels.natives,
+    })
+}
+
+/// Append the encoding of `operation` to `prog`
+fn encode(operation: &Operation, labels: &Labels, prog: &mut Vec<u8>) -> Result<()> {
+    let mut buf = [0; OP_SIZE];
+    let code = &operation.code;
+    let code_buf = code.to_parts();
+    match code {
+        Op::HLT => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            pack(prog, &buf, 2);
+        }
+        Op::Reg | Op::Ret | Op::Gc => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            pack(prog, &buf, 2);
+        }
+        Op::Set => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let dest = register(&operation.args[0])?;
+            let val = labels.immediate(&operation.args[1])?;
+            if is_wide(val) && immediate(&operation.args[1]).is_err() {
+                return Err(se!("label address too large: {}", operation.args[1]).into());
             }
-            _ => {
-                buf[0] = code_buf[0];
-                buf[1] = code_buf[1];
-                pack(&mut prog, &buf, 2);
+            buf[2] = dest;
+            pack_32(&mut buf, val as u32);
+            pack(prog, &buf, 8);
+            if is_wide(val) {
+                let [hi0, hi1] = Op::SetHi.to_parts();
+                buf[0] = hi0;
+                buf[1] = hi1;
+                pack_32(&mut buf, (val >> 32) as u32);
+                pack(prog, &buf, 8);
             }
-        };
+        }
+        Op::SetHi => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            buf[2] = register(&operation.args[0])?;
+            let val = labels.immediate(&operation.args[1])?;
+            pack_32(&mut buf, val as u32);
+            pack(prog, &buf, 8);
+        }
+        Op::Calln => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let name = &operation.args[0];
+            let index = labels.natives.iter().position(|n| n == name).unwrap();
+            pack_32(&mut buf, index as u32);
+            pack(prog, &buf, 8);
+        }
+        Op::Syscall => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let val = labels.immediate(&operation.args[0])?;
+            pack_32(&mut buf, val as u32);
+            pack(prog, &buf, 8);
+        }
+        Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Mod
+        | Op::And
+        | Op::Or
+        | Op::Xor
+        | Op::Shl
+        | Op::Shr
+        | Op::Tadd
+        | Op::Tsub
+        | Op::Tmul
+        | Op::Tdiv
+        | Op::Tmod
+        | Op::Tget => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let a = register(&operation.args[0])?;
+            let b = register(&operation.args[1])?;
+            let c = register(&operation.args[2])?;
+            buf[2] = a;
+            buf[3] = b;
+            buf[4] = c;
+            pack(prog, &buf, 5);
+        }
+        Op::Inc
+        | Op::Dec
+        | Op::Free
+        | Op::Push
+        | Op::Pop
+        | Op::Ldsp
+        | Op::Ldfp
+        | Op::Recv
+        | Op::Pid
+        | Op::Ttest
+        | Op::Tlist => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            buf[2] = register(&operation.args[0])?;
+            pack(prog, &buf, 3);
+        }
+        Op::Jmp | Op::Jeq | Op::Jne | Op::Call => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let target = labels.jump_target(&operation.args[0])?;
+            pack_32(&mut buf, target as u32);
+            pack(prog, &buf, 8);
+        }
+        Op::Spawn => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            buf[2] = register(&operation.args[0])?;
+            let target = labels.jump_target(&operation.args[1])?;
+            pack_32(&mut buf, target as u32);
+            pack(prog, &buf, 8);
+        }
+        Op::Eq
+        | Op::Neq
+        | Op::Gt
+        | Op::Lt
+        | Op::Gte
+        | Op::Lte
+        | Op::Cmov
+        | Op::Not
+        | Op::Neg
+        | Op::Load8
+        | Op::Load16
+        | Op::Load32
+        | Op::Load64
+        | Op::Store8
+        | Op::Store16
+        | Op::Store32
+        | Op::Store64
+        | Op::Alloc
+        | Op::Send
+        | Op::Tneg
+        | Op::Teq
+        | Op::Tlt
+        | Op::Tgt
+        | Op::Tlte
+        | Op::Tgte
+        | Op::Tbox
+        | Op::Tunbox
+        | Op::Tnum
+        | Op::Tstr
+        | Op::Tpush
+        | Op::Tlen => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let a = register(&operation.args[0])?;
+            let b = register(&operation.args[1])?;
+            buf[2] = a;
+            buf[3] = b;
+            pack(prog, &buf, 4);
+        }
+        Op::Jmpr | Op::Jeqr | Op::Jner => {
+            // offsets are relative to the start of the jump
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            let arg = &operation.args[0];
+            let offset = match labels.code.get(arg.as_str()) {
+                Some(target) => *target as i64 - prog.len() as i64,
+                None => labels.jump_target(arg)? as i64,
+            };
+            pack_32(&mut buf, offset as i32 as u32);
+            pack(prog, &buf, 8);
+        }
+        _ => {
+            buf[0] = code_buf[0];
+            buf[1] = code_buf[1];
+            pack(prog, &buf, 2);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(src: &str) -> String {
+        parse(src)
+            .and_then(|ops| translate(&ops))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn register_syntax() {
+        let a = translate(&parse("ADD $r1, r2, $3\nINC 63").unwrap()).unwrap();
+        let b = translate(&parse("ADD 1 2 3\nINC $r63").unwrap()).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn immediates() {
+        assert_eq!(immediate("42").unwrap(), 42);
+        assert_eq!(immediate("-42").unwrap(), -42);
+        assert_eq!(immediate("0xff").unwrap(), 255);
+        assert_eq!(immediate("-0x10").unwrap(), -16);
+        assert_eq!(immediate("'a'").unwrap(), 97);
+        assert_eq!(immediate("'\\n'").unwrap(), 10);
+        assert_eq!(immediate("-9223372036854775808").unwrap(), i64::MIN);
+        assert!(immediate("-9223372036854775809").is_err());
+    }
+
+    #[test]
+    fn comments() {
+        let asm = parse("; header\nSET 0 ';' # trailing\n# done").unwrap();
+        assert_eq!(asm.ops().len(), 1);
+        assert_eq!(asm.ops()[0].args, vec!["0", "';'"]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert!(error("HLT\nADD 0 1").contains("line 2: ADD expects 3 operands"));
+        assert!(error("INC r64").contains("line 1: register out of range"));
+        assert!(error("\n\nBOGUS").contains("line 3: unknown instruction"));
+        assert!(error("HLT\nJMP nowhere").contains("line 2: undefined label"));
+    }
+
+    #[test]
+    fn labels() {
+        assert!(error("a: HLT\nb: HLT\na: HLT").contains("line 3: duplicate label: a"));
+        assert!(error(".data\nx: .byte 1\n.code\nx: HLT").contains("duplicate label: x"));
+        assert!(error("JEQ later").contains("line 1: undefined label: later"));
+        assert!(error("JNER back").contains("line 1: undefined label: back"));
+        // labels are byte offsets, and may come before or after their use
+        let program = assemble("JMP end\nstart: INC r0\nend: JMP start").unwrap();
+        let expected = assemble("JMP 16\nINC r0\nJMP 8").unwrap();
+        assert_eq!(program.code, expected.code);
+        // relative jumps are from the jumping operation
+        let program = assemble("top: HLT\nJEQR top\nJNER end\nend: HLT").unwrap();
+        let expected = assemble("HLT\nJEQR -8\nJNER 8\nHLT").unwrap();
+        assert_eq!(program.code, expected.code);
+    }
+
+    #[test]
+    fn data_directives() {
+        let program = assemble(concat!(
+            ".equ COUNT 2\n",
+            ".data\n",
+            "s: .asciz \"a;b\\n\"\n",
+            "   .align 8\n",
+            "n: .word COUNT, -1\n",
+            "b: .byte 'x', 255, -1\n",
+            "p: .word s\n",
+            ".code\n",
+            "SET r0 n\n",
+        ))
+        .unwrap();
+        let mut data = b"a;b\n\0".to_vec();
+        data.resize(8, 0);
+        data.extend_from_slice(&2i64.to_be_bytes());
+        data.extend_from_slice(&(-1i64).to_be_bytes());
+        data.extend_from_slice(&[b'x', 255, 255]);
+        data.extend_from_slice(&8i64.to_be_bytes());
+        assert_eq!(program.data, data);
+        let expected = assemble("SET r0 16").unwrap();
+        assert_eq!(program.code, expected.code);
+    }
+
+    #[test]
+    fn directive_errors() {
+        assert!(error(".byte 1").contains("line 1: .byte outside of .data"));
+        assert!(error(".data\nHLT").contains("line 2: instruction HLT outside of .code"));
+        assert!(error(".data\n.byte 256").contains("line 2: byte out of range"));
+        assert!(error(".data\n.align 3").contains("power of two"));
+        assert!(error(".data\nx: .byte 1\n.code\nJMP x").contains("cannot jump to data label"));
+        assert!(error(".include \"/nonexistent.asm\"").contains("line 1: cannot read"));
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let src = concat!(
+            ".data\n",
+            "msg: .asciz \"hi\"\n",
+            "end:\n",
+            ".code\n",
+            "start: SET r0 msg\n",
+            "SET r1 -5\n",
+            "SET r2 0x123456789\n",
+            "loop: ADD r0 r1 r2\n",
+            "LOAD64 r0 r3\n",
+            "JEQR loop\n",
+            "JNER 16\n",
+            "CALL start\n",
+            "JMP 8\n",
+            "RET\n",
+        );
+        let program = assemble(src).unwrap();
+        let text = listing(&program);
+        let again = assemble(&text).unwrap();
+        assert_eq!(again.code, program.code);
+        assert_eq!(again.data, program.data);
+        assert_eq!(again.symbols, program.symbols);
+
+        let ops = disassemble(&program.code);
+        assert_eq!(ops[0].to_string(), "SET $r0 8");
+        assert_eq!(ops[6].to_string(), "JEQR -16");
+        let named = disassemble_program(&program);
+        assert_eq!(named[0].tag(), Some("start"));
+        assert_eq!(named[6].to_string(), "JEQR loop");
     }
-    Ok(prog)
 }
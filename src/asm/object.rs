@@ -0,0 +1,147 @@
+//! The `.rokb` bytecode file format.
+//!
+//! All integers are big-endian.
+//!
+//! ```text
+//! magic     4 bytes   "ROKB"
+//! version   u16
+//! sections  u16       number of entries in the section table
+//! table     sections * { kind: u16, offset: u32, len: u32 }
+//! ...       section contents, at the offsets given in the table
+//! checksum  u32       CRC-32 of every preceding byte
+//! ```
+//!
+//! Section kinds:
+//!
+//! - `CODE`: the instructions.
+//! - `DATA`: initial memory contents, placed at `DATA_BASE` on load.
+//! - `SYMBOLS`: `u32` count, then `{ kind: u8, value: u64, len: u16, name }`
+//!   with kind 0 for code labels and 1 for data labels.
+//! - `LINES`: `u16` file count and `{ len: u16, name }` file names, then a
+//!   `u32` count of `{ offset: u32, file: u16, line: u32 }`. A file index of
+//!   `NO_FILE` means the source was not read from a file.
+//! - `NATIVES`: `u32` count, then `{ len: u16, name }` for each native
+//!   function called by `CALLN`, by index.
+//!
+//! `CODE` is required, the other sections are optional. Readers reject
+//! unknown section kinds.
+//!
+//! Version 2 added `NATIVES`. Version 1 files are still read.
+use crate::asm::{Program, SymbolKind};
+
+pub const MAGIC: &[u8; 4] = b"ROKB";
+pub const VERSION: u16 = 2;
+/// Oldest version the loader reads
+pub const MIN_VERSION: u16 = 1;
+/// First version with a `NATIVES` section
+pub const NATIVES_VERSION: u16 = 2;
+/// File extension for bytecode files
+pub const EXTENSION: &str = "rokb";
+
+pub const CODE: u16 = 1;
+pub const DATA: u16 = 2;
+pub const SYMBOLS: u16 = 3;
+pub const LINES: u16 = 4;
+pub const NATIVES: u16 = 5;
+
+pub const NO_FILE: u16 = u16::MAX;
+
+/// Size of the fixed header before the section table
+pub const HEADER_SIZE: usize = 8;
+/// Size of one section table entry
+pub const ENTRY_SIZE: usize = 10;
+
+/// Encode `program` in the `.rokb` format
+pub fn write(program: &Program) -> Vec<u8> {
+    let mut sections = vec![(CODE, program.code.clone())];
+    if !program.data.is_empty() {
+        sections.push((DATA, program.data.clone()));
+    }
+    if !program.symbols.is_empty() {
+        sections.push((SYMBOLS, symbols(program)));
+    }
+    if !program.lines.is_empty() {
+        sections.push((LINES, lines(program)));
+    }
+    if !program.natives.is_empty() {
+        let mut out = (program.natives.len() as u32).to_be_bytes().to_vec();
+        for name in &program.natives {
+            push_name(&mut out, name);
+        }
+        sections.push((NATIVES, out));
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    out.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+    let mut offset = HEADER_SIZE + ENTRY_SIZE * sections.len();
+    for (kind, contents) in &sections {
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&(offset as u32).to_be_bytes());
+        out.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+        offset += contents.len();
+    }
+    for (_, contents) in &sections {
+        out.extend_from_slice(contents);
+    }
+    let sum = checksum(&out);
+    out.extend_from_slice(&sum.to_be_bytes());
+    out
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn symbols(program: &Program) -> Vec<u8> {
+    let mut out = (program.symbols.len() as u32).to_be_bytes().to_vec();
+    for symbol in &program.symbols {
+        out.push(match symbol.kind {
+            SymbolKind::Code => 0,
+            SymbolKind::Data => 1,
+        });
+        out.extend_from_slice(&symbol.value.to_be_bytes());
+        push_name(&mut out, &symbol.name);
+    }
+    out
+}
+
+fn lines(program: &Program) -> Vec<u8> {
+    let mut files: Vec<&str> = vec![];
+    for line in &program.lines {
+        if let Some(ref file) = line.file {
+            if !files.contains(&file.as_str()) {
+                files.push(file);
+            }
+        }
+    }
+    let mut out = (files.len() as u16).to_be_bytes().to_vec();
+    for file in &files {
+        push_name(&mut out, file);
+    }
+    out.extend_from_slice(&(program.lines.len() as u32).to_be_bytes());
+    for line in &program.lines {
+        let file = match line.file {
+            Some(ref file) => files.iter().position(|f| f == file).unwrap() as u16,
+            None => NO_FILE,
+        };
+        out.extend_from_slice(&(line.offset as u32).to_be_bytes());
+        out.extend_from_slice(&file.to_be_bytes());
+        out.extend_from_slice(&(line.line as u32).to_be_bytes());
+    }
+    out
+}
+
+/// CRC-32 (IEEE), as used by zip and png
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
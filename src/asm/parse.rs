@@ -0,0 +1,625 @@
+//! Assembly source parser.
+//!
+//! A line holds an optional `label:`, then an instruction or a directive.
+//! Comments start with `;` or `#`, and operands may be separated by commas.
+//!
+//! ```text
+//! .equ NEWLINE '\n'
+//! .data
+//! greeting: .asciz "hello"
+//! table:    .word 1, 2, 3
+//!           .align 8
+//! .code
+//! start:    SET $r0 greeting
+//! ```
+//!
+//! Directives:
+//!
+//! - `.code`, `.data`: switch section. Labels in `.data` name data addresses.
+//! - `.byte v, ...`: 8-bit values. `.word v, ...`: 64-bit big-endian values.
+//! - `.asciz "str"`: a NUL-terminated string.
+//! - `.align n`: pad the data section to a multiple of `n` bytes.
+//! - `.equ NAME value`: a constant usable wherever an immediate is.
+//! - `.include "file"`: assemble another file in place, relative to the
+//!   including file.
+use crate::asm::{Op, Operation};
+use crate::errors::{Error, Result, StringError};
+use crate::rt::memory::DATA_BASE;
+use crate::rt::proc::REGISTERS;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{fs, path};
+
+/// Parsed source, ready for `translate`
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    pub(super) ops: Vec<Operation>,
+    pub(super) data: Vec<u8>,
+    /// Data labels: name -> address in VM memory
+    pub(super) data_labels: HashMap<String, usize>,
+}
+impl Assembly {
+    pub fn ops(&self) -> &[Operation] {
+        &self.ops
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn push(&mut self, operation: Operation) {
+        self.ops.push(operation);
+    }
+
+    /// Append a NUL-terminated string to the data, labelled `label`
+    pub fn asciz<T: Into<String>>(&mut self, label: T, s: &str) {
+        self.data_labels
+            .insert(label.into(), DATA_BASE + self.data.len());
+        self.data.extend_from_slice(s.as_bytes());
+        self.data.push(0);
+    }
+
+    /// Append an aligned 64-bit word to the data, labelled `label`
+    pub fn word<T: Into<String>>(&mut self, label: T, val: i64) {
+        while !(DATA_BASE + self.data.len()).is_multiple_of(8) {
+            self.data.push(0);
+        }
+        self.data_labels
+            .insert(label.into(), DATA_BASE + self.data.len());
+        self.data.extend_from_slice(&val.to_be_bytes());
+    }
+}
+impl From<Vec<Operation>> for Assembly {
+    fn from(ops: Vec<Operation>) -> Self {
+        Self {
+            ops,
+            ..Self::default()
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Result<Assembly> {
+    let mut parser = Parser::default();
+    parser.source(s, None)?;
+    parser.finish()
+}
+
+/// Parse a file, resolving `.include` relative to it
+pub fn parse_file<P: AsRef<path::Path>>(path: P) -> Result<Assembly> {
+    let mut parser = Parser::default();
+    let path = path.as_ref();
+    let (canonical, src) = parser.open(path)?;
+    parser.file(path, canonical, &src)?;
+    parser.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Section {
+    #[default]
+    Code,
+    Data,
+}
+
+#[derive(Default)]
+struct Parser {
+    asm: Assembly,
+    consts: HashMap<String, i64>,
+    section: Section,
+    /// Code label waiting for the next instruction, with its file and line
+    label: Option<(Option<Rc<str>>, usize, String)>,
+    /// Files being included, outermost first
+    including: Vec<path::PathBuf>,
+}
+impl Parser {
+    /// Read a file to parse, checking for circular includes
+    fn open(&self, path: &path::Path) -> Result<(path::PathBuf, String)> {
+        let read_error = |e| se!("cannot read {}: {}", path.display(), e);
+        let canonical = fs::canonicalize(path).map_err(read_error)?;
+        if self.including.contains(&canonical) {
+            return Err(se!("circular include of {}", path.display()).into());
+        }
+        let src = fs::read_to_string(path).map_err(read_error)?;
+        Ok((canonical, src))
+    }
+
+    /// Parse the contents of a file. Errors name the file and line.
+    fn file(&mut self, path: &path::Path, canonical: path::PathBuf, src: &str) -> Result<()> {
+        self.including.push(canonical);
+        let res = self.source(src, Some(path.display().to_string().into()));
+        self.including.pop();
+        res
+    }
+
+    fn source(&mut self, src: &str, file: Option<Rc<str>>) -> Result<()> {
+        for (i, text) in src.lines().enumerate() {
+            let line = i + 1;
+            let at = |e| at_line(file.as_deref(), line, e);
+            let mut idents = tokenize(text).map_err(at)?;
+            if idents.is_empty() {
+                continue;
+            }
+            if is_label(&idents[0]) {
+                let name = idents.remove(0).trim_end_matches(':').to_owned();
+                self.label(file.clone(), line, name).map_err(at)?;
+                if idents.is_empty() {
+                    continue;
+                }
+            }
+            let mnemonic = idents.remove(0);
+            // substitute constants, except for the name `.equ` defines
+            let defines = mnemonic.eq_ignore_ascii_case(".equ");
+            let args = idents
+                .into_iter()
+                .enumerate()
+                .map(|(i, arg)| match self.consts.get(&arg) {
+                    Some(v) if !(defines && i == 0) => v.to_string(),
+                    _ => arg,
+                })
+                .collect::<Vec<_>>();
+            if mnemonic.eq_ignore_ascii_case(".include") {
+                let name = match args.as_slice() {
+                    [name] => string_literal(name).map_err(at)?,
+                    _ => return Err(at(se!(".include expects a file name").into())),
+                };
+                let dir = match file {
+                    Some(ref f) => path::Path::new(&**f)
+                        .parent()
+                        .map(|p| p.to_owned())
+                        .unwrap_or_default(),
+                    None => path::PathBuf::new(),
+                };
+                let included = dir.join(name);
+                let (canonical, src) = self.open(&included).map_err(at)?;
+                self.file(&included, canonical, &src)?;
+            } else if mnemonic.starts_with('.') {
+                self.directive(&mnemonic, &args).map_err(at)?;
+            } else {
+                self.instruction(mnemonic, args, file.clone(), line)
+                    .map_err(at)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, file: Option<Rc<str>>, line: usize, name: String) -> Result<()> {
+        if self.section == Section::Data {
+            let addr = DATA_BASE + self.asm.data.len();
+            if self.asm.data_labels.insert(name.clone(), addr).is_some() {
+                return Err(se!("duplicate label: {}", name).into());
+            }
+            return Ok(());
+        }
+        if let Some((_, _, prev)) = self.label.take() {
+            return Err(se!("labels {} and {} name the same instruction", prev, name).into());
+        }
+        self.label = Some((file, line, name));
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        mnemonic: String,
+        args: Vec<String>,
+        file: Option<Rc<str>>,
+        line: usize,
+    ) -> Result<()> {
+        if self.section != Section::Code {
+            return Err(se!("instruction {} outside of .code", mnemonic).into());
+        }
+        let op = Op::from(mnemonic.as_str());
+        if op == Op::IGL && !mnemonic.eq_ignore_ascii_case("IGL") {
+            return Err(se!("unknown instruction: {}", mnemonic).into());
+        }
+        check_operands(op, &mnemonic, &args)?;
+        self.asm.ops.push(Operation {
+            tag: self.label.take().map(|(_, _, name)| name),
+            code: op,
+            args,
+            file,
+            line,
+        });
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, args: &[String]) -> Result<()> {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            ".code" | ".data" => {
+                expect_args(&name, args, 0)?;
+                self.section = if name == ".code" {
+                    Section::Code
+                } else {
+                    Section::Data
+                };
+                return Ok(());
+            }
+            ".equ" => {
+                expect_args(&name, args, 2)?;
+                if !is_label(&format!("{}:", args[0])) {
+                    return Err(se!("invalid constant name: {}", args[0]).into());
+                }
+                let val = self.value(&args[1])?;
+                if self.consts.insert(args[0].clone(), val).is_some() {
+                    return Err(se!("duplicate constant: {}", args[0]).into());
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+        if self.section != Section::Data {
+            return Err(se!("{} outside of .data", name).into());
+        }
+        match name.as_str() {
+            ".byte" => {
+                if args.is_empty() {
+                    return Err(se!(".byte expects at least one value").into());
+                }
+                for arg in args {
+                    let val = self.value(arg)?;
+                    if !(i8::MIN as i64..=u8::MAX as i64).contains(&val) {
+                        return Err(se!("byte out of range: {}", arg).into());
+                    }
+                    self.asm.data.push(val as u8);
+                }
+            }
+            ".word" => {
+                if args.is_empty() {
+                    return Err(se!(".word expects at least one value").into());
+                }
+                for arg in args {
+                    let val = self.value(arg)?;
+                    self.asm.data.extend_from_slice(&val.to_be_bytes());
+                }
+            }
+            ".asciz" => {
+                expect_args(&name, args, 1)?;
+                let s = string_literal(&args[0])?;
+                self.asm.data.extend_from_slice(s.as_bytes());
+                self.asm.data.push(0);
+            }
+            ".align" => {
+                expect_args(&name, args, 1)?;
+                let n = self.value(&args[0])?;
+                if n <= 0 || !(n as u64).is_power_of_two() {
+                    return Err(se!(".align expects a power of two, found {}", args[0]).into());
+                }
+                // align the address, not the offset within the section
+                while !(DATA_BASE + self.asm.data.len()).is_multiple_of(n as usize) {
+                    self.asm.data.push(0);
+                }
+            }
+            _ => return Err(se!("unknown directive: {}", name).into()),
+        }
+        Ok(())
+    }
+
+    /// An immediate or the address of a data label defined earlier
+    fn value(&self, arg: &str) -> Result<i64> {
+        match self.asm.data_labels.get(arg) {
+            Some(&addr) => Ok(addr as i64),
+            None => immediate(arg),
+        }
+    }
+
+    fn finish(mut self) -> Result<Assembly> {
+        // a trailing label marks the end of the program
+        if let Some((file, line, name)) = self.label.take() {
+            self.asm.ops.push(Operation {
+                tag: Some(name),
+                code: Op::HLT,
+                args: vec![],
+                file,
+                line,
+            });
+        }
+        Ok(self.asm)
+    }
+}
+
+fn expect_args(name: &str, args: &[String], n: usize) -> Result<()> {
+    if args.len() != n {
+        return Err(se!("{} expects {} operands, found {}", name, n, args.len()).into());
+    }
+    Ok(())
+}
+
+/// Whether `ident` is a label definition such as `loop:`. Names cannot start
+/// with a digit, so they are never confused with offsets.
+pub(super) fn is_label(ident: &str) -> bool {
+    let name = match ident.strip_suffix(':') {
+        Some(name) => name,
+        None => return false,
+    };
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Split a line into tokens on whitespace and commas, dropping `;` and `#`
+/// comments. Character and string literals such as `' '` or `"a; b"` stay
+/// one token.
+fn tokenize(line: &str) -> Result<Vec<String>> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == ';' || c == '#' {
+            break;
+        } else if c == '\'' || c == '"' {
+            let mut token = String::new();
+            token.push(chars.next().unwrap());
+            loop {
+                match chars.next() {
+                    Some('\\') => {
+                        token.push('\\');
+                        token.extend(chars.next());
+                    }
+                    Some(q) if q == c => {
+                        token.push(q);
+                        break;
+                    }
+                    Some(c) => token.push(c),
+                    None => return Err(se!("unterminated literal: {}", token).into()),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ';' || c == '#' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Prefix an error with the source location it was found at
+pub(super) fn at_line(file: Option<&str>, line: usize, e: Error) -> Error {
+    let message = match e.downcast_ref::<StringError>() {
+        Some(se) => se.0.clone(),
+        None => e.to_string(),
+    };
+    match file {
+        Some(file) => se!("{}: line {}: {}", file, line, message).into(),
+        None => se!("line {}: {}", line, message).into(),
+    }
+}
+
+/// Kinds of operand an instruction takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Operand {
+    Register,
+    /// A literal, constant or label
+    Immediate,
+    /// A label or a literal offset
+    Target,
+    /// The name of a native function
+    Name,
+}
+
+impl Op {
+    pub(super) fn operands(self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            Op::HLT | Op::IGL | Op::Reg | Op::Ret | Op::Gc => &[],
+            Op::Set | Op::SetHi => &[Register, Immediate],
+            Op::Syscall => &[Immediate],
+            Op::Calln => &[Name],
+            Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::Mod
+            | Op::And
+            | Op::Or
+            | Op::Xor
+            | Op::Shl
+            | Op::Shr
+            | Op::Tadd
+            | Op::Tsub
+            | Op::Tmul
+            | Op::Tdiv
+            | Op::Tmod
+            | Op::Tget => &[Register, Register, Register],
+            Op::Inc
+            | Op::Dec
+            | Op::Free
+            | Op::Push
+            | Op::Pop
+            | Op::Ldsp
+            | Op::Ldfp
+            | Op::Recv
+            | Op::Pid
+            | Op::Ttest
+            | Op::Tlist => &[Register],
+            Op::Spawn => &[Register, Target],
+            Op::Jmp | Op::Jeq | Op::Jne | Op::Call | Op::Jmpr | Op::Jeqr | Op::Jner => &[Target],
+            Op::Eq
+            | Op::Neq
+            | Op::Gt
+            | Op::Lt
+            | Op::Gte
+            | Op::Lte
+            | Op::Cmov
+            | Op::Not
+            | Op::Neg
+            | Op::Load8
+            | Op::Load16
+            | Op::Load32
+            | Op::Load64
+            | Op::Store8
+            | Op::Store16
+            | Op::Store32
+            | Op::Store64
+            | Op::Alloc
+            | Op::Send
+            | Op::Tneg
+            | Op::Teq
+            | Op::Tlt
+            | Op::Tgt
+            | Op::Tlte
+            | Op::Tgte
+            | Op::Tbox
+            | Op::Tunbox
+            | Op::Tnum
+            | Op::Tstr
+            | Op::Tpush
+            | Op::Tlen => &[Register, Register],
+        }
+    }
+
+    pub(super) fn is_relative_jump(self) -> bool {
+        matches!(self, Op::Jmpr | Op::Jeqr | Op::Jner)
+    }
+}
+
+/// Check the number and syntax of the operands of `op`
+fn check_operands(op: Op, mnemonic: &str, args: &[String]) -> Result<()> {
+    let expected = op.operands();
+    if args.len() != expected.len() {
+        let kinds = expected
+            .iter()
+            .map(|k| format!("{:?}", k).to_lowercase())
+            .collect::<Vec<_>>();
+        return Err(se!(
+            "{} expects {} operand{} ({}), found {}",
+            mnemonic.to_uppercase(),
+            expected.len(),
+            if expected.len() == 1 { "" } else { "s" },
+            kinds.join(", "),
+            args.len()
+        )
+        .into());
+    }
+    for (kind, arg) in expected.iter().zip(args) {
+        match kind {
+            Operand::Register => {
+                register(arg)?;
+            }
+            Operand::Immediate => match immediate(arg) {
+                Ok(val) => {
+                    if op != Op::Set && (val < i32::MIN as i64 || val > u32::MAX as i64) {
+                        return Err(se!("{} immediate out of 32-bit range: {}", op, arg).into());
+                    }
+                }
+                Err(e) => {
+                    if !is_label(&format!("{}:", arg)) {
+                        return Err(e);
+                    }
+                }
+            },
+            Operand::Target => {
+                if let Ok(val) = immediate(arg) {
+                    let (min, max) = if op.is_relative_jump() {
+                        (i32::MIN as i64, i32::MAX as i64)
+                    } else {
+                        (0, u32::MAX as i64)
+                    };
+                    if val < min || val > max {
+                        return Err(se!("jump target out of range: {}", arg).into());
+                    }
+                } else if !is_label(&format!("{}:", arg)) {
+                    return Err(se!("invalid jump target: {}", arg).into());
+                }
+            }
+            Operand::Name => {
+                if !is_label(&format!("{}:", arg)) {
+                    return Err(se!("invalid native function name: {}", arg).into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a register: `$r12`, `r12`, `$12` or `12`
+pub(super) fn register(arg: &str) -> Result<u8> {
+    let digits = arg.strip_prefix('$').unwrap_or(arg);
+    let digits = digits.strip_prefix('r').unwrap_or(digits);
+    match digits.parse::<u8>() {
+        Ok(r) if (r as usize) < REGISTERS => Ok(r),
+        Ok(_) => Err(se!("register out of range (0-{}): {}", REGISTERS - 1, arg).into()),
+        Err(_) => Err(se!("invalid register: {}", arg).into()),
+    }
+}
+
+fn unescape(c: char) -> Option<char> {
+    Some(match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' | '\'' | '"' => c,
+        _ => return None,
+    })
+}
+
+/// Parse a character literal such as `'a'` or `'\n'` to its code point
+fn char_literal(arg: &str) -> Option<i64> {
+    let inner = arg.strip_prefix('\'')?.strip_suffix('\'')?;
+    let mut chars = inner.chars();
+    let c = match chars.next()? {
+        '\\' => unescape(chars.next()?)?,
+        c => c,
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(c as i64)
+}
+
+/// Parse a string literal such as `"hi\n"`
+fn string_literal(arg: &str) -> Result<String> {
+    let inner = arg
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|_| arg.len() >= 2)
+        .ok_or_else(|| se!("expected a string literal, found {}", arg))?;
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let escaped = chars.next().and_then(unescape);
+            s.push(escaped.ok_or_else(|| se!("invalid escape in {}", arg))?);
+        } else {
+            s.push(c);
+        }
+    }
+    Ok(s)
+}
+
+/// Parse an immediate: decimal or `0x` hex, optionally negative, or a
+/// character literal. Unsigned values above `i64::MAX` keep their bit pattern.
+pub(super) fn immediate(arg: &str) -> Result<i64> {
+    if let Some(c) = char_literal(arg) {
+        return Ok(c);
+    }
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+    let magnitude = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse::<u64>(),
+    }
+    .map_err(|_| se!("invalid immediate: {}", arg))?;
+    if !negative {
+        Ok(magnitude as i64)
+    } else if magnitude <= i64::MIN.unsigned_abs() {
+        Ok((magnitude as i64).wrapping_neg())
+    } else {
+        Err(se!("immediate out of range: {}", arg).into())
+    }
+}
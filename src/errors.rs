@@ -14,5 +14,5 @@ pub type Result<T> = std::result::Result<T, Error>;
 // build a string error
 #[macro_export]
 macro_rules! se {
-    ($($arg:tt)*) => {{ crate::errors::StringError(format!($($arg)*)) }};
+    ($($arg:tt)*) => {{ $crate::errors::StringError(format!($($arg)*)) }};
 }
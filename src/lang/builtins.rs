@@ -0,0 +1,630 @@
+use crate::errors::Result;
+use crate::lang::eval;
+use crate::lang::exception::Thrown;
+use crate::lang::value::{Atom, ExInfo, Native, Num, Value};
+use crate::lang::Scope;
+use num::{BigInt, Signed, ToPrimitive, Zero};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+fn num_arg<'a>(name: &str, v: &'a Value) -> Result<&'a Num> {
+    match v {
+        Value::Num(n) => Ok(n),
+        _ => Err(se!("{} expects numbers, found {}: {}", name, v.type_name(), v).into()),
+    }
+}
+
+fn int_arg(name: &str, v: &Value) -> Result<i64> {
+    let n = num_arg(name, v)?;
+    if !n.is_integer() {
+        return Err(se!("{} expects an integer, found: {}", name, v).into());
+    }
+    n.to_integer()
+        .to_i64()
+        .ok_or_else(|| se!("{} argument out of range: {}", name, v).into())
+}
+
+fn arity(name: &str, args: &[Value], min: usize, max: Option<usize>) -> Result<()> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        let expected = match max {
+            Some(max) if max == min => format!("{}", min),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("{} or more", min),
+        };
+        return Err(se!(
+            "Wrong number of args ({}) passed to {}, expected {}",
+            args.len(),
+            name,
+            expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn arith(name: &'static str, args: Vec<Value>) -> Result<Value> {
+    let one = Num::from_integer(BigInt::from(1));
+    if args.is_empty() {
+        return match name {
+            "+" => Ok(Value::Num(Num::zero())),
+            "*" => Ok(Value::Num(one)),
+            _ => Err(se!("Wrong number of args (0) passed to {}", name).into()),
+        };
+    }
+    let mut nums = args.iter().map(|v| num_arg(name, v));
+    let first = nums.next().unwrap()?.clone();
+    if args.len() == 1 {
+        return match name {
+            "-" => Ok(Value::Num(-first)),
+            "/" => Ok(Value::Num(num_op(name, one, &first)?)),
+            _ => Ok(Value::Num(first)),
+        };
+    }
+    let mut acc = first;
+    for n in nums {
+        acc = num_op(name, acc, n?)?;
+    }
+    Ok(Value::Num(acc))
+}
+
+fn num_op(name: &str, a: Num, b: &Num) -> Result<Num> {
+    Ok(match name {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => {
+            if b.is_zero() {
+                return Err(se!("Divide by zero").into());
+            }
+            a / b
+        }
+        _ => unreachable!(),
+    })
+}
+
+fn compare(name: &'static str, args: Vec<Value>) -> Result<Value> {
+    arity(name, &args, 1, None)?;
+    for pair in args.windows(2) {
+        let a = num_arg(name, &pair[0])?;
+        let b = num_arg(name, &pair[1])?;
+        let ok = match name {
+            "<" => a < b,
+            ">" => a > b,
+            "<=" => a <= b,
+            ">=" => a >= b,
+            _ => unreachable!(),
+        };
+        if !ok {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn seq_arg<'a>(name: &str, v: &'a Value) -> Result<&'a [Value]> {
+    v.as_seq().ok_or_else(|| {
+        se!(
+            "{} expects a sequence, found {}: {}",
+            name,
+            v.type_name(),
+            v
+        )
+        .into()
+    })
+}
+
+fn atom_arg<'a>(name: &str, v: &'a Value) -> Result<&'a Rc<Atom>> {
+    match v {
+        Value::Atom(atom) => Ok(atom),
+        _ => Err(se!("{} expects an atom, found {}: {}", name, v.type_name(), v).into()),
+    }
+}
+
+/// Set the value of an atom and call each watch with `(key atom old new)`
+fn set_atom(atom: &Rc<Atom>, new: Value) -> Result<()> {
+    let old = atom.replace(new.clone());
+    for (key, func) in atom.watches() {
+        let args = vec![key, Value::Atom(atom.clone()), old.clone(), new.clone()];
+        eval::apply(&func, args)?;
+    }
+    Ok(())
+}
+
+fn define(scope: &Scope, name: &'static str, f: fn(Vec<Value>) -> Result<Value>) {
+    scope.define(name, Value::Native(Native::new(name, f)));
+}
+
+pub fn define_builtins(scope: &Scope) {
+    // -- numbers --
+    define(scope, "+", |args| arith("+", args));
+    define(scope, "-", |args| arith("-", args));
+    define(scope, "*", |args| arith("*", args));
+    define(scope, "/", |args| arith("/", args));
+    define(scope, "<", |args| compare("<", args));
+    define(scope, ">", |args| compare(">", args));
+    define(scope, "<=", |args| compare("<=", args));
+    define(scope, ">=", |args| compare(">=", args));
+    define(scope, "mod", |args| {
+        arity("mod", &args, 2, Some(2))?;
+        let a = num_arg("mod", &args[0])?;
+        let b = num_arg("mod", &args[1])?;
+        if b.is_zero() {
+            return Err(se!("Divide by zero").into());
+        }
+        let r = a - b * (a / b).floor();
+        Ok(Value::Num(r))
+    });
+    define(scope, "inc", |args| {
+        arity("inc", &args, 1, Some(1))?;
+        Ok(Value::Num(
+            num_arg("inc", &args[0])? + Num::from_integer(BigInt::from(1)),
+        ))
+    });
+    define(scope, "dec", |args| {
+        arity("dec", &args, 1, Some(1))?;
+        Ok(Value::Num(
+            num_arg("dec", &args[0])? - Num::from_integer(BigInt::from(1)),
+        ))
+    });
+    define(scope, "abs", |args| {
+        arity("abs", &args, 1, Some(1))?;
+        Ok(Value::Num(num_arg("abs", &args[0])?.abs()))
+    });
+
+    // -- equality and logic --
+    define(scope, "=", |args| {
+        arity("=", &args, 1, None)?;
+        Ok(Value::Bool(args.windows(2).all(|p| p[0] == p[1])))
+    });
+    define(scope, "==", |args| {
+        arity("==", &args, 1, None)?;
+        Ok(Value::Bool(args.windows(2).all(|p| p[0] == p[1])))
+    });
+    define(scope, "!=", |args| {
+        arity("!=", &args, 1, None)?;
+        Ok(Value::Bool(!args.windows(2).all(|p| p[0] == p[1])))
+    });
+    define(scope, "not", |args| {
+        arity("not", &args, 1, Some(1))?;
+        Ok(Value::Bool(!args[0].is_truthy()))
+    });
+
+    // -- type predicates --
+    define(scope, "type", |args| {
+        arity("type", &args, 1, Some(1))?;
+        Ok(Value::keyword(args[0].type_name()))
+    });
+    define(scope, "nil?", |args| {
+        arity("nil?", &args, 1, Some(1))?;
+        Ok(Value::Bool(args[0] == Value::Nil))
+    });
+    define(scope, "number?", |args| {
+        arity("number?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(args[0], Value::Num(_))))
+    });
+    define(scope, "string?", |args| {
+        arity("string?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(args[0], Value::Str(_))))
+    });
+    define(scope, "keyword?", |args| {
+        arity("keyword?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(args[0], Value::Keyword(_))))
+    });
+    define(scope, "vector?", |args| {
+        arity("vector?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(args[0], Value::Vector(_))))
+    });
+    define(scope, "map?", |args| {
+        arity("map?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(args[0], Value::Map(_))))
+    });
+    define(scope, "fn?", |args| {
+        arity("fn?", &args, 1, Some(1))?;
+        Ok(Value::Bool(matches!(
+            args[0],
+            Value::Func(_) | Value::Native(_)
+        )))
+    });
+
+    // -- collections --
+    define(scope, "list", |args| Ok(Value::List(args.into())));
+    define(scope, "vector", |args| Ok(Value::Vector(args.into())));
+    define(scope, "hash-map", |args| {
+        if !args.len().is_multiple_of(2) {
+            return Err(se!("hash-map expects an even number of args").into());
+        }
+        let mut map = BTreeMap::new();
+        let mut args = args.into_iter();
+        while let (Some(k), Some(v)) = (args.next(), args.next()) {
+            map.insert(k, v);
+        }
+        Ok(Value::Map(map))
+    });
+    define(scope, "count", |args| {
+        arity("count", &args, 1, Some(1))?;
+        let n = match &args[0] {
+            Value::Str(s) => s.chars().count(),
+            Value::Map(m) => m.len(),
+            Value::Set(s) => s.len(),
+            v => seq_arg("count", v)?.len(),
+        };
+        Ok(Value::int(n as i64))
+    });
+    define(scope, "empty?", |args| {
+        arity("empty?", &args, 1, Some(1))?;
+        let empty = match &args[0] {
+            Value::Str(s) => s.is_empty(),
+            Value::Map(m) => m.is_empty(),
+            Value::Set(s) => s.is_empty(),
+            v => seq_arg("empty?", v)?.is_empty(),
+        };
+        Ok(Value::Bool(empty))
+    });
+    define(scope, "first", |args| {
+        arity("first", &args, 1, Some(1))?;
+        Ok(seq_arg("first", &args[0])?
+            .first()
+            .cloned()
+            .unwrap_or(Value::Nil))
+    });
+    define(scope, "rest", |args| {
+        arity("rest", &args, 1, Some(1))?;
+        let seq = seq_arg("rest", &args[0])?;
+        Ok(Value::List(
+            seq.iter().skip(1).cloned().collect::<Vec<_>>().into(),
+        ))
+    });
+    define(scope, "nth", |args| {
+        arity("nth", &args, 2, Some(3))?;
+        let seq = seq_arg("nth", &args[0])?;
+        let i = int_arg("nth", &args[1])?;
+        match seq.get(i as usize) {
+            Some(v) if i >= 0 => Ok(v.clone()),
+            _ => match args.get(2) {
+                Some(default) => Ok(default.clone()),
+                None => Err(se!("Index {} out of bounds for length {}", i, seq.len()).into()),
+            },
+        }
+    });
+    define(scope, "cons", |args| {
+        arity("cons", &args, 2, Some(2))?;
+        let mut items = vec![args[0].clone()];
+        items.extend_from_slice(seq_arg("cons", &args[1])?);
+        Ok(Value::List(items.into()))
+    });
+    define(scope, "conj", |args| {
+        arity("conj", &args, 1, None)?;
+        let mut args = args.into_iter();
+        let coll = args.next().unwrap();
+        match coll {
+            Value::Vector(mut v) => {
+                v.extend(args);
+                Ok(Value::Vector(v))
+            }
+            Value::List(l) => {
+                let mut items = args.rev().collect::<Vec<_>>();
+                items.extend(l.iter().cloned());
+                Ok(Value::List(items.into()))
+            }
+            Value::Nil => {
+                let items = args.rev().collect::<Vec<_>>();
+                Ok(Value::List(items.into()))
+            }
+            Value::Set(mut s) => {
+                s.extend(args);
+                Ok(Value::Set(s))
+            }
+            v => Err(se!("conj expects a collection, found {}: {}", v.type_name(), v).into()),
+        }
+    });
+    define(scope, "concat", |args| {
+        let mut items = vec![];
+        for arg in &args {
+            items.extend_from_slice(seq_arg("concat", arg)?);
+        }
+        Ok(Value::List(items.into()))
+    });
+    define(scope, "get", |args| {
+        arity("get", &args, 2, Some(3))?;
+        let default = args.get(2).cloned().unwrap_or(Value::Nil);
+        let found = match &args[0] {
+            Value::Map(m) => m.get(&args[1]).cloned(),
+            Value::Set(s) => s.get(&args[1]).cloned(),
+            Value::Vector(v) => {
+                let i = int_arg("get", &args[1])?;
+                if i >= 0 {
+                    v.get(i as usize).cloned()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        Ok(found.unwrap_or(default))
+    });
+    define(scope, "assoc", |args| {
+        arity("assoc", &args, 3, None)?;
+        if args.len().is_multiple_of(2) {
+            return Err(se!("assoc expects a map followed by key/value pairs").into());
+        }
+        let mut args = args.into_iter();
+        let mut map = match args.next().unwrap() {
+            Value::Map(m) => m,
+            Value::Nil => BTreeMap::new(),
+            v => return Err(se!("assoc expects a map, found {}: {}", v.type_name(), v).into()),
+        };
+        while let (Some(k), Some(v)) = (args.next(), args.next()) {
+            map.insert(k, v);
+        }
+        Ok(Value::Map(map))
+    });
+    define(scope, "dissoc", |args| {
+        arity("dissoc", &args, 1, None)?;
+        let mut args = args.into_iter();
+        let mut map = match args.next().unwrap() {
+            Value::Map(m) => m,
+            Value::Nil => BTreeMap::new(),
+            v => return Err(se!("dissoc expects a map, found {}: {}", v.type_name(), v).into()),
+        };
+        for k in args {
+            map.remove(&k);
+        }
+        Ok(Value::Map(map))
+    });
+    define(scope, "keys", |args| {
+        arity("keys", &args, 1, Some(1))?;
+        match &args[0] {
+            Value::Map(m) => Ok(Value::List(m.keys().cloned().collect::<Vec<_>>().into())),
+            Value::Nil => Ok(Value::Nil),
+            v => Err(se!("keys expects a map, found {}: {}", v.type_name(), v).into()),
+        }
+    });
+    define(scope, "vals", |args| {
+        arity("vals", &args, 1, Some(1))?;
+        match &args[0] {
+            Value::Map(m) => Ok(Value::List(m.values().cloned().collect::<Vec<_>>().into())),
+            Value::Nil => Ok(Value::Nil),
+            v => Err(se!("vals expects a map, found {}: {}", v.type_name(), v).into()),
+        }
+    });
+    define(scope, "contains?", |args| {
+        arity("contains?", &args, 2, Some(2))?;
+        let found = match &args[0] {
+            Value::Map(m) => m.contains_key(&args[1]),
+            Value::Set(s) => s.contains(&args[1]),
+            _ => false,
+        };
+        Ok(Value::Bool(found))
+    });
+    define(scope, "range", |args| {
+        arity("range", &args, 1, Some(2))?;
+        let (start, end) = match args.len() {
+            1 => (0, int_arg("range", &args[0])?),
+            _ => (int_arg("range", &args[0])?, int_arg("range", &args[1])?),
+        };
+        Ok(Value::List(
+            (start..end).map(Value::int).collect::<Vec<_>>().into(),
+        ))
+    });
+
+    // -- functions --
+    define(scope, "apply", |args| {
+        arity("apply", &args, 2, None)?;
+        let mut args = args;
+        let last = args.pop().unwrap();
+        let func = args.remove(0);
+        args.extend_from_slice(seq_arg("apply", &last)?);
+        eval::apply(&func, args)
+    });
+    define(scope, "map", |args| {
+        arity("map", &args, 2, Some(2))?;
+        let items = seq_arg("map", &args[1])?
+            .iter()
+            .map(|v| eval::apply(&args[0], vec![v.clone()]))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Value::List(items.into()))
+    });
+    define(scope, "filter", |args| {
+        arity("filter", &args, 2, Some(2))?;
+        let mut items = vec![];
+        for v in seq_arg("filter", &args[1])? {
+            if eval::apply(&args[0], vec![v.clone()])?.is_truthy() {
+                items.push(v.clone());
+            }
+        }
+        Ok(Value::List(items.into()))
+    });
+    define(scope, "reduce", |args| {
+        arity("reduce", &args, 2, Some(3))?;
+        let (mut acc, items) = match args.len() {
+            2 => {
+                let seq = seq_arg("reduce", &args[1])?;
+                match seq.split_first() {
+                    Some((first, rest)) => (first.clone(), rest),
+                    None => return eval::apply(&args[0], vec![]),
+                }
+            }
+            _ => (args[1].clone(), seq_arg("reduce", &args[2])?),
+        };
+        for v in items {
+            acc = eval::apply(&args[0], vec![acc, v.clone()])?;
+        }
+        Ok(acc)
+    });
+
+    // -- atoms --
+    define(scope, "atom", |args| {
+        arity("atom", &args, 1, Some(1))?;
+        Ok(Value::Atom(Rc::new(Atom::new(args[0].clone()))))
+    });
+    define(scope, "deref", |args| {
+        arity("deref", &args, 1, Some(1))?;
+        Ok(atom_arg("deref", &args[0])?.get())
+    });
+    define(scope, "reset!", |args| {
+        arity("reset!", &args, 2, Some(2))?;
+        let atom = atom_arg("reset!", &args[0])?;
+        set_atom(atom, args[1].clone())?;
+        Ok(args[1].clone())
+    });
+    define(scope, "swap!", |args| {
+        arity("swap!", &args, 2, None)?;
+        let atom = atom_arg("swap!", &args[0])?;
+        let mut fn_args = vec![atom.get()];
+        fn_args.extend_from_slice(&args[2..]);
+        let new = eval::apply(&args[1], fn_args)?;
+        set_atom(atom, new.clone())?;
+        Ok(new)
+    });
+    define(scope, "compare-and-set!", |args| {
+        arity("compare-and-set!", &args, 3, Some(3))?;
+        let atom = atom_arg("compare-and-set!", &args[0])?;
+        if atom.get() != args[1] {
+            return Ok(Value::Bool(false));
+        }
+        set_atom(atom, args[2].clone())?;
+        Ok(Value::Bool(true))
+    });
+    define(scope, "add-watch", |args| {
+        arity("add-watch", &args, 3, Some(3))?;
+        atom_arg("add-watch", &args[0])?.add_watch(args[1].clone(), args[2].clone());
+        Ok(args[0].clone())
+    });
+    define(scope, "remove-watch", |args| {
+        arity("remove-watch", &args, 2, Some(2))?;
+        atom_arg("remove-watch", &args[0])?.remove_watch(&args[1]);
+        Ok(args[0].clone())
+    });
+
+    // -- errors --
+    define(scope, "throw", |args| {
+        arity("throw", &args, 1, Some(1))?;
+        Err(Box::new(Thrown::new(args[0].clone())))
+    });
+    define(scope, "ex-info", |args| {
+        arity("ex-info", &args, 1, Some(2))?;
+        let data = args.get(1).cloned().unwrap_or(Value::Nil);
+        match data {
+            Value::Map(_) | Value::Nil => (),
+            v => {
+                return Err(se!(
+                    "ex-info expects a map of data, found {}: {}",
+                    v.type_name(),
+                    v
+                )
+                .into())
+            }
+        }
+        Ok(Value::Ex(Rc::new(ExInfo {
+            message: args[0].to_plain_string(),
+            data,
+        })))
+    });
+    define(scope, "ex-message", |args| {
+        arity("ex-message", &args, 1, Some(1))?;
+        match &args[0] {
+            Value::Ex(ex) => Ok(Value::Str(ex.message.clone())),
+            _ => Ok(Value::Nil),
+        }
+    });
+    define(scope, "ex-data", |args| {
+        arity("ex-data", &args, 1, Some(1))?;
+        match &args[0] {
+            Value::Ex(ex) => Ok(ex.data.clone()),
+            _ => Ok(Value::Nil),
+        }
+    });
+
+    // -- strings and io --
+    define(scope, "str", |args| {
+        Ok(Value::Str(
+            args.iter()
+                .map(|v| match v {
+                    Value::Nil => String::new(),
+                    v => v.to_plain_string(),
+                })
+                .collect(),
+        ))
+    });
+    define(scope, "print", |args| {
+        let s = args.iter().map(Value::to_plain_string).collect::<Vec<_>>();
+        print!("{}", s.join(" "));
+        Ok(Value::Nil)
+    });
+    define(scope, "println", |args| {
+        let s = args.iter().map(Value::to_plain_string).collect::<Vec<_>>();
+        println!("{}", s.join(" "));
+        Ok(Value::Nil)
+    });
+
+    // -- memory --
+    // values are reference counted here, so there is never anything to
+    // collect: `(gc)` returns the 0 bytes it freed. It collects on the VM.
+    define(scope, "gc", |args| {
+        arity("gc", &args, 0, Some(0))?;
+        Ok(Value::Num(Num::zero()))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::{read_eval, Scope};
+
+    fn eval(scope: &mut Scope, s: &str) -> String {
+        match read_eval(s, scope) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn atoms() {
+        let mut scope = Scope::new();
+        eval(&mut scope, "(def a (atom 1))");
+        assert_eq!(eval(&mut scope, "[@a (deref a)]"), "[1 1]");
+        assert_eq!(eval(&mut scope, "(swap! a + 2 3)"), "6");
+        assert_eq!(eval(&mut scope, "(swap! a (fn [x] (* x 10)))"), "60");
+        assert_eq!(eval(&mut scope, "(reset! a :k)"), ":k");
+        assert_eq!(eval(&mut scope, "a"), "#<atom :k>");
+        assert_eq!(eval(&mut scope, "(compare-and-set! a :j 1)"), "false");
+        assert_eq!(eval(&mut scope, "@a"), ":k");
+        assert_eq!(eval(&mut scope, "(compare-and-set! a :k 1)"), "true");
+        assert_eq!(eval(&mut scope, "@a"), "1");
+        assert_eq!(
+            eval(&mut scope, "(swap! 1 inc)"),
+            "error: swap! expects an atom, found number: 1"
+        );
+    }
+
+    #[test]
+    fn gc_has_nothing_to_collect() {
+        let mut scope = Scope::new();
+        assert_eq!(eval(&mut scope, "(def v [1 2 3]) (def v nil) (gc)"), "0");
+        assert_eq!(
+            eval(&mut scope, "(gc 1)"),
+            "error: Wrong number of args (1) passed to gc, expected 0"
+        );
+    }
+
+    #[test]
+    fn watches() {
+        let mut scope = Scope::new();
+        eval(
+            &mut scope,
+            "(def a (atom 0)) (def log (atom []))
+             (add-watch a :b (fn [k r old new] (swap! log conj [k (= r a) old new])))
+             (add-watch a :a (fn [k r old new] (swap! log conj [k old new])))",
+        );
+        eval(&mut scope, "(swap! a inc) (reset! a 5)");
+        // watches run in key order
+        assert_eq!(
+            eval(&mut scope, "@log"),
+            "[[:a 0 1] [:b true 0 1] [:a 1 5] [:b true 1 5]]"
+        );
+        eval(
+            &mut scope,
+            "(reset! log []) (remove-watch a :a) (compare-and-set! a 5 6) (compare-and-set! a 0 7)",
+        );
+        assert_eq!(eval(&mut scope, "@log"), "[[:b true 5 6]]");
+    }
+}
@@ -0,0 +1,908 @@
+//! Compiler from rok forms to VM operations.
+//!
+//! `compile` turns a program into assembly for the register VM, covering a
+//! first-order subset of the language:
+//!
+//! - number, string, `true`, `false`, `nil` and vector constants
+//! - top-level `def`, and top-level `defn` or `def` of a `fn`, whose
+//!   functions are called by name
+//! - `if`, `do`, `let`, `loop`, `recur`, `and` and `or`
+//! - `+ - * / mod inc dec abs = == != < > <= >= not nil? vector count nth`,
+//!   `print`, `println` and `gc`
+//!
+//! Values are tagged, see `rt::value`, and computed with the typed
+//! operations, so numbers keep their exact semantics: small ints promote
+//! to big integers and division makes ratios.
+//!
+//! Registers are allocated as a stack. A function's arguments arrive in
+//! `r1`.., its locals and temporaries take the registers above them, and
+//! it returns its result in `r0`. Callers save their live registers around
+//! a call with `PUSH` and `POP`. A call in tail position of a function
+//! jumps to the callee, so tail recursion runs in constant stack. A
+//! function needing more than the 63 registers above `r0` does not compile.
+//!
+//! The program ends by printing the value of its last form, as `rok -f`
+//! does, and leaving it in `r0`.
+use crate::asm::{self, Assembly, Op, Operation, Program};
+use crate::errors::Result;
+use crate::lang::token;
+use crate::lang::value::{self, List, Num, Value};
+use crate::rt::proc::REGISTERS;
+use crate::rt::sys;
+use crate::rt::value::Value as Tagged;
+use num::ToPrimitive;
+use std::collections::HashMap;
+use std::mem;
+
+type Reg = u8;
+
+/// An operation waiting for its labels to be resolved
+struct Inst {
+    label: Option<String>,
+    code: Op,
+    args: Vec<String>,
+}
+
+/// Operations emitted so far, with the label of the next one
+#[derive(Default)]
+struct Code {
+    insts: Vec<Inst>,
+    label: Option<String>,
+}
+
+/// Registers and bindings of the function being compiled
+#[derive(Default)]
+struct Frame {
+    /// Name of the function, `None` in the main program
+    name: Option<String>,
+    locals: Vec<(String, Reg)>,
+    /// Lowest free register
+    next: Reg,
+    /// Label and registers of the innermost `loop` or function, for `recur`
+    recur: Option<(String, Vec<Reg>)>,
+    /// Whether the tail position of the innermost `loop` is also that of
+    /// the function, so a call there can reuse the function's frame
+    tail_calls: bool,
+}
+
+/// A top-level definition
+enum Def<'a> {
+    Var(&'a str, Option<&'a Value>),
+    Fn(&'a str, &'a List, &'a [Value]),
+}
+
+#[derive(Default)]
+struct Compiler {
+    asm: Assembly,
+    code: Code,
+    frame: Frame,
+    /// Labels placed at the same operation as an earlier one: name -> that label
+    aliases: HashMap<String, String>,
+    labels: usize,
+    /// Top-level functions: name -> arity
+    functions: HashMap<String, usize>,
+    /// Globals, and whether their `def` has been compiled
+    globals: HashMap<String, bool>,
+    /// String constants: contents -> data label
+    strings: HashMap<String, String>,
+}
+
+/// Compile source text to a program, see `compile`
+pub fn compile_str(s: &str) -> Result<Program> {
+    let forms = value::parse_file(token::lex(s)?)?;
+    asm::translate(&compile(&forms)?)
+}
+
+/// Compile a program's top-level forms to assembly
+pub fn compile(forms: &[Value]) -> Result<Assembly> {
+    let mut c = Compiler::default();
+    let defs = forms.iter().map(definition).collect::<Result<Vec<_>>>()?;
+    for def in defs.iter().flatten() {
+        c.declare(def)?;
+    }
+
+    for def in defs.iter().flatten() {
+        if let Def::Fn(name, params, body) = *def {
+            c.function(name, params, body)?;
+        }
+    }
+    let functions = mem::take(&mut c.code);
+
+    c.frame = Frame {
+        next: 1,
+        ..Frame::default()
+    };
+    let result = c.alloc()?;
+    let mut printed = false;
+    for (form, def) in forms.iter().zip(&defs) {
+        match *def {
+            Some(Def::Fn(..)) => {}
+            Some(Def::Var(name, value)) => c.def(name, value, result)?,
+            None => c.expr(form, result, false)?,
+        }
+        printed = !matches!(def, Some(Def::Fn(..)));
+    }
+    if printed {
+        c.print_value(result, true);
+        c.print_str("\n");
+        c.mov(result, 0);
+    }
+    c.emit(Op::HLT, vec![]);
+
+    let mut insts = mem::take(&mut c.code.insts);
+    insts.extend(functions.insts);
+    for inst in insts {
+        let args = inst
+            .args
+            .into_iter()
+            .map(|arg| c.aliases.get(&arg).cloned().unwrap_or(arg))
+            .collect();
+        let mut operation = Operation::new(inst.code, args);
+        if let Some(label) = inst.label {
+            operation = operation.with_tag(label);
+        }
+        c.asm.push(operation);
+    }
+    Ok(c.asm)
+}
+
+fn head(items: &List) -> Option<&str> {
+    match items.first() {
+        Some(Value::Symbol(ident)) => Some(ident.name()),
+        _ => None,
+    }
+}
+
+/// The definition `form` makes, if it is a `def` or `defn`
+fn definition(form: &Value) -> Result<Option<Def<'_>>> {
+    let items = match form {
+        Value::List(items) => items,
+        _ => return Ok(None),
+    };
+    let context = match head(items) {
+        Some(name @ "def") | Some(name @ "defn") => name,
+        _ => return Ok(None),
+    };
+    let name = match items.get(1) {
+        Some(Value::Symbol(ident)) => ident.name(),
+        _ => return Err(se!("{} expects a name", context).into()),
+    };
+    if context == "defn" {
+        let (params, body) = fn_parts(&items[2..], "defn")?;
+        return Ok(Some(Def::Fn(name, params, body)));
+    }
+    match &items[2..] {
+        [] => Ok(Some(Def::Var(name, None))),
+        [Value::List(f)] if head(f) == Some("fn") => {
+            let (params, body) = fn_parts(&f[1..], "fn")?;
+            Ok(Some(Def::Fn(name, params, body)))
+        }
+        [value] => Ok(Some(Def::Var(name, Some(value)))),
+        _ => Err(se!("def expects a name and an optional value").into()),
+    }
+}
+
+/// The parameters and body of a function, after its optional name
+fn fn_parts<'a>(args: &'a [Value], context: &str) -> Result<(&'a List, &'a [Value])> {
+    let args = match args.first() {
+        Some(Value::Symbol(_)) => &args[1..],
+        _ => args,
+    };
+    match args.first() {
+        Some(Value::Vector(params)) => Ok((params, &args[1..])),
+        _ => Err(se!("{} expects a parameter vector", context).into()),
+    }
+}
+
+fn symbol<'a>(form: &'a Value, context: &str) -> Result<&'a str> {
+    match form {
+        Value::Symbol(ident) if ident.name() != "&" => Ok(ident.name()),
+        _ => Err(se!(
+            "cannot compile {} binding {}: only symbols are supported",
+            context,
+            form
+        )
+        .into()),
+    }
+}
+
+/// `n` as a small int, if it is one
+fn small_int(n: &Num) -> Option<Tagged> {
+    if n.is_integer() {
+        n.to_integer().to_i64().and_then(Tagged::int)
+    } else {
+        None
+    }
+}
+
+fn arity(name: &str, args: &[Value], expected: usize) -> Result<()> {
+    if args.len() != expected {
+        return Err(se!(
+            "Wrong number of args ({}) passed to {}, expected {}",
+            args.len(),
+            name,
+            expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn reg(r: Reg) -> String {
+    format!("r{}", r)
+}
+
+impl Compiler {
+    fn declare(&mut self, def: &Def) -> Result<()> {
+        match *def {
+            Def::Var(name, _) => {
+                if self.globals.insert(name.to_owned(), false).is_none() {
+                    self.asm
+                        .word(global_label(name), Tagged::Nil.to_bits() as i64);
+                }
+            }
+            Def::Fn(name, params, _) => {
+                for param in params.iter() {
+                    symbol(param, "fn")?;
+                }
+                self.functions.insert(name.to_owned(), params.len());
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, code: Op, args: Vec<String>) {
+        let label = self.code.label.take();
+        self.code.insts.push(Inst { label, code, args });
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    /// Define `label` at the next operation
+    fn place(&mut self, label: String) {
+        match self.code.label {
+            Some(ref other) => {
+                self.aliases.insert(label, other.clone());
+            }
+            None => self.code.label = Some(label),
+        }
+    }
+
+    fn alloc(&mut self) -> Result<Reg> {
+        let r = self.frame.next;
+        if r as usize >= REGISTERS {
+            return Err(se!("cannot compile: out of registers").into());
+        }
+        self.frame.next += 1;
+        Ok(r)
+    }
+
+    /// Free the registers allocated since `mark`
+    fn free(&mut self, mark: Reg) {
+        self.frame.next = mark;
+    }
+
+    fn set(&mut self, dest: Reg, v: Tagged) {
+        self.emit(Op::Set, vec![reg(dest), (v.to_bits() as i64).to_string()]);
+    }
+
+    fn mov(&mut self, src: Reg, dest: Reg) {
+        if src != dest {
+            self.emit(Op::Or, vec![reg(src), reg(src), reg(dest)]);
+        }
+    }
+
+    /// Set `dest` to whether the condition holds, using `tmp`. With
+    /// `negate`, to whether it does not.
+    fn flag(&mut self, dest: Reg, tmp: Reg, negate: bool) {
+        self.set(dest, Tagged::Bool(negate));
+        self.set(tmp, Tagged::Bool(!negate));
+        self.emit(Op::Cmov, vec![reg(tmp), reg(dest)]);
+    }
+
+    /// Jump to `label` if `r` is falsey, or truthy if `truthy`
+    fn branch(&mut self, r: Reg, truthy: bool, label: &str) {
+        self.emit(Op::Ttest, vec![reg(r)]);
+        let jump = if truthy { Op::Jeq } else { Op::Jne };
+        self.emit(jump, vec![label.to_owned()]);
+    }
+
+    fn function(&mut self, name: &str, params: &List, body: &[Value]) -> Result<()> {
+        let label = fn_label(name);
+        let regs = (1..=params.len()).map(|r| r as Reg).collect::<Vec<_>>();
+        self.frame = Frame {
+            name: Some(name.to_owned()),
+            locals: vec![],
+            next: 1,
+            recur: Some((label.clone(), regs.clone())),
+            tail_calls: true,
+        };
+        for (param, &r) in params.iter().zip(&regs) {
+            self.alloc()?;
+            let param = symbol(param, "fn")?.to_owned();
+            self.frame.locals.push((param, r));
+        }
+        self.place(label);
+        let dest = self.alloc()?;
+        self.body(body, dest, true)?;
+        self.mov(dest, 0);
+        self.emit(Op::Ret, vec![]);
+        Ok(())
+    }
+
+    fn def(&mut self, name: &str, value: Option<&Value>, dest: Reg) -> Result<()> {
+        match value {
+            Some(form) => self.expr(form, dest, false)?,
+            None => self.set(dest, Tagged::Nil),
+        }
+        self.globals.insert(name.to_owned(), true);
+        let addr = self.alloc()?;
+        self.emit(Op::Set, vec![reg(addr), global_label(name)]);
+        self.emit(Op::Store64, vec![reg(dest), reg(addr)]);
+        self.free(addr);
+        Ok(())
+    }
+
+    /// Compile `form` to leave its value in `dest`. `tail` is whether the
+    /// form is in tail position of the innermost `loop` or function.
+    fn expr(&mut self, form: &Value, dest: Reg, tail: bool) -> Result<()> {
+        match form {
+            Value::Nil => self.set(dest, Tagged::Nil),
+            Value::Bool(b) => self.set(dest, Tagged::Bool(*b)),
+            Value::Num(n) => match small_int(n) {
+                Some(v) => self.set(dest, v),
+                None => {
+                    let label = self.string(&n.to_string());
+                    self.constant(Op::Tnum, &label, dest);
+                }
+            },
+            Value::Str(s) => {
+                let label = self.string(s);
+                self.constant(Op::Tstr, &label, dest);
+            }
+            Value::Vector(items) => self.vector(items, dest)?,
+            Value::Symbol(ident) => self.symbol(ident.name(), dest)?,
+            Value::List(items) if !items.is_empty() => self.list(items, dest, tail)?,
+            _ => return Err(se!("cannot compile {}: {}", form.type_name(), form).into()),
+        }
+        Ok(())
+    }
+
+    /// Make a value with `TNUM` or `TSTR` from a string constant
+    fn constant(&mut self, op: Op, label: &str, dest: Reg) {
+        self.emit(Op::Set, vec![reg(dest), label.to_owned()]);
+        self.emit(op, vec![reg(dest), reg(dest)]);
+    }
+
+    fn vector(&mut self, items: &[Value], dest: Reg) -> Result<()> {
+        // build the list in a temporary, the items may refer to `dest`
+        let list = self.alloc()?;
+        self.emit(Op::Tlist, vec![reg(list)]);
+        let item = self.alloc()?;
+        for form in items {
+            self.expr(form, item, false)?;
+            self.emit(Op::Tpush, vec![reg(list), reg(item)]);
+        }
+        self.mov(list, dest);
+        self.free(list);
+        Ok(())
+    }
+
+    fn local(&self, name: &str) -> Option<Reg> {
+        self.frame
+            .locals
+            .iter()
+            .rev()
+            .find(|(local, _)| local == name)
+            .map(|&(_, r)| r)
+    }
+
+    fn symbol(&mut self, name: &str, dest: Reg) -> Result<()> {
+        if let Some(r) = self.local(name) {
+            self.mov(r, dest);
+            return Ok(());
+        }
+        // functions may run after any `def`
+        let defined = self.globals.get(name).copied();
+        if defined == Some(true) || (defined.is_some() && self.frame.name.is_some()) {
+            self.emit(Op::Set, vec![reg(dest), global_label(name)]);
+            self.emit(Op::Load64, vec![reg(dest), reg(dest)]);
+            return Ok(());
+        }
+        if self.functions.contains_key(name) {
+            return Err(se!("cannot compile {}: functions can only be called", name).into());
+        }
+        Err(se!("Unbound symbol: {}", name).into())
+    }
+
+    fn list(&mut self, items: &List, dest: Reg, tail: bool) -> Result<()> {
+        let name = match head(items) {
+            Some(name) => name,
+            None => {
+                return Err(se!(
+                    "cannot compile {}: only calls by name are supported",
+                    items[0]
+                )
+                .into())
+            }
+        };
+        let args = &items[1..];
+        match name {
+            "if" => self.if_form(args, dest, tail),
+            "do" => self.body(args, dest, tail),
+            "let" => self.let_form(args, dest, tail, false),
+            "loop" => self.let_form(args, dest, tail, true),
+            "recur" => self.recur(args, tail),
+            "and" => self.and_or(args, dest, tail, true),
+            "or" => self.and_or(args, dest, tail, false),
+            "def" | "defn" | "fn" => {
+                Err(se!("cannot compile {}: only supported at the top level", name).into())
+            }
+            _ if self.local(name).is_some() => {
+                Err(se!("cannot compile a call to local {}", name).into())
+            }
+            _ if self.functions.contains_key(name) => self.call(name, args, dest, tail),
+            _ => self.builtin(name, args, dest),
+        }
+    }
+
+    fn body(&mut self, forms: &[Value], dest: Reg, tail: bool) -> Result<()> {
+        let (last, init) = match forms.split_last() {
+            Some(split) => split,
+            None => {
+                self.set(dest, Tagged::Nil);
+                return Ok(());
+            }
+        };
+        for form in init {
+            self.expr(form, dest, false)?;
+        }
+        self.expr(last, dest, tail)
+    }
+
+    fn if_form(&mut self, args: &[Value], dest: Reg, tail: bool) -> Result<()> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                se!("if expects a condition, a then branch and an optional else branch").into(),
+            );
+        }
+        let (other, end) = (self.new_label(), self.new_label());
+        self.expr(&args[0], dest, false)?;
+        self.branch(dest, false, &other);
+        self.expr(&args[1], dest, tail)?;
+        self.emit(Op::Jmp, vec![end.clone()]);
+        self.place(other);
+        match args.get(2) {
+            Some(form) => self.expr(form, dest, tail)?,
+            None => self.set(dest, Tagged::Nil),
+        }
+        self.place(end);
+        Ok(())
+    }
+
+    fn and_or(&mut self, args: &[Value], dest: Reg, tail: bool, and: bool) -> Result<()> {
+        if args.is_empty() {
+            let v = if and { Tagged::Bool(true) } else { Tagged::Nil };
+            self.set(dest, v);
+            return Ok(());
+        }
+        let end = self.new_label();
+        for (i, form) in args.iter().enumerate() {
+            let last = i + 1 == args.len();
+            self.expr(form, dest, tail && last)?;
+            if !last {
+                self.branch(dest, !and, &end);
+            }
+        }
+        self.place(end);
+        Ok(())
+    }
+
+    fn let_form(&mut self, args: &[Value], dest: Reg, tail: bool, is_loop: bool) -> Result<()> {
+        let context = if is_loop { "loop" } else { "let" };
+        let bindings = match args.first() {
+            Some(Value::Vector(bindings)) => bindings,
+            _ => return Err(se!("{} expects a binding vector", context).into()),
+        };
+        if !bindings.len().is_multiple_of(2) {
+            return Err(se!(
+                "{} expects an even number of forms in its binding vector",
+                context
+            )
+            .into());
+        }
+        let (mark, locals) = (self.frame.next, self.frame.locals.len());
+        let mut regs = vec![];
+        for pair in bindings.chunks(2) {
+            let name = symbol(&pair[0], context)?;
+            let r = self.alloc()?;
+            self.expr(&pair[1], r, false)?;
+            self.frame.locals.push((name.to_owned(), r));
+            regs.push(r);
+        }
+        let result = if is_loop {
+            let start = self.new_label();
+            self.place(start.clone());
+            let outer = self.frame.recur.replace((start, regs));
+            let tail_calls = self.frame.tail_calls;
+            self.frame.tail_calls = tail && tail_calls;
+            let result = self.body(&args[1..], dest, true);
+            self.frame.recur = outer;
+            self.frame.tail_calls = tail_calls;
+            result
+        } else {
+            self.body(&args[1..], dest, tail)
+        };
+        self.frame.locals.truncate(locals);
+        self.free(mark);
+        result
+    }
+
+    fn recur(&mut self, args: &[Value], tail: bool) -> Result<()> {
+        let (label, regs) = match self.frame.recur {
+            Some(ref recur) if tail => recur.clone(),
+            _ => return Err(se!("recur can only be used in tail position of a fn or loop").into()),
+        };
+        if args.len() != regs.len() {
+            return Err(se!("recur expects {} args, found {}", regs.len(), args.len()).into());
+        }
+        // evaluate every argument before rebinding any
+        let mark = self.frame.next;
+        let mut temps = vec![];
+        for arg in args {
+            let t = self.alloc()?;
+            self.expr(arg, t, false)?;
+            temps.push(t);
+        }
+        for (&t, &r) in temps.iter().zip(&regs) {
+            self.mov(t, r);
+        }
+        self.emit(Op::Jmp, vec![label]);
+        self.free(mark);
+        Ok(())
+    }
+
+    /// Call a top-level function. A call in tail position of a function
+    /// jumps instead, leaving the callee to return to the caller's caller.
+    fn call(&mut self, name: &str, args: &[Value], dest: Reg, tail: bool) -> Result<()> {
+        arity(name, args, self.functions[name])?;
+        let mark = self.frame.next;
+        let mut temps = vec![];
+        for arg in args {
+            let t = self.alloc()?;
+            self.expr(arg, t, false)?;
+            temps.push(t);
+        }
+        if tail && self.frame.tail_calls {
+            // no register is live after a tail call
+            for (i, &t) in temps.iter().enumerate() {
+                self.mov(t, i as Reg + 1);
+            }
+            self.emit(Op::Jmp, vec![fn_label(name)]);
+            self.free(mark);
+            return Ok(());
+        }
+        for r in 1..mark {
+            self.emit(Op::Push, vec![reg(r)]);
+        }
+        // each argument register is at or below its temporary, and those
+        // below were already moved
+        for (i, &t) in temps.iter().enumerate() {
+            self.mov(t, i as Reg + 1);
+        }
+        self.emit(Op::Call, vec![fn_label(name)]);
+        for r in (1..mark).rev() {
+            self.emit(Op::Pop, vec![reg(r)]);
+        }
+        self.mov(0, dest);
+        self.free(mark);
+        Ok(())
+    }
+
+    /// Apply the typed arithmetic `op` to `dest` and each value of `forms`
+    fn fold(&mut self, op: Op, forms: &[Value], dest: Reg) -> Result<()> {
+        let t = self.alloc()?;
+        for form in forms {
+            self.expr(form, t, false)?;
+            self.emit(op, vec![reg(dest), reg(t), reg(dest)]);
+        }
+        Ok(())
+    }
+
+    fn builtin(&mut self, name: &str, args: &[Value], dest: Reg) -> Result<()> {
+        let mark = self.frame.next;
+        match name {
+            "+" | "*" => {
+                let (op, identity) = if name == "+" {
+                    (Op::Tadd, 0)
+                } else {
+                    (Op::Tmul, 1)
+                };
+                match args.split_first() {
+                    Some((first, rest)) => {
+                        self.expr(first, dest, false)?;
+                        self.fold(op, rest, dest)?;
+                    }
+                    None => self.set(dest, Tagged::Int(identity)),
+                }
+            }
+            "-" | "/" => {
+                let op = if name == "-" { Op::Tsub } else { Op::Tdiv };
+                match args.split_first() {
+                    None => return Err(se!("Wrong number of args (0) passed to {}", name).into()),
+                    // `(- x)` is `(- 0 x)` and `(/ x)` is `(/ 1 x)`
+                    Some((_, [])) => {
+                        self.set(dest, Tagged::Int((name == "/") as i64));
+                        self.fold(op, args, dest)?;
+                    }
+                    Some((first, rest)) => {
+                        self.expr(first, dest, false)?;
+                        self.fold(op, rest, dest)?;
+                    }
+                }
+            }
+            "mod" => {
+                arity(name, args, 2)?;
+                self.expr(&args[0], dest, false)?;
+                self.fold(Op::Tmod, &args[1..], dest)?;
+            }
+            "inc" | "dec" => {
+                arity(name, args, 1)?;
+                self.expr(&args[0], dest, false)?;
+                let one = self.alloc()?;
+                self.set(one, Tagged::Int(1));
+                let op = if name == "inc" { Op::Tadd } else { Op::Tsub };
+                self.emit(op, vec![reg(dest), reg(one), reg(dest)]);
+            }
+            "abs" => {
+                arity(name, args, 1)?;
+                self.expr(&args[0], dest, false)?;
+                let t = self.alloc()?;
+                self.emit(Op::Tneg, vec![reg(dest), reg(t)]);
+                self.emit(Op::Tlt, vec![reg(dest), reg(t)]);
+                self.emit(Op::Cmov, vec![reg(t), reg(dest)]);
+            }
+            "=" | "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                arity(name, args, 2)?;
+                let op = match name {
+                    "=" | "==" | "!=" => Op::Teq,
+                    "<" => Op::Tlt,
+                    ">" => Op::Tgt,
+                    "<=" => Op::Tlte,
+                    _ => Op::Tgte,
+                };
+                self.expr(&args[0], dest, false)?;
+                let t = self.alloc()?;
+                self.expr(&args[1], t, false)?;
+                self.emit(op, vec![reg(dest), reg(t)]);
+                self.flag(dest, t, name == "!=");
+            }
+            "not" => {
+                arity(name, args, 1)?;
+                self.expr(&args[0], dest, false)?;
+                let t = self.alloc()?;
+                self.emit(Op::Ttest, vec![reg(dest)]);
+                self.flag(dest, t, true);
+            }
+            "nil?" => {
+                arity(name, args, 1)?;
+                self.expr(&args[0], dest, false)?;
+                let t = self.alloc()?;
+                self.set(t, Tagged::Nil);
+                self.emit(Op::Teq, vec![reg(dest), reg(t)]);
+                self.flag(dest, t, false);
+            }
+            "vector" => self.vector(args, dest)?,
+            "count" => {
+                arity(name, args, 1)?;
+                self.expr(&args[0], dest, false)?;
+                self.emit(Op::Tlen, vec![reg(dest), reg(dest)]);
+            }
+            "nth" => {
+                arity(name, args, 2)?;
+                self.expr(&args[0], dest, false)?;
+                let t = self.alloc()?;
+                self.expr(&args[1], t, false)?;
+                self.emit(Op::Tget, vec![reg(dest), reg(t), reg(dest)]);
+            }
+            "print" | "println" => {
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.print_str(" ");
+                    }
+                    self.expr(arg, dest, false)?;
+                    self.print_value(dest, false);
+                }
+                if name == "println" {
+                    self.print_str("\n");
+                }
+                self.set(dest, Tagged::Nil);
+            }
+            "gc" => {
+                arity(name, args, 0)?;
+                self.emit(Op::Gc, vec![]);
+                self.set(dest, Tagged::Nil);
+            }
+            _ => return Err(se!("cannot compile {}: not supported", name).into()),
+        }
+        self.free(mark);
+        Ok(())
+    }
+
+    /// Label of a string constant in the data
+    fn string(&mut self, s: &str) -> String {
+        if let Some(label) = self.strings.get(s) {
+            return label.clone();
+        }
+        let label = format!("str.{}", self.strings.len());
+        self.asm.asciz(label.clone(), s);
+        self.strings.insert(s.to_owned(), label.clone());
+        label
+    }
+
+    fn print_str(&mut self, s: &str) {
+        let label = self.string(s);
+        self.emit(Op::Push, vec![reg(1)]);
+        self.emit(Op::Set, vec![reg(1), label]);
+        self.emit(Op::Syscall, vec![sys::PRINT_STR.to_string()]);
+        self.emit(Op::Pop, vec![reg(1)]);
+    }
+
+    /// Print the value in `r`, quoting strings if `readable`
+    fn print_value(&mut self, r: Reg, readable: bool) {
+        self.emit(Op::Push, vec![reg(1)]);
+        self.emit(Op::Push, vec![reg(2)]);
+        self.mov(r, 1);
+        self.emit(Op::Set, vec![reg(2), (readable as u8).to_string()]);
+        self.emit(Op::Syscall, vec![sys::PRINT_VAL.to_string()]);
+        self.emit(Op::Pop, vec![reg(2)]);
+        self.emit(Op::Pop, vec![reg(1)]);
+    }
+}
+
+fn fn_label(name: &str) -> String {
+    format!("fn.{}", name)
+}
+
+fn global_label(name: &str) -> String {
+    format!("var.{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::{read_eval, Scope};
+    use crate::rt::sys::{Host, Mode};
+    use crate::rt::Runtime;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects stdout
+    struct Output(Vec<u8>);
+    impl Host for Output {
+        fn read(&mut self, _: u64, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+        fn write(&mut self, _: u64, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn read_line(&mut self) -> io::Result<Option<String>> {
+            Ok(None)
+        }
+        fn open(&mut self, _: &str, _: Mode) -> io::Result<u64> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+        fn close(&mut self, _: u64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run a compiled program, returning its output and `r0`
+    fn run(src: &str) -> (String, Tagged) {
+        let output = Arc::new(Mutex::new(Output(vec![])));
+        let mut runtime = Runtime::new(compile_str(src).unwrap());
+        runtime.set_host(output.clone());
+        runtime.run_to_completion().unwrap();
+        let out = String::from_utf8(output.lock().unwrap().0.clone()).unwrap();
+        (
+            out,
+            Tagged::from_bits(runtime.processes()[0].registers()[0]),
+        )
+    }
+
+    #[test]
+    fn same_results_as_eval() {
+        let programs = [
+            "(+ 1 2 (* 3 4) (- 10) (- 10 4 3))",
+            "(let [x 5 y (* x 2)] (if (> y x) (- y x) 0))",
+            "(defn fib [n] (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))) (fib 15)",
+            "(defn fact [n acc] (if (= n 0) acc (recur (dec n) (* n acc)))) (fact 30 1)",
+            "(loop [i 0 acc 0] (if (< i 10) (recur (inc i) (+ acc (* i i))) acc))",
+            "(def base 7) (defn add-base [x] (+ x base)) (add-base (add-base 1))",
+            "(def f (fn [a b c] (- a (* b c)))) (f 100 (f 9 2 3) 4)",
+            "[(mod 7 3) (mod -7 3) (mod 7 -3) (mod -7 -3) (abs -9) (abs (/ -1 2))]",
+            "(defn even? [n] (= 0 (mod n 2))) (and (even? 4) (not (even? 5)))",
+            "(or false nil)",
+            "(and)",
+            "(if false 1)",
+            "(def x 1) (do (<= x 1))",
+            "(+ (/ 1 3) (/ 1 6))",
+            "(/ 10 4 5)",
+            "(* 140737488355327 140737488355327 -3)",
+            "(- (+ 140737488355327 1) 1)",
+            "123456789012345678901234567890",
+            "[1 \"two\" [nil true] (count \"three\") (nth [4 5] 1) (vector)]",
+            "[(= [1 (/ 2 2)] [1 1]) (!= \"a\" \"a\") (nil? nil) (nil? false) (not 0)]",
+            "(loop [i 0 v []] (if (< i 100) (recur (inc i) [i v]) (do (gc) (nth (nth v 1) 0))))",
+        ];
+        for src in &programs {
+            let expected = read_eval(src, &mut Scope::new()).unwrap();
+            assert_eq!(run(src).0, format!("{}\n", expected), "{}", src);
+        }
+        assert_eq!(run("(- 3 10)").1, Tagged::Int(-7));
+    }
+
+    #[test]
+    fn printing() {
+        let src = "(println \"sum:\" (+ 1 2) true nil [\"x\"])\n(print \"no newline\")\n\"done\"";
+        assert_eq!(run(src).0, "sum: 3 true nil [\"x\"]\nno newline\"done\"\n");
+        // deep recursion and many live registers across calls
+        let src = "(defn down [n] (if (= n 0) 0 (+ 1 (down (dec n)))))\n\
+                   (let [a 1 b 2 c 3] (+ a b c (down 1000) a b c))";
+        assert_eq!(run(src).0, "1012\n");
+    }
+
+    #[test]
+    fn tail_calls() {
+        let src =
+            "(defn loopy [n acc] (if (= n 0) acc (loopy (- n 1) (+ acc 1)))) (loopy 100000 0)";
+        assert_eq!(run(src).0, "100000\n");
+        // mutual recursion, and calls in tail position of a loop in tail position
+        let src = "(defn even? [n] (if (= n 0) true (odd? (dec n))))\n\
+                   (defn odd? [n] (if (= n 0) false (loop [m n] (even? (dec m)))))\n\
+                   (even? 100001)";
+        assert_eq!(run(src).0, "false\n");
+        // calls in tail position of an inner loop, or of the main program, still return
+        let src = "(defn id [x] x)\n\
+                   (defn f [n] (+ 1 (loop [i n] (id i))))\n\
+                   (loop [i 2] (if (< i 3) (recur (inc i)) (id (f i))))";
+        assert_eq!(run(src).0, "4\n");
+        let code = compile_str("(defn g [n] (if (= n 0) 0 (g (dec n))))").unwrap();
+        let calls = asm::disassemble(&code.code)
+            .iter()
+            .filter(|op| op.code() == Op::Call)
+            .count();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |src: &str| compile_str(src).unwrap_err().to_string();
+        assert_eq!(error("(+ 1 y)"), "error: Unbound symbol: y");
+        assert_eq!(
+            error("(defn f [a] a) (f 1 2)"),
+            "error: Wrong number of args (2) passed to f, expected 1"
+        );
+        assert_eq!(
+            error("(str 1 2)"),
+            "error: cannot compile str: not supported"
+        );
+        assert_eq!(
+            error("(+ 1 (recur 2))"),
+            "error: recur can only be used in tail position of a fn or loop"
+        );
+        assert_eq!(
+            error("(let [f (fn [x] x)] 1)"),
+            "error: cannot compile fn: only supported at the top level"
+        );
+        assert_eq!(
+            error("(defn f [] 1) (+ f 1)"),
+            "error: cannot compile f: functions can only be called"
+        );
+        assert_eq!(error("{:a 1}"), "error: cannot compile map: {:a 1}");
+    }
+}
@@ -0,0 +1,684 @@
+use crate::errors::Result;
+use crate::lang::exception;
+use crate::lang::module;
+use crate::lang::pattern;
+use crate::lang::value::{Function, Ident, List, Value};
+use crate::lang::Scope;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+/// Evaluations that may be nested before `eval` fails with a stack
+/// overflow error. Run deep programs on a thread with `lang::STACK_SIZE`.
+pub const MAX_DEPTH: usize = 10_000;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// One level of nested evaluation, released when dropped
+struct Depth;
+impl Depth {
+    fn enter() -> Result<Self> {
+        let depth = DEPTH.with(|d| d.get());
+        if depth >= MAX_DEPTH {
+            return Err(se!(
+                "Stack overflow: evaluation nested more than {} deep",
+                MAX_DEPTH
+            )
+            .into());
+        }
+        DEPTH.with(|d| d.set(depth + 1));
+        Ok(Depth)
+    }
+}
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+/// The next thing the trampoline in `run` should do. Forms in tail position
+/// are returned as a step instead of being evaluated recursively, so tail
+/// calls and `recur` run in constant rust stack.
+enum Step {
+    Done(Value),
+    /// Evaluate a form in tail position
+    Eval(Value, Scope),
+    /// Tail call a closure
+    Call(Rc<Function>, Vec<Value>),
+    /// Enter a `loop` body whose initial bindings are already in scope
+    Loop(Frame, Scope),
+    /// Rebind the innermost `fn` or `loop` and run its body again
+    Recur(Vec<Value>),
+}
+
+/// The innermost `fn` or `loop`, which `recur` jumps back to
+struct Frame {
+    params: List,
+    body: List,
+    scope: Scope,
+    func: Option<Rc<Function>>,
+    /// Name shown in stack traces
+    name: Option<String>,
+}
+impl Frame {
+    fn from_fn(func: &Rc<Function>) -> Self {
+        Self {
+            params: func.params.clone(),
+            body: func.body.clone(),
+            scope: func.scope.clone(),
+            func: Some(func.clone()),
+            name: Some(
+                func.ident
+                    .as_ref()
+                    .map_or("fn", |ident| ident.name())
+                    .to_owned(),
+            ),
+        }
+    }
+
+    /// Create a fresh scope with the frame's params bound to `args`
+    fn bind(&self, args: Vec<Value>) -> Result<Scope> {
+        let scope = self.scope.child();
+        match self.func {
+            Some(ref func) => {
+                if let Some(ref ident) = func.ident {
+                    scope.define(ident.name(), Value::Func(func.clone()));
+                }
+                check_arity(func, args.len())?;
+            }
+            None => {
+                if args.len() != self.params.len() {
+                    return Err(se!(
+                        "Mismatched argument count to recur, expected: {} args, got: {}",
+                        self.params.len(),
+                        args.len()
+                    )
+                    .into());
+                }
+            }
+        }
+        pattern::bind(
+            &Value::Vector(self.params.clone()),
+            &Value::Vector(args.into()),
+            &scope,
+        )?;
+        Ok(scope)
+    }
+}
+
+fn run(step: Step) -> Result<Value> {
+    let _depth = Depth::enter()?;
+    let mut frame: Option<Frame> = None;
+    run_steps(step, &mut frame).map_err(|e| match frame {
+        Some(Frame {
+            name: Some(ref name),
+            ..
+        }) => exception::with_frame(e, name),
+        _ => e,
+    })
+}
+
+fn run_steps(mut step: Step, frame: &mut Option<Frame>) -> Result<Value> {
+    loop {
+        step = match step {
+            Step::Done(value) => return Ok(value),
+            Step::Eval(form, scope) => eval_step(&form, &scope)?,
+            Step::Call(func, args) => {
+                let f = frame.insert(Frame::from_fn(&func));
+                let scope = f.bind(args)?;
+                body_step(&f.body, scope)?
+            }
+            Step::Loop(mut f, scope) => {
+                // a loop reports errors as part of its enclosing function
+                f.name = frame.as_ref().and_then(|outer| outer.name.clone());
+                let f = frame.insert(f);
+                body_step(&f.body, scope)?
+            }
+            Step::Recur(args) => match frame {
+                Some(ref f) => {
+                    let scope = f.bind(args)?;
+                    body_step(&f.body, scope)?
+                }
+                None => {
+                    return Err(
+                        se!("recur can only be used in tail position of a fn or loop").into(),
+                    );
+                }
+            },
+        }
+    }
+}
+
+/// Evaluate a single form in the given scope
+pub fn eval(form: &Value, scope: &Scope) -> Result<Value> {
+    run(Step::Eval(form.clone(), scope.clone()))
+}
+
+fn eval_step(form: &Value, scope: &Scope) -> Result<Step> {
+    let value = match form {
+        Value::Symbol(ident) => scope
+            .get(ident.name())
+            .or_else(|| module::lookup_qualified(ident.name(), scope))
+            .ok_or_else(|| se!("Unbound symbol: {}", ident.name()))?,
+        Value::List(items) => {
+            if items.is_empty() {
+                return Ok(Step::Done(form.clone()));
+            }
+            if let Value::Symbol(ref head) = items[0] {
+                if let Some(step) = eval_special(head.name(), &items[1..], scope) {
+                    return step;
+                }
+            }
+            let func = eval(&items[0], scope)?;
+            let args = items[1..]
+                .iter()
+                .map(|arg| eval(arg, scope))
+                .collect::<Result<Vec<_>>>()?;
+            match func {
+                Value::Func(func) => return Ok(Step::Call(func, args)),
+                func => apply(&func, args)?,
+            }
+        }
+        Value::Vector(items) => Value::Vector(
+            items
+                .iter()
+                .map(|item| eval(item, scope))
+                .collect::<Result<Vec<_>>>()?
+                .into(),
+        ),
+        Value::Map(map) => {
+            let mut res = BTreeMap::new();
+            for (k, v) in map {
+                res.insert(eval(k, scope)?, eval(v, scope)?);
+            }
+            Value::Map(res)
+        }
+        Value::Set(set) => Value::Set(
+            set.iter()
+                .map(|item| eval(item, scope))
+                .collect::<Result<BTreeSet<_>>>()?,
+        ),
+        _ => form.clone(),
+    };
+    Ok(Step::Done(value))
+}
+
+/// Call a function value with already evaluated arguments
+pub fn apply(func: &Value, args: Vec<Value>) -> Result<Value> {
+    match func {
+        Value::Native(native) => (native.func)(args),
+        Value::Func(func) => run(Step::Call(func.clone(), args)),
+        // keywords and maps act as lookup functions
+        Value::Keyword(_) | Value::Map(_) => {
+            let (map, key) = match func {
+                Value::Keyword(_) => (args.first(), Some(func)),
+                _ => (Some(func), args.first()),
+            };
+            match (map, key) {
+                (Some(Value::Map(m)), Some(k)) => Ok(m.get(k).cloned().unwrap_or(Value::Nil)),
+                (Some(Value::Nil), Some(_)) => Ok(Value::Nil),
+                _ => Err(se!("Invalid lookup call on {}", func).into()),
+            }
+        }
+        _ => Err(se!("Value is not callable: {}", func).into()),
+    }
+}
+
+fn check_arity(func: &Function, count: usize) -> Result<()> {
+    let variadic = func.params.iter().any(|p| *p == Value::symbol("&"));
+    let required = func
+        .params
+        .iter()
+        .take_while(|p| **p != Value::symbol("&"))
+        .count();
+    if count < required || (!variadic && count > required) {
+        let name = func
+            .ident
+            .as_ref()
+            .map(|i| i.name().to_owned())
+            .unwrap_or_else(|| "fn".into());
+        return Err(se!(
+            "Wrong number of args ({}) passed to {}, expected {}{}",
+            count,
+            name,
+            required,
+            if variadic { " or more" } else { "" }
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Evaluate each form in order, returning the value of the last (or `nil`)
+pub fn eval_body(body: &[Value], scope: &Scope) -> Result<Value> {
+    run(body_step(body, scope.clone())?)
+}
+
+/// Evaluate all but the last form of a body, leaving the last in tail position
+fn body_step(body: &[Value], scope: Scope) -> Result<Step> {
+    match body.split_last() {
+        Some((last, init)) => {
+            for form in init {
+                eval(form, &scope)?;
+            }
+            Ok(Step::Eval(last.clone(), scope))
+        }
+        None => Ok(Step::Done(Value::Nil)),
+    }
+}
+
+fn expect_symbol<'a>(form: &'a Value, context: &str) -> Result<&'a Ident> {
+    match form {
+        Value::Symbol(ident) => Ok(ident),
+        _ => Err(se!("{} expects a symbol, found: {}", context, form).into()),
+    }
+}
+
+fn expect_vector<'a>(form: &'a Value, context: &str) -> Result<&'a List> {
+    match form {
+        Value::Vector(items) => Ok(items),
+        _ => Err(se!("{} expects a vector, found: {}", context, form).into()),
+    }
+}
+
+/// Evaluate a special form, returning `None` if `name` is not one
+fn eval_special(name: &str, args: &[Value], scope: &Scope) -> Option<Result<Step>> {
+    let res = match name {
+        "quote" => quote_form(args),
+        "def" => def_form(args, scope),
+        "defn" => defn_form(args, scope),
+        "if" => if_form(args, scope),
+        "do" => body_step(args, scope.clone()),
+        "and" => and_form(args, scope),
+        "or" => or_form(args, scope),
+        "let" => let_form(args, scope),
+        "fn" => fn_form(args, scope).map(Step::Done),
+        "match" => match_form(args, scope),
+        "loop" => loop_form(args, scope),
+        "recur" => recur_form(args, scope),
+        "try" => try_form(args, scope),
+        "ns" => ns_form(args, scope),
+        "require" => require_form(args, scope),
+        _ => return None,
+    };
+    Some(res)
+}
+
+fn quote_form(args: &[Value]) -> Result<Step> {
+    match args {
+        [form] => Ok(Step::Done(form.clone())),
+        _ => Err(se!("quote expects exactly one form").into()),
+    }
+}
+
+fn def_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(se!("def expects a name and an optional value").into());
+    }
+    let ident = expect_symbol(&args[0], "def")?;
+    let value = match args.get(1) {
+        Some(form) => eval(form, scope)?,
+        None => Value::Nil,
+    };
+    scope.define(ident.name(), value.clone());
+    Ok(Step::Done(value))
+}
+
+fn defn_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    if args.len() < 2 {
+        return Err(se!("defn expects a name and a parameter vector").into());
+    }
+    let ident = expect_symbol(&args[0], "defn")?;
+    let func = fn_form(args, scope)?;
+    scope.define(ident.name(), func.clone());
+    Ok(Step::Done(func))
+}
+
+fn if_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    if args.len() < 2 || args.len() > 3 {
+        return Err(
+            se!("if expects a condition, a then branch and an optional else branch").into(),
+        );
+    }
+    let branch = if eval(&args[0], scope)?.is_truthy() {
+        &args[1]
+    } else {
+        match args.get(2) {
+            Some(form) => form,
+            None => return Ok(Step::Done(Value::Nil)),
+        }
+    };
+    Ok(Step::Eval(branch.clone(), scope.clone()))
+}
+
+fn and_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let (last, init) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Step::Done(Value::Bool(true))),
+    };
+    for form in init {
+        let res = eval(form, scope)?;
+        if !res.is_truthy() {
+            return Ok(Step::Done(res));
+        }
+    }
+    Ok(Step::Eval(last.clone(), scope.clone()))
+}
+
+fn or_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let (last, init) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Step::Done(Value::Nil)),
+    };
+    for form in init {
+        let res = eval(form, scope)?;
+        if res.is_truthy() {
+            return Ok(Step::Done(res));
+        }
+    }
+    Ok(Step::Eval(last.clone(), scope.clone()))
+}
+
+/// Evaluate a binding vector in a new child scope, each value seeing the
+/// bindings before it
+fn bind_sequential(bindings: &List, scope: &Scope, context: &str) -> Result<Scope> {
+    if !bindings.len().is_multiple_of(2) {
+        return Err(se!(
+            "{} expects an even number of forms in its binding vector",
+            context
+        )
+        .into());
+    }
+    let scope = scope.child();
+    for pair in bindings.chunks(2) {
+        let value = eval(&pair[1], &scope)?;
+        pattern::bind(&pair[0], &value, &scope)?;
+    }
+    Ok(scope)
+}
+
+fn let_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let bindings = match args.first() {
+        Some(form) => expect_vector(form, "let")?,
+        None => return Err(se!("let expects a binding vector").into()),
+    };
+    let scope = bind_sequential(bindings, scope, "let")?;
+    body_step(&args[1..], scope)
+}
+
+fn fn_form(args: &[Value], scope: &Scope) -> Result<Value> {
+    let (ident, rest) = match args.first() {
+        Some(Value::Symbol(ident)) => (Some(ident.clone()), &args[1..]),
+        _ => (None, args),
+    };
+    let params = match rest.first() {
+        Some(form) => expect_vector(form, "fn")?,
+        None => return Err(se!("fn expects a parameter vector").into()),
+    };
+    let body: List = rest[1..].to_vec().into();
+    let func = Function::new(ident, params.clone(), body, scope.clone());
+    Ok(Value::Func(Rc::new(func)))
+}
+
+fn match_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    if args.is_empty() || args.len().is_multiple_of(2) {
+        return Err(se!("match expects a value followed by pattern/body pairs").into());
+    }
+    let value = eval(&args[0], scope)?;
+    for clause in args[1..].chunks(2) {
+        let mut bindings = vec![];
+        if pattern::matches(&clause[0], &value, &mut bindings)? {
+            let scope = scope.child();
+            for (name, v) in bindings {
+                scope.define(name, v);
+            }
+            return Ok(Step::Eval(clause[1].clone(), scope));
+        }
+    }
+    Err(se!("No match clause matched value: {}", value).into())
+}
+
+fn loop_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let bindings = match args.first() {
+        Some(form) => expect_vector(form, "loop")?,
+        None => return Err(se!("loop expects a binding vector").into()),
+    };
+    let inner = bind_sequential(bindings, scope, "loop")?;
+    let params = bindings.iter().step_by(2).cloned().collect::<Vec<_>>();
+    let frame = Frame {
+        params: params.into(),
+        body: args[1..].to_vec().into(),
+        scope: scope.clone(),
+        func: None,
+        name: None,
+    };
+    Ok(Step::Loop(frame, inner))
+}
+
+fn recur_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let args = args
+        .iter()
+        .map(|arg| eval(arg, scope))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Step::Recur(args))
+}
+
+fn is_clause(form: &Value, name: &str) -> bool {
+    match form {
+        Value::List(items) => items.first() == Some(&Value::symbol(name)),
+        _ => false,
+    }
+}
+
+/// `(try body... (catch e handler...) (finally cleanup...))`
+///
+/// The catch clause binds the thrown value (or an ex-info describing a
+/// builtin error) to `e`. Either clause is optional. The finally clause runs
+/// however the try is exited and its value is discarded.
+fn try_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let body_len = args
+        .iter()
+        .position(|form| is_clause(form, "catch") || is_clause(form, "finally"))
+        .unwrap_or(args.len());
+    let (body, clauses) = args.split_at(body_len);
+    let mut catch = None;
+    let mut finally = None;
+    for clause in clauses {
+        let items = match clause {
+            Value::List(items) => items,
+            _ => return Err(se!("try expects catch and finally clauses last").into()),
+        };
+        if is_clause(clause, "catch") && catch.is_none() && finally.is_none() {
+            if items.len() < 2 {
+                return Err(se!("catch expects a binding for the caught value").into());
+            }
+            catch = Some((&items[1], &items[2..]));
+        } else if is_clause(clause, "finally") && finally.is_none() {
+            finally = Some(&items[1..]);
+        } else {
+            return Err(
+                se!("try expects at most one catch followed by at most one finally").into(),
+            );
+        }
+    }
+
+    let res = match (eval_body(body, scope), catch) {
+        (Err(e), Some((binding, handler))) => {
+            let scope = scope.child();
+            pattern::bind(binding, &exception::to_value(e), &scope)
+                .and_then(|_| eval_body(handler, &scope))
+        }
+        (res, _) => res,
+    };
+    if let Some(cleanup) = finally {
+        eval_body(cleanup, scope)?;
+    }
+    res.map(Step::Done)
+}
+
+fn ns_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    match args {
+        [Value::Symbol(ident)] => {
+            module::declare(ident.name(), scope);
+            Ok(Step::Done(Value::Nil))
+        }
+        _ => Err(se!("ns expects a namespace name").into()),
+    }
+}
+
+/// `(require foo.bar)`, `(require foo.bar :as fb)` or `(require foo.bar :refer [a b])`
+fn require_form(args: &[Value], scope: &Scope) -> Result<Step> {
+    let name = match args.first() {
+        Some(form) => expect_symbol(form, "require")?,
+        None => return Err(se!("require expects a module name").into()),
+    };
+    let mut alias = None;
+    let mut refer = None;
+    for opt in args[1..].chunks(2) {
+        match opt {
+            [Value::Keyword(k), Value::Symbol(a)] if k == "as" => alias = Some(a.name()),
+            [Value::Keyword(k), v @ Value::Vector(_)] if k == "refer" => refer = Some(v),
+            _ => {
+                return Err(se!(
+                    "Invalid require option for {}, expected :as name or :refer [names]",
+                    name.name()
+                )
+                .into());
+            }
+        }
+    }
+    let module = module::require(name.name(), alias, scope)?;
+    if let Some(Value::Vector(names)) = refer {
+        for n in names.iter() {
+            let ident = expect_symbol(n, "require :refer")?;
+            let value = module
+                .get(ident.name())
+                .ok_or_else(|| se!("{} does not define {}", name.name(), ident.name()))?;
+            scope.define(ident.name(), value);
+        }
+    }
+    Ok(Step::Done(Value::Nil))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::{read_eval, Scope};
+
+    fn eval(s: &str) -> String {
+        match read_eval(s, &mut Scope::new()) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn destructuring() {
+        assert_eq!(
+            eval("(let [[a b & rest :as all] [1 2 3 4]] [a b rest all])"),
+            "[1 2 (3 4) [1 2 3 4]]"
+        );
+        assert_eq!(
+            eval("(let [{:keys [x y] :or {y 10}} {:x 1}] (+ x y))"),
+            "11"
+        );
+        assert_eq!(
+            eval("(defn dist [[x1 y1] {:keys [x y]}] (+ (- x x1) (- y y1))) (dist [1 2] {:x 4 :y 6})"),
+            "7"
+        );
+        assert_eq!(
+            eval("(let [{:keys [b]} [2]] b)"),
+            "error: Cannot destructure vector as a map: [2]"
+        );
+        assert_eq!(eval("((fn [_ & more] more) 1 2 3)"), "(2 3)");
+    }
+
+    #[test]
+    fn match_clauses() {
+        let src = "(defn describe [v]
+                     (match v
+                       0 :zero
+                       \"s\" :string
+                       [x] [:one x]
+                       [x & xs] [:many x xs]
+                       {:op :add :args [a b]} (+ a b)
+                       _ :other))";
+        let mut scope = Scope::new();
+        read_eval(src, &mut scope).unwrap();
+        let describe = |arg: &str| {
+            read_eval(&format!("(describe {})", arg), &mut scope.clone())
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(describe("0"), ":zero");
+        assert_eq!(describe("\"s\""), ":string");
+        assert_eq!(describe("[5]"), "[:one 5]");
+        assert_eq!(describe("[5 6 7]"), "[:many 5 (6 7)]");
+        assert_eq!(describe("{:op :add :args [2 3]}"), "5");
+        assert_eq!(describe("{:op :sub}"), ":other");
+        assert_eq!(
+            eval("(match [1 2] [x] x {:a a} a)"),
+            "error: No match clause matched value: [1 2]"
+        );
+        assert_eq!(
+            eval("(match 1 2)"),
+            "error: match expects a value followed by pattern/body pairs"
+        );
+    }
+
+    /// Deeper than `MAX_DEPTH`, so these only pass in constant stack
+    #[test]
+    fn tail_calls() {
+        assert_eq!(
+            eval("(defn count-down [n acc] (if (= n 0) acc (count-down (- n 1) (+ acc 1)))) (count-down 20000 0)"),
+            "20000"
+        );
+        assert_eq!(
+            eval(
+                "(defn ev? [n] (if (= n 0) true (od? (- n 1))))
+                  (defn od? [n] (if (= n 0) false (ev? (- n 1))))
+                  [(ev? 20001) (od? 20001)]"
+            ),
+            "[false true]"
+        );
+        assert_eq!(
+            eval("(loop [i 0] (if (< i 100000) (recur (inc i)) i))"),
+            "100000"
+        );
+        assert_eq!(
+            eval("(defn f [n] (if (> n 0) (recur (dec n)) :done)) (f 20000)"),
+            ":done"
+        );
+    }
+
+    #[test]
+    fn recur_outside_tail_position() {
+        assert_eq!(
+            eval("(loop [i 0] (+ 1 (recur i)))"),
+            "error: recur can only be used in tail position of a fn or loop"
+        );
+        assert_eq!(
+            eval("(recur 1)"),
+            "error: recur can only be used in tail position of a fn or loop"
+        );
+        assert_eq!(
+            eval("(loop [a 1 b 2] (recur 1))"),
+            "error: Mismatched argument count to recur, expected: 2 args, got: 1"
+        );
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        let src = "(defn down [n] (if (= n 0) 0 (+ 1 (down (- n 1)))))
+                   [(down 5000)
+                    (try (down 20000) (catch e (ex-message e)))]";
+        assert_eq!(
+            crate::lang::with_stack(|| eval(src)),
+            format!(
+                "[5000 \"Stack overflow: evaluation nested more than {} deep\"]",
+                super::MAX_DEPTH
+            )
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::errors::{Error, StringError};
+use crate::lang::value::{ExInfo, Value};
+use std::fmt;
+use std::rc::Rc;
+
+/// A rok value unwinding the stack, either from `throw` or from a builtin
+/// error that passed through a rok function. `trace` holds the names of the
+/// functions it unwound through, innermost first.
+#[derive(Debug)]
+pub struct Thrown {
+    pub value: Value,
+    pub trace: Vec<String>,
+}
+impl Thrown {
+    pub fn new(value: Value) -> Self {
+        Self {
+            value,
+            trace: vec![],
+        }
+    }
+}
+impl fmt::Display for Thrown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Ex(ref ex) => {
+                write!(f, "error: {}", ex.message)?;
+                if ex.data != Value::Nil {
+                    write!(f, " {}", ex.data)?;
+                }
+            }
+            ref v => write!(f, "error: uncaught value: {}", v)?,
+        }
+        // deep recursion shows as one line per function
+        let mut i = 0;
+        while i < self.trace.len() {
+            let frame = &self.trace[i];
+            let run = self.trace[i..].iter().take_while(|f| *f == frame).count();
+            write!(f, "\n  at {}", frame)?;
+            if run > 1 {
+                write!(f, " ({} times)", run)?;
+            }
+            i += run;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for Thrown {}
+
+/// Convert any error into the value seen by a `catch` handler
+pub fn to_value(e: Error) -> Value {
+    let e = match e.downcast::<Thrown>() {
+        Ok(thrown) => return thrown.value,
+        Err(e) => e,
+    };
+    let message = match e.downcast_ref::<StringError>() {
+        Some(se) => se.0.clone(),
+        None => e.to_string(),
+    };
+    Value::Ex(Rc::new(ExInfo {
+        message,
+        data: Value::Nil,
+    }))
+}
+
+/// Record that `e` unwound through the rok function `name`
+pub fn with_frame(e: Error, name: &str) -> Error {
+    let mut thrown = match e.downcast::<Thrown>() {
+        Ok(thrown) => thrown,
+        Err(e) => Box::new(Thrown::new(to_value(e))),
+    };
+    thrown.trace.push(name.to_owned());
+    thrown
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::{read_eval, Scope};
+
+    fn eval(scope: &mut Scope, s: &str) -> String {
+        match read_eval(s, scope) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn throw_and_catch() {
+        let mut scope = Scope::new();
+        assert_eq!(
+            eval(
+                &mut scope,
+                "(try (throw (ex-info \"bad\" {:code 7})) (catch e [(ex-message e) (ex-data e)]))"
+            ),
+            "[\"bad\" {:code 7}]"
+        );
+        assert_eq!(eval(&mut scope, "(try (throw 42) (catch e (+ e 1)))"), "43");
+        assert_eq!(
+            eval(&mut scope, "(try (throw {:k 1}) (catch {k :k} k))"),
+            "1"
+        );
+        assert_eq!(eval(&mut scope, "(ex-message 1)"), "nil");
+        // builtin errors are caught as ex-info values
+        assert_eq!(
+            eval(
+                &mut scope,
+                "(try (/ 1 0) (catch e [(ex-message e) (ex-data e)]))"
+            ),
+            "[\"Divide by zero\" nil]"
+        );
+        assert_eq!(eval(&mut scope, "(throw :k)"), "error: uncaught value: :k");
+        assert_eq!(
+            eval(&mut scope, "(throw (ex-info \"no\" {:a 1}))"),
+            "error: no {:a 1}"
+        );
+    }
+
+    #[test]
+    fn finally() {
+        let mut scope = Scope::new();
+        eval(
+            &mut scope,
+            "(def log (atom []))
+             (defn note [x] (swap! log conj x))",
+        );
+        assert_eq!(eval(&mut scope, "(try :ok (finally (note :ok)))"), ":ok");
+        assert_eq!(
+            eval(
+                &mut scope,
+                "(try (throw :e) (catch e (note :caught) e) (finally (note :caught-finally)))"
+            ),
+            ":e"
+        );
+        assert_eq!(
+            eval(&mut scope, "(try (/ 1 0) (finally (note :uncaught)))"),
+            "error: Divide by zero"
+        );
+        assert_eq!(
+            eval(&mut scope, "@log"),
+            "[:ok :caught :caught-finally :uncaught]"
+        );
+        // an error from finally replaces the result
+        assert_eq!(
+            eval(
+                &mut scope,
+                "(try 1 (finally (throw (ex-info \"cleanup\" {}))))"
+            ),
+            "error: cleanup {}"
+        );
+        assert_eq!(
+            eval(
+                &mut scope,
+                "(try (throw :first) (finally (throw (ex-info \"second\" {}))))"
+            ),
+            "error: second {}"
+        );
+    }
+
+    #[test]
+    fn stack_trace() {
+        let mut scope = Scope::new();
+        eval(
+            &mut scope,
+            "(defn f [] (throw (ex-info \"boom\" {:x 1})))
+             (defn g [] (+ 1 (f)))
+             (defn h [n] (if (= n 0) (g) (+ 1 (h (dec n)))))",
+        );
+        assert_eq!(
+            eval(&mut scope, "(g)"),
+            "error: boom {:x 1}\n  at f\n  at g"
+        );
+        // `(g)` is a tail call, so the innermost `h` is replaced by it
+        assert_eq!(
+            eval(&mut scope, "(h 3)"),
+            "error: boom {:x 1}\n  at f\n  at g\n  at h (3 times)"
+        );
+        assert_eq!(
+            eval(&mut scope, "(defn k [] (/ 1 0)) (k)"),
+            "error: Divide by zero\n  at k"
+        );
+    }
+}
@@ -1,27 +1,161 @@
+// functions hash and compare by id, so the scope a closure captures
+// never affects its position as a map key
+#![allow(clippy::mutable_key_type)]
+
+pub mod builtins;
+pub mod compile;
+pub mod eval;
+pub mod exception;
+pub mod module;
+pub mod native;
+pub mod pattern;
 pub mod token;
 pub mod value;
 
 use crate::errors::Result;
 use rustyline::error::ReadlineError;
-use std::{fs, path};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{fs, path, thread};
 use value::Value;
 
-pub struct Scope {}
+/// A lexical environment. Cloning a `Scope` is cheap and yields a handle
+/// to the same bindings, which is how closures capture their environment.
+#[derive(Clone)]
+pub struct Scope {
+    inner: Rc<ScopeInner>,
+}
+struct ScopeInner {
+    vars: RefCell<HashMap<String, Value>>,
+    parent: Option<Scope>,
+    ns: Rc<module::Namespace>,
+}
+impl Scope {
+    /// Create a root scope for the `user` namespace with all builtins defined
+    pub fn new() -> Self {
+        Self::module("user", Rc::new(module::Modules::new()))
+    }
+
+    /// Create a root scope for a namespace with all builtins defined
+    pub fn module<T: Into<String>>(name: T, modules: Rc<module::Modules>) -> Self {
+        let scope = Self::root(module::Namespace::new(name, modules));
+        builtins::define_builtins(&scope);
+        scope
+    }
+
+    /// Create a root scope with no bindings
+    pub fn empty() -> Self {
+        Self::root(module::Namespace::new(
+            "user",
+            Rc::new(module::Modules::new()),
+        ))
+    }
+
+    fn root(ns: module::Namespace) -> Self {
+        Self {
+            inner: Rc::new(ScopeInner {
+                vars: RefCell::new(HashMap::new()),
+                parent: None,
+                ns: Rc::new(ns),
+            }),
+        }
+    }
+
+    /// Create a new scope whose lookups fall back to this one
+    pub fn child(&self) -> Self {
+        Self {
+            inner: Rc::new(ScopeInner {
+                vars: RefCell::new(HashMap::new()),
+                parent: Some(self.clone()),
+                ns: self.inner.ns.clone(),
+            }),
+        }
+    }
+
+    /// The namespace this scope belongs to
+    pub fn ns(&self) -> &module::Namespace {
+        &self.inner.ns
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        let mut scope = self;
+        loop {
+            if let Some(v) = scope.inner.vars.borrow().get(name) {
+                return Some(v.clone());
+            }
+            match scope.inner.parent {
+                Some(ref parent) => scope = parent,
+                None => return None,
+            }
+        }
+    }
+
+    pub fn define<T: Into<String>>(&self, name: T, value: Value) {
+        self.inner.vars.borrow_mut().insert(name.into(), value);
+    }
+
+    /// Define a Rust closure as a function, converting its arguments and
+    /// result, see `native`
+    pub fn define_fn<T, Args, F>(&self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: native::IntoNative<Args>,
+    {
+        let name = name.into();
+        let func = func.into_native(&name);
+        self.define(name.clone(), Value::Native(value::Native { name, func }));
+    }
+}
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stack size for a thread running the evaluator, enough for
+/// `eval::MAX_DEPTH` nested evaluations
+pub const STACK_SIZE: usize = 256 << 20;
+
+/// Run `f` on a thread with a `STACK_SIZE` stack, so that deep recursion
+/// in rok code fails with an error instead of overflowing the Rust stack
+pub fn with_stack<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    thread::scope(|s| {
+        let handle = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(s, f)
+            .expect("failed to spawn the evaluator thread");
+        handle
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    })
+}
 
 pub fn read_eval(s: &str, scope: &mut Scope) -> Result<Value> {
     // lex to tokens
     let tokens = token::lex(s)?;
-    // println!("tokens: {}", tokens);
     // parse to forms
-    let values = value::parse_file(tokens)?;
-    // todo: eval
-    Ok(Value::List(values))
+    let forms = value::parse_file(tokens)?;
+    let mut res = Value::Nil;
+    for form in forms.iter() {
+        res = eval::eval(form, scope)?;
+    }
+    Ok(res)
 }
 
 pub struct Repl {
     save_history: bool,
     history_path: Option<path::PathBuf>,
 }
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Repl {
     pub fn new() -> Self {
         Self {
@@ -45,7 +179,7 @@ impl Repl {
         if let Some(ref history_path) = self.history_path {
             rl.load_history(history_path).ok();
         }
-        let mut scope = Scope {};
+        let mut scope = Scope::new();
         loop {
             let line = rl.readline(">>> ");
             match line {
@@ -58,7 +192,7 @@ impl Repl {
                         }
                         Ok(t) => t,
                     };
-                    println!("{:?}", res);
+                    println!("{}", res);
                 }
                 Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
                     break;
@@ -78,10 +212,16 @@ impl Repl {
 
 trait RockAlphabetic {
     fn is_rok_alphabetic(&self) -> bool;
+    fn is_rok_ident(&self) -> bool;
 }
 
 impl RockAlphabetic for char {
     fn is_rok_alphabetic(&self) -> bool {
         self.is_alphabetic() || *self == '.' || *self == '-' || *self == '_' || *self == '?'
     }
+
+    /// Characters allowed after the first character of an identifier or keyword
+    fn is_rok_ident(&self) -> bool {
+        self.is_rok_alphabetic() || self.is_ascii_digit() || "!*+/<>=&".contains(*self)
+    }
 }
@@ -0,0 +1,309 @@
+use crate::errors::Result;
+use crate::lang::{eval, exception, token, value, Scope};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{env, ffi, fs, path};
+
+/// Modules loaded by an interpreter, shared by all of its namespaces
+pub struct Modules {
+    search_path: Vec<path::PathBuf>,
+    loaded: RefCell<HashMap<String, Scope>>,
+    /// Names of modules currently being loaded, outermost first
+    loading: RefCell<Vec<String>>,
+}
+impl Modules {
+    /// Create a registry searching the current directory, then each entry of `ROK_PATH`
+    pub fn new() -> Self {
+        let cwd = env::current_dir().unwrap_or_else(|_| path::PathBuf::from("."));
+        Self::with_search_path(search_path(cwd, env::var_os("ROK_PATH").as_deref()))
+    }
+
+    pub fn with_search_path(search_path: Vec<path::PathBuf>) -> Self {
+        Self {
+            search_path,
+            loaded: RefCell::new(HashMap::new()),
+            loading: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn search_path(&self) -> &[path::PathBuf] {
+        &self.search_path
+    }
+
+    /// Find the file for a module, e.g. `foo.bar` -> `<dir>/foo/bar.rok`
+    pub fn resolve(&self, name: &str) -> Result<path::PathBuf> {
+        let relative = format!("{}.rok", name.replace('.', "/"));
+        self.search_path
+            .iter()
+            .map(|dir| dir.join(&relative))
+            .find(|p| p.is_file())
+            .ok_or_else(|| {
+                let searched = self
+                    .search_path
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>();
+                se!(
+                    "Could not find module {} ({}) in: {}",
+                    name,
+                    relative,
+                    searched.join(", ")
+                )
+                .into()
+            })
+    }
+}
+/// `dir` followed by the directories listed in `rok_path`
+fn search_path(dir: path::PathBuf, rok_path: Option<&ffi::OsStr>) -> Vec<path::PathBuf> {
+    let mut search_path = vec![dir];
+    if let Some(paths) = rok_path {
+        search_path.extend(env::split_paths(paths));
+    }
+    search_path
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The namespace a scope belongs to
+pub struct Namespace {
+    name: RefCell<String>,
+    aliases: RefCell<HashMap<String, String>>,
+    modules: Rc<Modules>,
+}
+impl Namespace {
+    pub fn new<T: Into<String>>(name: T, modules: Rc<Modules>) -> Self {
+        Self {
+            name: RefCell::new(name.into()),
+            aliases: RefCell::new(HashMap::new()),
+            modules,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.name.borrow().clone()
+    }
+
+    pub fn modules(&self) -> &Rc<Modules> {
+        &self.modules
+    }
+}
+
+/// Handle `(ns name)`: name the namespace of `scope`. Outside of a module
+/// being loaded (e.g. in the repl) this also makes the namespace available
+/// to qualified lookups, unless a module of that name is on the search
+/// path: `require` loads that instead.
+pub fn declare(name: &str, scope: &Scope) {
+    let ns = scope.ns();
+    *ns.name.borrow_mut() = name.to_owned();
+    if ns.modules.loading.borrow().is_empty() && ns.modules.resolve(name).is_err() {
+        ns.modules
+            .loaded
+            .borrow_mut()
+            .entry(name.to_owned())
+            .or_insert_with(|| scope.clone());
+    }
+}
+
+/// Load `name` (once) and make it available in the namespace of `scope`,
+/// optionally under `alias`
+pub fn require(name: &str, alias: Option<&str>, scope: &Scope) -> Result<Scope> {
+    let ns = scope.ns();
+    let module = load(name, &ns.modules)?;
+    if let Some(alias) = alias {
+        ns.aliases
+            .borrow_mut()
+            .insert(alias.to_owned(), name.to_owned());
+    }
+    Ok(module)
+}
+
+fn load(name: &str, modules: &Rc<Modules>) -> Result<Scope> {
+    if let Some(module) = modules.loaded.borrow().get(name) {
+        return Ok(module.clone());
+    }
+    if modules.loading.borrow().iter().any(|n| n == name) {
+        let mut cycle = modules.loading.borrow().clone();
+        cycle.push(name.to_owned());
+        return Err(se!("Circular require: {}", cycle.join(" -> ")).into());
+    }
+    let path = modules.resolve(name)?;
+    let src = fs::read_to_string(&path)?;
+
+    modules.loading.borrow_mut().push(name.to_owned());
+    let scope = Scope::module(name, modules.clone());
+    let res = eval_module(&src, &scope);
+    modules.loading.borrow_mut().pop();
+    res.map_err(|e| exception::with_frame(e, &format!("{} ({})", name, path.display())))?;
+
+    let declared = scope.ns().name();
+    if declared != name {
+        return Err(se!(
+            "Module {} declares namespace {}, expected {}",
+            path.display(),
+            declared,
+            name
+        )
+        .into());
+    }
+    modules
+        .loaded
+        .borrow_mut()
+        .insert(name.to_owned(), scope.clone());
+    Ok(scope)
+}
+
+fn eval_module(src: &str, scope: &Scope) -> Result<()> {
+    let forms = value::parse_file(token::lex(src)?)?;
+    for form in forms.iter() {
+        eval::eval(form, scope)?;
+    }
+    Ok(())
+}
+
+/// Resolve a qualified symbol such as `fb/thing` or `foo.bar/thing`
+pub fn lookup_qualified(name: &str, scope: &Scope) -> Option<value::Value> {
+    let split = name.find('/').filter(|&i| i > 0 && i < name.len() - 1)?;
+    let (prefix, symbol) = (&name[..split], &name[split + 1..]);
+    let ns = scope.ns();
+    let module_name = ns
+        .aliases
+        .borrow()
+        .get(prefix)
+        .cloned()
+        .unwrap_or_else(|| prefix.to_owned());
+    let module = ns.modules.loaded.borrow().get(&module_name).cloned()?;
+    module.get(symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::read_eval;
+
+    /// A fresh directory holding `files`, as (module path, source) pairs
+    fn module_dir(test: &str, files: &[(&str, &str)]) -> path::PathBuf {
+        let dir = env::temp_dir().join(format!("rok-modules-{}-{}", std::process::id(), test));
+        fs::remove_dir_all(&dir).ok();
+        for (file, src) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, src).unwrap();
+        }
+        dir
+    }
+
+    fn scope(dir: &path::Path) -> Scope {
+        Scope::module(
+            "user",
+            Rc::new(Modules::with_search_path(vec![dir.to_owned()])),
+        )
+    }
+
+    fn eval(scope: &Scope, s: &str) -> String {
+        match read_eval(s, &mut scope.clone()) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn require_as_and_refer() {
+        let dir = module_dir(
+            "refer",
+            &[(
+                "util/math.rok",
+                "(ns util.math) (defn square [x] (* x x)) (def two 2)",
+            )],
+        );
+        let scope = scope(&dir);
+        assert_eq!(
+            eval(
+                &scope,
+                "(require util.math :as m :refer [two]) [(m/square 3) two]"
+            ),
+            "[9 2]"
+        );
+        assert_eq!(eval(&scope, "(util.math/square 4)"), "16");
+        assert_eq!(
+            eval(&scope, "(require util.math :refer [three])"),
+            "error: util.math does not define three"
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn loads_once() {
+        let dir = module_dir("once", &[("counter.rok", "(ns counter) (def n (atom 0))")]);
+        let scope = scope(&dir);
+        eval(&scope, "(require counter) (swap! counter/n inc)");
+        fs::write(dir.join("counter.rok"), "(ns counter) (def n (atom 100))").unwrap();
+        assert_eq!(eval(&scope, "(require counter) @counter/n"), "1");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rok_path() {
+        let dir = module_dir(
+            "path",
+            &[("lib/greet.rok", "(ns lib.greet) (def hi \"hi\")")],
+        );
+        let other = env::temp_dir().join("rok-modules-elsewhere");
+        let rok_path = env::join_paths([&other, &dir]).unwrap();
+        let search_path = search_path(env::temp_dir(), Some(&rok_path));
+        assert_eq!(search_path, [env::temp_dir(), other, dir.clone()]);
+        let modules = Modules::with_search_path(search_path);
+        assert_eq!(
+            modules.resolve("lib.greet").unwrap(),
+            dir.join("lib/greet.rok")
+        );
+        let scope = Scope::module("user", Rc::new(modules));
+        assert_eq!(eval(&scope, "(require lib.greet) lib.greet/hi"), "\"hi\"");
+        assert!(eval(&scope, "(require lib.missing)")
+            .starts_with("error: Could not find module lib.missing (lib/missing.rok) in: "));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn circular_require() {
+        let dir = module_dir(
+            "circular",
+            &[
+                ("a.rok", "(ns a) (require b)"),
+                ("b.rok", "(ns b) (require a)"),
+            ],
+        );
+        let err = eval(&scope(&dir), "(require a)");
+        assert!(
+            err.starts_with("error: Circular require: a -> b -> a\n  at b ("),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn ns_in_the_repl() {
+        let dir = module_dir(
+            "repl",
+            &[("foo/bar.rok", "(ns foo.bar) (def x :from-file)")],
+        );
+        let scope = scope(&dir);
+        // a namespace with a file is still loaded from it
+        assert_eq!(
+            eval(
+                &scope,
+                "(ns foo.bar) (def x :from-repl) (require foo.bar) foo.bar/x"
+            ),
+            ":from-file"
+        );
+        // one without is available to qualified lookups
+        let scope = self::scope(&dir);
+        assert_eq!(eval(&scope, "(ns scratch) (def y 1) scratch/y"), "1");
+        fs::remove_dir_all(dir).ok();
+    }
+}
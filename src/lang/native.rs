@@ -0,0 +1,357 @@
+//! Rust closures as rok functions, with conversions between `Value` and
+//! Rust types.
+//!
+//! ```ignore
+//! scope.define_fn("greet", |name: String, times: i64| {
+//!     Ok(vec![format!("hi {}", name); times as usize])
+//! });
+//! ```
+//!
+//! Numbers convert to and from the integer types, `f64` and `Num`; strings
+//! to and from `String`; lists and vectors to `Vec` (which converts back to
+//! a vector); maps to `BTreeMap` and `HashMap`; and `nil` to `None`.
+use crate::errors::Result;
+use crate::lang::value::{NativeFn, Num, Value};
+use num::{BigInt, FromPrimitive, ToPrimitive};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// A Rust value a rok value can convert to
+pub trait FromValue: Sized {
+    /// What the conversion accepts, for errors, e.g. "an integer"
+    fn expected() -> String;
+    fn from_value(v: &Value) -> Option<Self>;
+}
+
+/// A Rust value that converts to a rok value
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl FromValue for Value {
+    fn expected() -> String {
+        "a value".to_owned()
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        Some(v.clone())
+    }
+}
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for bool {
+    fn expected() -> String {
+        "a bool".to_owned()
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+macro_rules! integer {
+    ($($t:ty),*) => {$(
+        impl FromValue for $t {
+            fn expected() -> String {
+                format!("an integer ({})", stringify!($t))
+            }
+            fn from_value(v: &Value) -> Option<Self> {
+                match v {
+                    Value::Num(n) if n.is_integer() => {
+                        <$t>::try_from(n.to_integer().to_i128()?).ok()
+                    }
+                    _ => None,
+                }
+            }
+        }
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                Value::Num(Num::from_integer(BigInt::from(self)))
+            }
+        }
+    )*};
+}
+integer!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+impl FromValue for f64 {
+    fn expected() -> String {
+        "a number".to_owned()
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Num(n) => n
+                .numer()
+                .to_f64()
+                .zip(n.denom().to_f64())
+                .map(|(a, b)| a / b),
+            _ => None,
+        }
+    }
+}
+/// NaN and infinities have no rok value and become `nil`
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        match Num::from_f64(self) {
+            Some(n) => Value::Num(n),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl FromValue for Num {
+    fn expected() -> String {
+        "a number".to_owned()
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Num(n) => Some(n.clone()),
+            _ => None,
+        }
+    }
+}
+impl IntoValue for Num {
+    fn into_value(self) -> Value {
+        Value::Num(self)
+    }
+}
+
+impl FromValue for String {
+    fn expected() -> String {
+        "a string".to_owned()
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Str(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::Str(self.to_owned())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Nil => Some(None),
+            v => T::from_value(v).map(Some),
+        }
+    }
+}
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, T::into_value)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn expected() -> String {
+        format!("a sequence of {}", T::expected())
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        v.as_seq()?.iter().map(T::from_value).collect()
+    }
+}
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let items = self.into_iter().map(T::into_value).collect::<Vec<_>>();
+        Value::Vector(items.into())
+    }
+}
+
+impl<K: FromValue + Ord, V: FromValue> FromValue for BTreeMap<K, V> {
+    fn expected() -> String {
+        format!("a map of {} to {}", K::expected(), V::expected())
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Map(m) => m
+                .iter()
+                .map(|(k, v)| Some((K::from_value(k)?, V::from_value(v)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+impl<K: IntoValue, V: IntoValue> IntoValue for BTreeMap<K, V> {
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.into_iter()
+                .map(|(k, v)| (k.into_value(), v.into_value()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for HashMap<K, V> {
+    fn expected() -> String {
+        format!("a map of {} to {}", K::expected(), V::expected())
+    }
+    fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Map(m) => m
+                .iter()
+                .map(|(k, v)| Some((K::from_value(k)?, V::from_value(v)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+impl<K: IntoValue, V: IntoValue> IntoValue for HashMap<K, V> {
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.into_iter()
+                .map(|(k, v)| (k.into_value(), v.into_value()))
+                .collect(),
+        )
+    }
+}
+
+/// Convert argument `v` of the function `name`
+pub fn arg<T: FromValue>(name: &str, v: &Value) -> Result<T> {
+    T::from_value(v).ok_or_else(|| {
+        se!(
+            "{} expects {}, found {}: {}",
+            name,
+            T::expected(),
+            v.type_name(),
+            v
+        )
+        .into()
+    })
+}
+
+/// A closure taking `FromValue` arguments and returning a `Result` of an
+/// `IntoValue`, see `Scope::define_fn`
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> NativeFn;
+}
+
+macro_rules! into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Result<R> + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self, name: &str) -> NativeFn {
+                let name = name.to_owned();
+                Rc::new(move |args: Vec<Value>| {
+                    let params: &[&str] = &[$(stringify!($arg)),*];
+                    if args.len() != params.len() {
+                        return Err(se!(
+                            "Wrong number of args ({}) passed to {}, expected {}",
+                            args.len(),
+                            name,
+                            params.len()
+                        )
+                        .into());
+                    }
+                    let mut args = args.iter();
+                    $(let $arg = arg::<$arg>(&name, args.next().unwrap())?;)*
+                    Ok(self($($arg),*)?.into_value())
+                })
+            }
+        }
+    };
+}
+into_native!();
+into_native!(A);
+into_native!(A, B);
+into_native!(A, B, C);
+into_native!(A, B, C, D);
+into_native!(A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::{read_eval, Scope};
+    use std::collections::BTreeMap;
+
+    fn eval(scope: &mut Scope, s: &str) -> String {
+        match read_eval(s, scope) {
+            Ok(v) => v.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn conversions() {
+        let mut scope = Scope::new();
+        scope.define_fn("add", |a: i64, b: i64| Ok(a + b));
+        scope.define_fn("half", |a: f64| Ok(a / 2.0));
+        scope.define_fn("shout", |s: String| Ok(s.to_uppercase()));
+        scope.define_fn("lens", |words: Vec<String>| {
+            Ok(words.iter().map(|w| w.len()).collect::<Vec<_>>())
+        });
+        scope.define_fn("total", |m: BTreeMap<String, i64>| {
+            Ok(m.values().sum::<i64>())
+        });
+        scope.define_fn("first-or", |items: Vec<i64>, default: Option<i64>| {
+            Ok(items.first().cloned().or(default))
+        });
+        scope.define_fn("now", || Ok(7u8));
+        assert_eq!(eval(&mut scope, "(add 2 3)"), "5");
+        assert_eq!(eval(&mut scope, "(half 3)"), "3/2");
+        assert_eq!(eval(&mut scope, "(shout \"hi\")"), "\"HI\"");
+        assert_eq!(eval(&mut scope, "(lens [\"a\" \"abc\"])"), "[1 3]");
+        assert_eq!(eval(&mut scope, "(total {\"a\" 1 \"b\" 2})"), "3");
+        assert_eq!(eval(&mut scope, "(first-or [] nil)"), "nil");
+        assert_eq!(eval(&mut scope, "(first-or [] 4)"), "4");
+        assert_eq!(
+            eval(&mut scope, "(map (fn [x] (add x (now))) [1 2])"),
+            "(8 9)"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut scope = Scope::new();
+        scope.define_fn("add", |a: i64, b: i64| Ok(a + b));
+        scope.define_fn("fail", |s: String| -> crate::errors::Result<()> {
+            Err(se!("{}", s).into())
+        });
+        assert_eq!(
+            eval(&mut scope, "(add 1)"),
+            "error: Wrong number of args (1) passed to add, expected 2"
+        );
+        assert_eq!(
+            eval(&mut scope, "(add 1 \"2\")"),
+            "error: add expects an integer (i64), found string: \"2\""
+        );
+        assert_eq!(
+            eval(&mut scope, "(add 1 (/ 1 2))"),
+            "error: add expects an integer (i64), found number: 1/2"
+        );
+        assert_eq!(eval(&mut scope, "(fail \"no\")"), "error: no");
+    }
+}
@@ -0,0 +1,319 @@
+//! Destructuring binding forms and `match` patterns.
+//!
+//! Binding forms are used by `let` and `fn` parameter lists:
+//!
+//! ```text
+//! (let [[a b & rest :as all] xs
+//!       {:keys [x y] :or {y 0} :as m} point
+//!       {n :name} person]
+//!   ...)
+//! ```
+//!
+//! Binding forms never fail on shape: missing items bind `nil`.
+//!
+//! `match` patterns are refutable. Literals and keywords compare by equality,
+//! symbols bind (`_` binds nothing), vector patterns must have the same length
+//! (unless they contain `& rest`), and map patterns `{key pattern}` require each
+//! key to be present with a value matching its pattern.
+use crate::errors::Result;
+use crate::lang::eval;
+use crate::lang::value::Value;
+use crate::lang::Scope;
+
+pub type Bindings = Vec<(String, Value)>;
+
+/// Destructure `value` with binding form `pattern`, defining names in `scope`
+pub fn bind(pattern: &Value, value: &Value, scope: &Scope) -> Result<()> {
+    let mut bindings = vec![];
+    destructure(pattern, value, scope, &mut bindings)?;
+    for (name, v) in bindings {
+        scope.define(name, v);
+    }
+    Ok(())
+}
+
+/// Collect the names bound by destructuring `value` with binding form `pattern`.
+/// `:or` defaults are evaluated in `scope`.
+pub fn destructure(
+    pattern: &Value,
+    value: &Value,
+    scope: &Scope,
+    out: &mut Bindings,
+) -> Result<()> {
+    match pattern {
+        Value::Symbol(ident) => {
+            if ident.name() != "_" {
+                out.push((ident.name().to_owned(), value.clone()));
+            }
+            Ok(())
+        }
+        Value::Vector(items) => destructure_seq(items, value, scope, out),
+        Value::Map(entries) => destructure_map(entries, value, scope, out),
+        _ => Err(se!("Invalid binding form: {}", pattern).into()),
+    }
+}
+
+fn destructure_seq(
+    items: &[Value],
+    value: &Value,
+    scope: &Scope,
+    out: &mut Bindings,
+) -> Result<()> {
+    let seq = value.as_seq().ok_or_else(|| {
+        se!(
+            "Cannot destructure {} as a sequence: {}",
+            value.type_name(),
+            value
+        )
+    })?;
+    let mut i = 0;
+    let mut n = 0;
+    while i < items.len() {
+        match items[i] {
+            Value::Symbol(ref s) if s.name() == "&" => {
+                let rest = items.get(i + 1).ok_or_else(|| {
+                    se!(
+                        "Missing binding form after & in {}",
+                        Value::Vector(items.to_vec().into())
+                    )
+                })?;
+                let rest_value = if n < seq.len() {
+                    Value::List(seq[n..].to_vec().into())
+                } else {
+                    Value::Nil
+                };
+                destructure(rest, &rest_value, scope, out)?;
+                n = seq.len();
+                i += 2;
+            }
+            Value::Keyword(ref k) if k == "as" => {
+                let name = items
+                    .get(i + 1)
+                    .ok_or_else(|| se!("Missing name after :as in binding form"))?;
+                destructure(name, value, scope, out)?;
+                i += 2;
+            }
+            ref item => {
+                let v = seq.get(n).cloned().unwrap_or(Value::Nil);
+                destructure(item, &v, scope, out)?;
+                n += 1;
+                i += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn destructure_map(
+    entries: &std::collections::BTreeMap<Value, Value>,
+    value: &Value,
+    scope: &Scope,
+    out: &mut Bindings,
+) -> Result<()> {
+    let map = match value {
+        Value::Map(m) => Some(m),
+        Value::Nil => None,
+        _ => {
+            return Err(se!(
+                "Cannot destructure {} as a map: {}",
+                value.type_name(),
+                value
+            )
+            .into());
+        }
+    };
+    let defaults = match entries.get(&Value::keyword("or")) {
+        Some(Value::Map(defaults)) => Some(defaults),
+        Some(other) => return Err(se!(":or expects a map, found: {}", other).into()),
+        None => None,
+    };
+    let lookup = |key: &Value, name: &Value| -> Result<Value> {
+        if let Some(v) = map.and_then(|m| m.get(key)) {
+            return Ok(v.clone());
+        }
+        match defaults.and_then(|d| d.get(name)) {
+            Some(default) => eval::eval(default, scope),
+            None => Ok(Value::Nil),
+        }
+    };
+    for (k, v) in entries {
+        match k {
+            Value::Keyword(kind) if kind == "keys" || kind == "strs" => {
+                let names = v
+                    .as_seq()
+                    .ok_or_else(|| se!(":{} expects a vector of symbols, found: {}", kind, v))?;
+                for name in names {
+                    let ident = match name {
+                        Value::Symbol(ident) => ident,
+                        _ => return Err(se!(":{} expects symbols, found: {}", kind, name).into()),
+                    };
+                    let key = if kind == "keys" {
+                        Value::keyword(ident.name())
+                    } else {
+                        Value::Str(ident.name().to_owned())
+                    };
+                    out.push((ident.name().to_owned(), lookup(&key, name)?));
+                }
+            }
+            Value::Keyword(kind) if kind == "as" => destructure(v, value, scope, out)?,
+            Value::Keyword(kind) if kind == "or" => (),
+            binding => {
+                let found = lookup(v, binding)?;
+                destructure(binding, &found, scope, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Test `value` against a `match` pattern, collecting bindings on success
+pub fn matches(pattern: &Value, value: &Value, out: &mut Bindings) -> Result<bool> {
+    match pattern {
+        Value::Symbol(ident) => {
+            if ident.name() != "_" {
+                out.push((ident.name().to_owned(), value.clone()));
+            }
+            Ok(true)
+        }
+        Value::Nil | Value::Bool(_) | Value::Num(_) | Value::Str(_) | Value::Keyword(_) => {
+            Ok(pattern == value)
+        }
+        Value::Vector(items) => {
+            let seq = match value {
+                Value::List(l) | Value::Vector(l) => l,
+                _ => return Ok(false),
+            };
+            let fixed = items
+                .iter()
+                .position(|p| *p == Value::symbol("&"))
+                .unwrap_or(items.len());
+            let has_rest = fixed < items.len();
+            if has_rest && items.len() != fixed + 2 {
+                return Err(se!("& must be followed by exactly one pattern in {}", pattern).into());
+            }
+            if seq.len() < fixed || (!has_rest && seq.len() != fixed) {
+                return Ok(false);
+            }
+            for (p, v) in items[..fixed].iter().zip(seq.iter()) {
+                if !matches(p, v, out)? {
+                    return Ok(false);
+                }
+            }
+            if has_rest {
+                let rest = Value::List(seq[fixed..].to_vec().into());
+                return matches(&items[fixed + 1], &rest, out);
+            }
+            Ok(true)
+        }
+        Value::Map(entries) => {
+            let map = match value {
+                Value::Map(m) => m,
+                _ => return Ok(false),
+            };
+            for (k, p) in entries {
+                match map.get(k) {
+                    Some(v) if matches(p, v, out)? => (),
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        _ => Err(se!("Invalid match pattern: {}", pattern).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::{token, value};
+
+    fn read(s: &str) -> Value {
+        value::parse_file(token::lex(s).unwrap()).unwrap()[0].clone()
+    }
+
+    /// Bindings of destructuring the value of `value` with `pattern`, as text
+    fn bindings(pattern: &str, value: &str) -> Vec<(String, String)> {
+        let scope = Scope::new();
+        let value = eval::eval(&read(value), &scope).unwrap();
+        let mut out = vec![];
+        destructure(&read(pattern), &value, &scope, &mut out).unwrap();
+        out.into_iter().map(|(k, v)| (k, v.to_string())).collect()
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(
+            bindings("[a b & rest :as all]", "[1 2 3 4]"),
+            pairs(&[
+                ("a", "1"),
+                ("b", "2"),
+                ("rest", "(3 4)"),
+                ("all", "[1 2 3 4]")
+            ])
+        );
+        // missing items bind nil, and so does an empty rest
+        assert_eq!(
+            bindings("[a [b c] & rest]", "[1]"),
+            pairs(&[("a", "1"), ("b", "nil"), ("c", "nil"), ("rest", "nil")])
+        );
+        assert_eq!(bindings("[_ x]", "(list 1 2)"), pairs(&[("x", "2")]));
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(
+            bindings("{:keys [x y] :or {y (+ 1 1)} :as m}", "{:x 1}"),
+            pairs(&[("m", "{:x 1}"), ("x", "1"), ("y", "2")])
+        );
+        assert_eq!(
+            bindings("{n :name [a] :tags}", "{:name \"rok\" :tags [7]}"),
+            pairs(&[("n", "\"rok\""), ("a", "7")])
+        );
+        assert_eq!(bindings("{:strs [s]}", "{\"s\" 3}"), pairs(&[("s", "3")]));
+        assert_eq!(bindings("{:keys [x]}", "nil"), pairs(&[("x", "nil")]));
+    }
+
+    #[test]
+    fn invalid_binding_forms() {
+        let scope = Scope::new();
+        let mut out = vec![];
+        let err = destructure(&read("[a]"), &read("1"), &scope, &mut out).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: Cannot destructure number as a sequence: 1"
+        );
+        let err = destructure(&read("3"), &read("1"), &scope, &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "error: Invalid binding form: 3");
+    }
+
+    #[test]
+    fn match_patterns() {
+        let test = |pattern: &str, value: &str| {
+            let mut out = vec![];
+            let matched = matches(&read(pattern), &read(value), &mut out).unwrap();
+            let out = out.into_iter().map(|(k, v)| format!("{}={}", k, v));
+            matched.then(|| out.collect::<Vec<_>>().join(" "))
+        };
+        assert_eq!(test(":ok", ":ok"), Some("".to_owned()));
+        assert_eq!(test("1", "2"), None);
+        assert_eq!(test("[x 2]", "[1 2]"), Some("x=1".to_owned()));
+        assert_eq!(test("[x 2]", "[1 2 3]"), None);
+        assert_eq!(
+            test("[x & more]", "[1 2 3]"),
+            Some("x=1 more=(2 3)".to_owned())
+        );
+        assert_eq!(
+            test("{:op :add :args [a b]}", "{:op :add :args [1 2]}"),
+            Some("a=1 b=2".to_owned())
+        );
+        assert_eq!(test("{:op :add}", "{:op :sub}"), None);
+        assert_eq!(test("{:op _}", "{}"), None);
+    }
+}
@@ -50,6 +50,7 @@ pub enum TokenKind {
     LeftBracket,
     RightBracket,
     HashSetStart,
+    At,
 
     Comma,
     // Dot,
@@ -102,6 +103,11 @@ impl TokenStream {
         TokenStream(vec![])
     }
 }
+impl Default for TokenStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl ops::Deref for TokenStream {
     type Target = Vec<Token>;
     fn deref(&self) -> &Self::Target {
@@ -120,7 +126,7 @@ impl fmt::Display for TokenStream {
         for (i, token) in self.0.iter().enumerate() {
             write!(f, "{}", token)?;
             if i < size - 1 {
-                write!(f, ",\n")?;
+                writeln!(f, ",")?;
             }
         }
         write!(f, "]")
@@ -179,7 +185,7 @@ impl str::FromStr for TokenStream {
             F: Fn(char) -> bool,
         {
             let mut s = String::new();
-            while let Some(next) = source.next() {
+            for next in source {
                 s.push(next);
                 if func(next) {
                     break;
@@ -194,7 +200,7 @@ impl str::FromStr for TokenStream {
             F: Fn(char) -> bool,
         {
             let mut s = String::new();
-            while let Some(next) = source.next() {
+            for next in source {
                 s.push(next);
                 if func(next) {
                     return Ok(s);
@@ -215,7 +221,7 @@ impl str::FromStr for TokenStream {
         ///
         /// E.g. we currently have "1234" with a `PutBackN` containing ['.', char, char, ...]
         ///      valid numbers can have a trailing dot
-        fn get_digit_tail<T>(mut source: &mut PutBackN<T>) -> Result<String>
+        fn get_digit_tail<T>(source: &mut PutBackN<T>) -> Result<String>
         where
             T: Iterator<Item = char>,
         {
@@ -229,10 +235,10 @@ impl str::FromStr for TokenStream {
                     s.push('.');
                 } else if next2.is_rok_alphabetic() {
                     source.put_back(next1.unwrap());
-                } else if next2.is_digit(10) {
+                } else if next2.is_ascii_digit() {
                     s.push(next1.unwrap()); // push the dot
-                    let digit_tail = drain_until(&mut source, |c| !c.is_digit(10));
-                    let next = get_next(&mut source).unwrap_or(' ');
+                    let digit_tail = drain_until(source, |c| !c.is_ascii_digit());
+                    let next = get_next(source).unwrap_or(' ');
                     if next.is_rok_alphabetic() {
                         return Err(se!(
                             "Unexpected character: {:?}. Found alphabetic trailing a digit",
@@ -274,6 +280,7 @@ impl str::FromStr for TokenStream {
                 c @ '+' => (Plus, c.to_string()),
                 c @ '*' => (Star, c.to_string()),
                 c @ ';' => (SemiColon, c.to_string()),
+                c @ '@' => (At, c.to_string()),
 
                 // handle the possibly double character tokens
                 c @ '!' => {
@@ -328,7 +335,7 @@ impl str::FromStr for TokenStream {
 
                 // keyword literal
                 c @ ':' => {
-                    let s = c.to_string() + &drain_until(&mut chars, |c| !c.is_rok_alphabetic());
+                    let s = c.to_string() + &drain_until(&mut chars, |c| !c.is_rok_ident());
                     if s == ":" {
                         return Err(
                             se!("Invalid keyword ':' at line {}, col {}", line_no, col_no).into(),
@@ -364,9 +371,11 @@ impl str::FromStr for TokenStream {
                     (Str, s.trim_end_matches("\'").to_owned())
                 }
 
-                // handle numbers (trailing dot allowed)
-                d if d.is_digit(10) => {
-                    let mut s = d.to_string() + &drain_until(&mut chars, |c| !c.is_digit(10));
+                // handle numbers (trailing dot allowed, leading minus allowed)
+                d if d.is_ascii_digit()
+                    || (d == '-' && get_next(&mut chars).is_some_and(|c| c.is_ascii_digit())) =>
+                {
+                    let mut s = d.to_string() + &drain_until(&mut chars, |c| !c.is_ascii_digit());
                     let next = get_next(&mut chars).unwrap_or(' ');
                     if next == '.' {
                         let tail = get_digit_tail(&mut chars)?;
@@ -381,7 +390,7 @@ impl str::FromStr for TokenStream {
 
                 // handle keywords
                 c => {
-                    let s = c.to_string() + &drain_until(&mut chars, |c| !c.is_rok_alphabetic());
+                    let s = c.to_string() + &drain_until(&mut chars, |c| !c.is_rok_ident());
                     match s.as_str() {
                         // "for" => (For, s),
                         // "in" => (In, s),
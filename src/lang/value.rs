@@ -1,11 +1,20 @@
 use crate::errors::Result;
 use crate::lang::token::{Token, TokenKind, TokenStream};
+use crate::lang::Scope;
 use num;
-use std::collections::BTreeMap;
+use num::{BigInt, One};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
-#[derive(Debug, Clone, Hash, PartialEq, PartialOrd)]
+pub type Num = num::rational::BigRational;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Ident {
     name: String,
 }
@@ -13,11 +22,17 @@ impl Ident {
     pub fn new<T: Into<String>>(name: T) -> Self {
         Self { name: name.into() }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
-#[derive(Clone, Hash, PartialOrd, PartialEq)]
+/// A sequence of values. Clones share storage and copy on write, so
+/// passing forms around the evaluator is cheap.
+#[derive(Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub struct List {
-    inner: Vec<Value>,
+    inner: Rc<Vec<Value>>,
 }
 impl std::ops::Deref for List {
     type Target = Vec<Value>;
@@ -27,7 +42,7 @@ impl std::ops::Deref for List {
 }
 impl std::ops::DerefMut for List {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+        Rc::make_mut(&mut self.inner)
     }
 }
 impl Debug for List {
@@ -35,73 +50,440 @@ impl Debug for List {
         write!(f, "{:?}", self.inner)
     }
 }
+impl From<Vec<Value>> for List {
+    fn from(inner: Vec<Value>) -> Self {
+        Self {
+            inner: Rc::new(inner),
+        }
+    }
+}
 impl List {
     pub fn new() -> Self {
-        Self { inner: vec![] }
+        Self {
+            inner: Rc::new(vec![]),
+        }
+    }
+}
+impl Default for List {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Value {
-    Num(num::rational::BigRational),
+    Nil,
+    Bool(bool),
+    Num(Num),
     Str(String),
+    Keyword(String),
+    Symbol(Ident),
     List(List),
+    Vector(List),
     Map(BTreeMap<Value, Value>),
-    Func(Function),
+    Set(BTreeSet<Value>),
+    Func(Rc<Function>),
+    Native(Native),
+    Ex(Rc<ExInfo>),
+    Atom(Rc<Atom>),
+}
+impl Value {
+    pub fn symbol<T: Into<String>>(name: T) -> Self {
+        Value::Symbol(Ident::new(name))
+    }
+
+    pub fn keyword<T: Into<String>>(name: T) -> Self {
+        Value::Keyword(name.into())
+    }
+
+    pub fn int(n: i64) -> Self {
+        Value::Num(Num::from_integer(BigInt::from(n)))
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Num(_) => "number",
+            Value::Str(_) => "string",
+            Value::Keyword(_) => "keyword",
+            Value::Symbol(_) => "symbol",
+            Value::List(_) => "list",
+            Value::Vector(_) => "vector",
+            Value::Map(_) => "map",
+            Value::Set(_) => "set",
+            Value::Func(_) | Value::Native(_) => "function",
+            Value::Ex(_) => "ex-info",
+            Value::Atom(_) => "atom",
+        }
+    }
+
+    /// Return the items of a sequential value (`nil` is the empty sequence)
+    pub fn as_seq(&self) -> Option<&[Value]> {
+        match self {
+            Value::Nil => Some(&[]),
+            Value::List(l) | Value::Vector(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    /// Return the text of a string without surrounding quotes, or the printed
+    /// representation of any other value
+    pub fn to_plain_string(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+fn write_seq<'a, I>(f: &mut fmt::Formatter<'_>, open: &str, items: I, close: &str) -> fmt::Result
+where
+    I: Iterator<Item = &'a Value>,
+{
+    write!(f, "{}", open)?;
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    write!(f, "{}", close)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Keyword(k) => write!(f, ":{}", k),
+            Value::Symbol(s) => write!(f, "{}", s.name),
+            Value::List(l) => write_seq(f, "(", l.iter(), ")"),
+            Value::Vector(l) => write_seq(f, "[", l.iter(), "]"),
+            Value::Set(s) => write_seq(f, "#{", s.iter(), "}"),
+            Value::Map(m) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} {}", k, v)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Func(func) => match func.ident {
+                Some(ref ident) => write!(f, "#<fn {}>", ident.name),
+                None => write!(f, "#<fn>"),
+            },
+            Value::Native(n) => write!(f, "#<native {}>", n.name),
+            Value::Ex(ex) => write!(f, "#<ex-info {:?} {}>", ex.message, ex.data),
+            Value::Atom(atom) => write!(f, "#<atom {}>", atom.get()),
+        }
+    }
+}
+
+/// An error value created by `ex-info`, or by catching a builtin error
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExInfo {
+    pub message: String,
+    pub data: Value,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, PartialOrd)]
+static NEXT_FUNCTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A closure defined by `fn`. Functions compare by identity.
+#[derive(Clone)]
 pub struct Function {
-    id: usize,
-    ident: Option<Ident>,
-    full: List,
-    args: List,
-    body: List,
-}
-
-fn parse_list(tokens: &[Token]) -> Result<List> {
-    println!("list tokens: {}", TokenStream(tokens.to_vec()));
-    Ok(List::new())
-}
-
-fn find_list_end(tokens: &[Token]) -> Result<usize> {
-    if tokens[0].kind == TokenKind::EndOfFile {
-        return Ok(1);
-    }
-    assert_eq!(tokens[0].kind, TokenKind::LeftParen);
-    let mut i = 1;
-    let mut open_count = 0;
-    loop {
-        println!("** NEXT: {}", tokens[i]);
-        if open_count == 0 && tokens[i].kind == TokenKind::RightParen {
-            i += 1;
-            break;
+    pub id: usize,
+    pub ident: Option<Ident>,
+    pub params: List,
+    pub body: List,
+    pub scope: Scope,
+}
+impl Function {
+    pub fn new(ident: Option<Ident>, params: List, body: List, scope: Scope) -> Self {
+        Self {
+            id: NEXT_FUNCTION_ID.fetch_add(1, AtomicOrdering::SeqCst),
+            ident,
+            params,
+            body,
+            scope,
+        }
+    }
+}
+impl Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Function({}, {:?}, {:?})",
+            self.id, self.ident, self.params
+        )
+    }
+}
+impl Hash for Function {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Function {}
+impl PartialOrd for Function {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Function {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+static NEXT_ATOM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A mutable reference cell created by `atom`. Atoms compare by identity.
+pub struct Atom {
+    pub id: usize,
+    value: RefCell<Value>,
+    watches: RefCell<BTreeMap<Value, Value>>,
+}
+impl Atom {
+    pub fn new(value: Value) -> Self {
+        Self {
+            id: NEXT_ATOM_ID.fetch_add(1, AtomicOrdering::SeqCst),
+            value: RefCell::new(value),
+            watches: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn get(&self) -> Value {
+        self.value.borrow().clone()
+    }
+
+    /// Replace the current value, returning the old one. Watches are not notified.
+    pub fn replace(&self, value: Value) -> Value {
+        self.value.replace(value)
+    }
+
+    pub fn add_watch(&self, key: Value, func: Value) {
+        self.watches.borrow_mut().insert(key, func);
+    }
+
+    pub fn remove_watch(&self, key: &Value) {
+        self.watches.borrow_mut().remove(key);
+    }
+
+    /// The registered `(key, fn)` watches
+    pub fn watches(&self) -> Vec<(Value, Value)> {
+        self.watches
+            .borrow()
+            .iter()
+            .map(|(k, f)| (k.clone(), f.clone()))
+            .collect()
+    }
+}
+impl Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Atom({}, {:?})", self.id, self.value.borrow())
+    }
+}
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+impl PartialEq for Atom {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Atom {}
+impl PartialOrd for Atom {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Atom {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+pub type NativeFn = Rc<dyn Fn(Vec<Value>) -> Result<Value>>;
+
+/// A function implemented in rust. Natives compare by name.
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub func: NativeFn,
+}
+impl Native {
+    pub fn new<T, F>(name: T, func: F) -> Self
+    where
+        T: Into<String>,
+        F: Fn(Vec<Value>) -> Result<Value> + 'static,
+    {
+        Self {
+            name: name.into(),
+            func: Rc::new(func),
         }
+    }
+}
+impl Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+impl Hash for Native {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+impl Eq for Native {}
+impl PartialOrd for Native {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Native {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+/// Parse a numeric lexeme, e.g. "12", "-3", "1.25" or "4.", into an exact rational
+fn parse_num(lexeme: &str) -> Result<Num> {
+    let (whole, frac) = match lexeme.find('.') {
+        Some(i) => (&lexeme[..i], &lexeme[i + 1..]),
+        None => (lexeme, ""),
+    };
+    let digits = format!("{}{}", whole, frac);
+    let numer = digits
+        .parse::<BigInt>()
+        .map_err(|_| se!("Invalid number: {}", lexeme))?;
+    let mut denom = BigInt::one();
+    for _ in 0..frac.len() {
+        denom = denom * BigInt::from(10);
+    }
+    Ok(Num::new(numer, denom))
+}
 
-        if tokens[i].kind == TokenKind::LeftParen {
-            open_count += 1;
-        } else if tokens[i].kind == TokenKind::RightParen {
-            open_count -= 1
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> &'a Token {
+        while self.pos < self.tokens.len() {
+            match self.tokens[self.pos].kind {
+                TokenKind::Comment | TokenKind::Comma => self.pos += 1,
+                _ => break,
+            }
         }
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
 
-        i += 1;
+    fn next(&mut self) -> &'a Token {
+        let token = self.peek();
+        if self.pos < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn parse_until(&mut self, open: &Token, close: TokenKind) -> Result<Vec<Value>> {
+        let mut items = vec![];
+        loop {
+            let token = self.peek();
+            if token.kind == close {
+                self.next();
+                return Ok(items);
+            }
+            if token.kind == TokenKind::EndOfFile {
+                return Err(se!(
+                    "Unclosed {:?} opened at line {}, col {}",
+                    open.lexeme,
+                    open.source_line,
+                    open.source_column
+                )
+                .into());
+            }
+            items.push(self.parse_form()?);
+        }
+    }
+
+    fn parse_form(&mut self) -> Result<Value> {
+        use self::TokenKind::*;
+        let token = self.next();
+        let value = match token.kind {
+            LeftParen => Value::List(self.parse_until(token, RightParen)?.into()),
+            LeftBrace => Value::Vector(self.parse_until(token, RightBrace)?.into()),
+            LeftBracket => {
+                let items = self.parse_until(token, RightBracket)?;
+                if !items.len().is_multiple_of(2) {
+                    return Err(se!(
+                        "Map literal at line {}, col {} must have an even number of forms",
+                        token.source_line,
+                        token.source_column
+                    )
+                    .into());
+                }
+                let mut map = BTreeMap::new();
+                let mut items = items.into_iter();
+                while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                    map.insert(k, v);
+                }
+                Value::Map(map)
+            }
+            At => Value::List(vec![Value::symbol("deref"), self.parse_form()?].into()),
+            HashSetStart => {
+                Value::Set(self.parse_until(token, RightBracket)?.into_iter().collect())
+            }
+            Num => Value::Num(parse_num(&token.lexeme)?),
+            Str => Value::Str(token.lexeme.clone()),
+            Keyword => Value::Keyword(token.lexeme[1..].to_owned()),
+            True => Value::Bool(true),
+            False => Value::Bool(false),
+            Nil => Value::Nil,
+            Ident | Plus | Minus | Star | Slash | Bang | Equal | Greater | Less | BangEqual
+            | EqualEqual | GreaterEqual | LessEqual | For | In | While | Loop | If | Else | And
+            | Or | Let | Func | Return => Value::symbol(token.lexeme.clone()),
+            RightParen | RightBrace | RightBracket | SemiColon | Comma | Comment | EndOfFile => {
+                return Err(se!(
+                    "Unexpected {:?} at line {}, col {}",
+                    token.lexeme,
+                    token.source_line,
+                    token.source_column
+                )
+                .into());
+            }
+        };
+        Ok(value)
     }
-    return Ok(i);
 }
 
 pub fn parse_file(tokens: TokenStream) -> Result<List> {
-    let mut lists = List::new();
-    let mut i = 0;
-    loop {
-        let start = i;
-        // println!("parsing from {}", tokens[start]);
-        let n = find_list_end(&tokens[i..])?;
-        i = start + n;
-        if i >= tokens.len() {
-            break;
-        }
-        let res = parse_list(&tokens[start..i])?;
-        lists.push(Value::List(res));
+    let mut forms = List::new();
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+    };
+    while parser.peek().kind != TokenKind::EndOfFile {
+        forms.push(parser.parse_form()?);
     }
-    Ok(lists)
+    Ok(forms)
 }
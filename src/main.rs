@@ -4,9 +4,12 @@ extern crate rok;
 #[macro_use]
 extern crate clap;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use rok::rt::trace::{JsonTrace, Profile, Tracer};
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -14,8 +17,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 fn run() -> Result<()> {
     let matches = App::new("rok")
         .version(crate_version!())
-        .about("rok!")
-        .help("Run a file, string, or start an interpreter")
+        .about("Run a file, string, or start an interpreter")
         .arg(
             Arg::with_name("asm")
                 .short("a")
@@ -28,6 +30,100 @@ fn run() -> Result<()> {
                 .help("file to evaluate")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("assemble to a .rokb bytecode file instead of running")
+                .requires("asm")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bytecode")
+                .short("b")
+                .long("bytecode")
+                .help("run a .rokb bytecode file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .short("d")
+                .long("debug")
+                .help("run assembly or bytecode in the debugger"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("write a JSON-lines trace of executed operations to a file, or - for stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("count executed operations and report them at exit"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .help("run VM processes on a pool of this many threads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-instructions")
+                .long("max-instructions")
+                .help("stop VM programs after this many operations")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-processes")
+                .long("max-processes")
+                .help("fail a SPAWN while this many VM processes are running")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("stop VM programs after this many seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory")
+                .long("memory")
+                .help("bytes of memory for each VM process, stack included")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stack")
+                .long("stack")
+                .help("bytes of stack for each VM process")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gc-threshold")
+                .long("gc-threshold")
+                .help("bytes a VM heap may grow to before it is first garbage collected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compile")
+                .long("compile")
+                .help("compile a rok program and run it on the VM")
+                .long_help(
+                    "compile a rok program and run it on the VM. Only a first-order subset \
+                     compiles: functions are defined at the top level with defn, or def of \
+                     a fn, and called by name. Anonymous fn, closures and functions as \
+                     values are not supported. Only compiled programs collect garbage: \
+                     the interpreter frees values as soon as they are unused, and its (gc) \
+                     returns 0.",
+                )
+                .conflicts_with("asm"),
+        )
+        .arg(
+            Arg::with_name("disasm")
+                .long("disasm")
+                .help("print a listing of a .rokb bytecode file")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("evaluate")
                 .help("string to evaluate")
@@ -38,11 +134,25 @@ fn run() -> Result<()> {
         )
         .get_matches();
 
+    if let Some(file) = matches.value_of("disasm") {
+        let program = rok::rt::loader::load_file(file)?;
+        print!("{}", rok::asm::listing(&program));
+        return Ok(());
+    }
+    if let Some(file) = matches.value_of("bytecode") {
+        if matches.is_present("debug") {
+            debug(rok::rt::loader::load_file(file)?, &matches)?;
+        } else {
+            run_program(rok::rt::loader::load_file(file)?, &matches)?;
+        }
+        return Ok(());
+    }
+
     let asm = matches.is_present("asm");
     let src = if let Some(file) = matches.value_of("file") {
         println!("evaluating file: {}", file);
         let mut s = String::new();
-        let mut f = fs::File::open(&file)?;
+        let mut f = fs::File::open(file)?;
         f.read_to_string(&mut s)?;
         Some(s)
     } else if let Some(string) = matches.value_of("evaluate") {
@@ -53,25 +163,136 @@ fn run() -> Result<()> {
     };
 
     if asm {
-        if let Some(src) = src {
-            let res = rok::rt::read_eval(&src)?;
-            println!("{:?}", res);
+        if let Some(out) = matches.value_of("output") {
+            let program = match matches.value_of("file") {
+                Some(file) => rok::asm::assemble_file(file)?,
+                None => rok::asm::assemble(&src.ok_or("nothing to assemble")?)?,
+            };
+            fs::write(out, rok::asm::object::write(&program))?;
+            println!("wrote {} bytes of code to {}", program.code.len(), out);
+        } else if matches.is_present("debug") {
+            let program = match matches.value_of("file") {
+                Some(file) => rok::asm::assemble_file(file)?,
+                None => rok::asm::assemble(&src.ok_or("nothing to debug")?)?,
+            };
+            debug(program, &matches)?;
+        } else if let Some(file) = matches.value_of("file") {
+            // `.include` paths are relative to the file
+            run_program(rok::asm::assemble_file(file)?, &matches)?;
+        } else if let Some(src) = src {
+            run_program(rok::asm::assemble(&src)?, &matches)?;
         } else {
             println!("Rok {}", crate_version!());
             rok::rt::Repl::new().save_history(true).run()?;
         }
-    } else {
-        if let Some(src) = src {
-            let res = rok::lang::read_eval(&src, &mut rok::lang::Scope {})?;
-            println!("{:?}", res);
+    } else if matches.is_present("compile") {
+        let program = rok::lang::compile::compile_str(&src.ok_or("nothing to compile")?)?;
+        if matches.is_present("debug") {
+            debug(program, &matches)?;
         } else {
-            println!("Rok {}", crate_version!());
-            rok::lang::Repl::new().save_history(true).run()?;
+            run_program(program, &matches)?;
         }
+    } else {
+        // deep recursion needs more stack than the main thread has
+        rok::lang::with_stack(move || {
+            let result = match src {
+                Some(src) => rok::lang::read_eval(&src, &mut rok::lang::Scope::new())
+                    .map(|res| println!("{}", res)),
+                None => {
+                    println!("Rok {}", crate_version!());
+                    rok::lang::Repl::new().save_history(true).run()
+                }
+            };
+            result.map_err(|e| e.to_string())
+        })?;
     }
     Ok(())
 }
 
+/// A runtime for `program` with the limits and settings of the flags
+fn runtime(program: rok::asm::Program, matches: &ArgMatches) -> Result<rok::rt::Runtime> {
+    let mut runtime = rok::rt::Runtime::new(program);
+    if let Some(n) = number(matches, "threads")? {
+        runtime.threads(n);
+    }
+    if let Some(n) = number(matches, "max-instructions")? {
+        runtime.max_instructions(n);
+    }
+    if let Some(n) = number(matches, "max-processes")? {
+        runtime.max_processes(n);
+    }
+    if let Some(secs) = number(matches, "timeout")? {
+        let timeout =
+            Duration::try_from_secs_f64(secs).map_err(|e| format!("invalid --timeout: {}", e))?;
+        runtime.timeout(timeout);
+    }
+    if let Some(n) = number(matches, "memory")? {
+        runtime.memory_size(n);
+    }
+    if let Some(n) = number(matches, "stack")? {
+        runtime.stack_size(n);
+    }
+    if let Some(n) = number(matches, "gc-threshold")? {
+        runtime.gc_thresholds(rok::rt::heap::Thresholds {
+            initial: n,
+            ..Default::default()
+        });
+    }
+    Ok(runtime)
+}
+
+/// Run the debugger on a program, with the memory and settings of the flags
+fn debug(program: rok::asm::Program, matches: &ArgMatches) -> Result<()> {
+    let runtime = runtime(program.clone(), matches)?;
+    rok::rt::Repl::new()
+        .save_history(true)
+        .debug(program, runtime)
+}
+
+/// Run a program with the tracers selected by `--trace` and `--profile`
+fn run_program(program: rok::asm::Program, matches: &ArgMatches) -> Result<()> {
+    let mut runtime = runtime(program, matches)?;
+    let mut tracers: Vec<Box<dyn Tracer + Send>> = vec![];
+    match matches.value_of("trace") {
+        Some("-") => tracers.push(Box::new(JsonTrace::new(io::stderr()))),
+        Some(path) => {
+            let file = fs::File::create(path)?;
+            tracers.push(Box::new(JsonTrace::new(io::BufWriter::new(file))));
+        }
+        None => {}
+    }
+    let profile = Arc::new(Mutex::new(Profile::new()));
+    if matches.is_present("profile") {
+        tracers.push(Box::new(profile.clone()));
+    }
+    if !tracers.is_empty() {
+        runtime.set_tracer(Some(Box::new(tracers)));
+    }
+    let result = runtime.run_to_completion();
+    // drop the tracers so a buffered trace is flushed
+    runtime.set_tracer(None);
+    if matches.is_present("profile") {
+        eprintln!("{}", profile.lock().unwrap());
+        eprintln!("gc: {}", runtime.gc_stats());
+    }
+    result?;
+    match runtime.exit_code() {
+        Some(code) if code != 0 => std::process::exit(code as i32),
+        _ => Ok(()),
+    }
+}
+
+/// Parse the value of a numeric option
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>> {
+    match matches.value_of(name) {
+        Some(s) => match s.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("invalid --{}: {}", name, s).into()),
+        },
+        None => Ok(None),
+    }
+}
+
 fn main() {
     if let Err(e) = run() {
         eprintln!("Error: {}", e);
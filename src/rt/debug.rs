@@ -0,0 +1,498 @@
+//! Interactive debugger for the VM, built on `Processor::step`.
+//!
+//! The debugged processor gets its memory, natives and collection
+//! thresholds from a `Runtime`, but runs alone: `SPAWN`, `SEND` and `RECV`
+//! fault, and the limits on instructions, time and processes do not apply.
+use crate::asm::{self, Operation, Program, SymbolKind, OP_SIZE};
+use crate::errors::Result;
+use crate::rt::proc::{Processor, State, REGISTERS};
+use crate::rt::Runtime;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+commands:
+  break [label|addr]     set a breakpoint, or list them
+  delete <label|addr>    remove a breakpoint
+  watch <$rN|addr> [n]   stop when a register or n bytes (1, 2, 4, 8) of memory change
+  unwatch <$rN|addr>     remove a watchpoint
+  step [n]               execute n instructions (default 1), entering calls
+  next                   execute one instruction, running calls to completion
+  continue               run until a breakpoint, watchpoint, halt or fault
+  regs                   show registers
+  mem <addr|label> <n>   dump n bytes of memory
+  stack                  show the stack and return addresses
+  list                   show the instructions around pc
+  restart                reload the program
+  quit                   leave the debugger";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watched {
+    Register(u8),
+    Memory { addr: u64, len: usize },
+}
+
+struct Watch {
+    what: Watched,
+    last: Option<u64>,
+}
+
+pub struct Debugger {
+    program: Program,
+    /// Sets up the processor on start and restart
+    runtime: Runtime,
+    ops: Vec<Operation>,
+    proc: Processor,
+    breakpoints: BTreeSet<usize>,
+    watches: Vec<Watch>,
+}
+impl Debugger {
+    pub fn new(program: Program, runtime: Runtime) -> Result<Self> {
+        Ok(Self {
+            ops: asm::disassemble_program(&program),
+            proc: runtime.processor(program.clone())?,
+            program,
+            runtime,
+            breakpoints: BTreeSet::new(),
+            watches: vec![],
+        })
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.proc
+    }
+
+    /// Run one debugger command, returning the text to show
+    pub fn command(&mut self, line: &str) -> Result<String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(String::new()),
+        };
+        match (cmd, args) {
+            ("help" | "h" | "?", []) => Ok(HELP.to_owned()),
+            ("break" | "b", []) => Ok(self.breakpoints()),
+            ("break" | "b", [at]) => {
+                let offset = self.code_address(at)?;
+                self.breakpoints.insert(offset);
+                Ok(format!("breakpoint at {}", self.describe(offset)))
+            }
+            ("delete" | "d", [at]) => {
+                let offset = self.code_address(at)?;
+                if !self.breakpoints.remove(&offset) {
+                    return Err(se!("no breakpoint at {}", at).into());
+                }
+                Ok(format!("deleted breakpoint at {}", self.describe(offset)))
+            }
+            ("watch" | "w", [what]) => self.watch(what, 8),
+            ("watch" | "w", [what, len]) => self.watch(what, number(len)? as usize),
+            ("unwatch", [what]) => {
+                let what = self.watched(what, None)?;
+                let before = self.watches.len();
+                self.watches.retain(|w| !same_target(w.what, what));
+                if self.watches.len() == before {
+                    return Err(se!("no watchpoint on {}", describe_watch(what)).into());
+                }
+                Ok(format!("removed watchpoint on {}", describe_watch(what)))
+            }
+            ("step" | "s", []) => self.resume(Some(1), None),
+            ("step" | "s", [n]) => self.resume(Some(number(n)? as usize), None),
+            ("next" | "n", []) => {
+                let over = match self.current() {
+                    Some(op) if op.code() == asm::Op::Call => {
+                        Some((self.proc.pc() + OP_SIZE, self.proc.fp()))
+                    }
+                    _ => None,
+                };
+                match over {
+                    Some(until) => self.resume(None, Some(until)),
+                    None => self.resume(Some(1), None),
+                }
+            }
+            ("continue" | "c", []) => self.resume(None, None),
+            ("regs" | "r", []) => Ok(self.registers()),
+            ("mem" | "m", [at, len]) => {
+                let addr = self.data_address(at)?;
+                self.dump(addr, number(len)? as usize)
+            }
+            ("stack", []) => Ok(self.stack()),
+            ("list" | "l", []) => Ok(self.list()),
+            ("restart", []) => {
+                self.proc = self.runtime.processor(self.program.clone())?;
+                for w in &mut self.watches {
+                    w.last = read_watch(&self.proc, w.what);
+                }
+                Ok(self.location())
+            }
+            _ => Err(se!("unknown command: {} (try help)", line.trim()).into()),
+        }
+    }
+
+    /// Step until `limit` instructions have run, execution returns to
+    /// `until` (a pc and frame pointer), or a breakpoint or watchpoint hits.
+    /// Faults are returned as errors, leaving the processor inspectable.
+    fn resume(&mut self, limit: Option<usize>, until: Option<(usize, usize)>) -> Result<String> {
+        let mut count = 0;
+        loop {
+            if self.proc.state() == State::Halted {
+                return Ok("halted".to_owned());
+            }
+            if count > 0 && self.breakpoints.contains(&self.proc.pc()) {
+                return Ok(format!("breakpoint\n{}", self.location()));
+            }
+            self.proc.step()?;
+            count += 1;
+            let mut out = String::new();
+            for w in &mut self.watches {
+                let now = read_watch(&self.proc, w.what);
+                if now != w.last {
+                    writeln!(
+                        out,
+                        "watch {}: {} -> {}",
+                        describe_watch(w.what),
+                        show(w.last),
+                        show(now)
+                    )
+                    .unwrap();
+                    w.last = now;
+                }
+            }
+            if !out.is_empty() {
+                return Ok(out + &self.location());
+            }
+            let returned =
+                until.is_some_and(|(pc, fp)| self.proc.pc() == pc && self.proc.fp() == fp);
+            if limit == Some(count) || returned {
+                return Ok(self.location());
+            }
+        }
+    }
+
+    fn current(&self) -> Option<&Operation> {
+        self.ops.get(self.proc.pc() / OP_SIZE)
+    }
+
+    /// The next instruction, with its label and source line
+    fn location(&self) -> String {
+        if self.proc.state() == State::Halted {
+            return "halted".to_owned();
+        }
+        let pc = self.proc.pc();
+        match self.current() {
+            Some(op) => {
+                let mut out = format!("{} {}", self.describe(pc), op);
+                if let Some(line) = self.program.line_at(pc) {
+                    write!(out, "  ({})", line).unwrap();
+                }
+                out
+            }
+            None => format!("{:#06x} end of program", pc),
+        }
+    }
+
+    /// A code offset and the nearest label before it, e.g. `0x0018 <loop+8>`
+    fn describe(&self, offset: usize) -> String {
+        let label = self
+            .program
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Code && s.value as usize <= offset)
+            .max_by_key(|s| s.value);
+        match label {
+            Some(s) if s.value as usize == offset => format!("{:#06x} <{}>", offset, s.name),
+            Some(s) => format!("{:#06x} <{}+{}>", offset, s.name, offset - s.value as usize),
+            None => format!("{:#06x}", offset),
+        }
+    }
+
+    fn breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_owned();
+        }
+        self.breakpoints
+            .iter()
+            .map(|&b| self.describe(b))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn symbol(&self, name: &str, kind: Option<SymbolKind>) -> Option<u64> {
+        self.program
+            .symbols
+            .iter()
+            .find(|s| s.name == name && kind.is_none_or(|k| s.kind == k))
+            .map(|s| s.value)
+    }
+
+    fn code_address(&self, at: &str) -> Result<usize> {
+        let offset = match self.symbol(at, Some(SymbolKind::Code)) {
+            Some(offset) => offset as usize,
+            None => number(at)? as usize,
+        };
+        if !offset.is_multiple_of(OP_SIZE) || offset >= self.program.code.len() {
+            return Err(se!("{} is not an instruction", at).into());
+        }
+        Ok(offset)
+    }
+
+    fn data_address(&self, at: &str) -> Result<u64> {
+        match self.symbol(at, None) {
+            Some(addr) => Ok(addr),
+            None => number(at),
+        }
+    }
+
+    fn watched(&self, what: &str, len: Option<usize>) -> Result<Watched> {
+        let reg = what.strip_prefix('$').unwrap_or(what);
+        if let Some(n) = reg.strip_prefix('r').and_then(|n| n.parse::<u8>().ok()) {
+            if n as usize >= REGISTERS {
+                return Err(se!("no register {}", what).into());
+            }
+            return Ok(Watched::Register(n));
+        }
+        let addr = self.data_address(what)?;
+        Ok(Watched::Memory {
+            addr,
+            len: len.unwrap_or(8),
+        })
+    }
+
+    fn watch(&mut self, what: &str, len: usize) -> Result<String> {
+        if ![1, 2, 4, 8].contains(&len) {
+            return Err(se!("watch length must be 1, 2, 4 or 8").into());
+        }
+        let what = self.watched(what, Some(len))?;
+        let last = read_watch(&self.proc, what);
+        self.watches.push(Watch { what, last });
+        Ok(format!(
+            "watching {} (now {})",
+            describe_watch(what),
+            show(last)
+        ))
+    }
+
+    fn registers(&self) -> String {
+        let regs = self.proc.registers();
+        let mut out = format!(
+            "pc {:#06x}  sp {:#x}  fp {:#x}  cond {}\n",
+            self.proc.pc(),
+            self.proc.sp(),
+            self.proc.fp(),
+            self.proc.cond()
+        );
+        for (i, chunk) in regs.chunks(4).enumerate() {
+            let cells = chunk
+                .iter()
+                .enumerate()
+                .map(|(j, v)| format!("$r{:<2} {:#018x}", i * 4 + j, v))
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", cells.join("  ")).unwrap();
+        }
+        out.pop();
+        out
+    }
+
+    fn dump(&self, addr: u64, len: usize) -> Result<String> {
+        let bytes = self.proc.memory().bytes();
+        let start = addr as usize;
+        let end = start
+            .checked_add(len)
+            .filter(|&end| addr <= usize::MAX as u64 && end <= bytes.len())
+            .ok_or_else(|| se!("{} bytes at {:#x} are out of bounds", len, addr))?;
+        let mut out = String::new();
+        for (i, row) in bytes[start..end].chunks(16).enumerate() {
+            let hex = row.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+            let text = row
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            writeln!(
+                out,
+                "{:#010x}  {:<47}  {}",
+                start + i * 16,
+                hex.join(" "),
+                text
+            )
+            .unwrap();
+        }
+        out.pop();
+        Ok(out)
+    }
+
+    fn stack(&self) -> String {
+        let memory = self.proc.memory();
+        let mut out = String::new();
+        let mut addr = self.proc.sp();
+        while addr < memory.size() {
+            let val = memory.read(addr as u64, 8).unwrap_or(0);
+            let marker = if addr == self.proc.fp() {
+                "  <- fp"
+            } else {
+                ""
+            };
+            writeln!(out, "{:#x}  {:#018x}{}", addr, val, marker).unwrap();
+            addr += 8;
+        }
+        if out.is_empty() {
+            out.push_str("stack is empty\n");
+        }
+        for ret in self.proc.backtrace() {
+            writeln!(out, "returning to {}", self.describe(ret)).unwrap();
+        }
+        out.pop();
+        out
+    }
+
+    fn list(&self) -> String {
+        let current = self.proc.pc() / OP_SIZE;
+        let first = current.saturating_sub(3);
+        self.ops
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(7)
+            .map(|(i, op)| {
+                let marker = if i == current { "=>" } else { "  " };
+                let bp = if self.breakpoints.contains(&(i * OP_SIZE)) {
+                    "*"
+                } else {
+                    " "
+                };
+                format!("{}{} {} {}", marker, bp, self.describe(i * OP_SIZE), op)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn read_watch(proc: &Processor, what: Watched) -> Option<u64> {
+    match what {
+        Watched::Register(r) => Some(proc.registers()[r as usize]),
+        Watched::Memory { addr, len } => proc.memory().read(addr, len),
+    }
+}
+
+/// Whether two watches are on the same register or address
+fn same_target(a: Watched, b: Watched) -> bool {
+    match (a, b) {
+        (Watched::Memory { addr: x, .. }, Watched::Memory { addr: y, .. }) => x == y,
+        _ => a == b,
+    }
+}
+
+fn describe_watch(what: Watched) -> String {
+    match what {
+        Watched::Register(r) => format!("$r{}", r),
+        Watched::Memory { addr, len } => format!("{} bytes at {:#x}", len, addr),
+    }
+}
+
+fn show(val: Option<u64>) -> String {
+    match val {
+        Some(v) => v.to_string(),
+        None => "out of bounds".to_owned(),
+    }
+}
+
+/// A decimal or `0x` hex number
+fn number(s: &str) -> Result<u64> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| se!("expected a label or number, found {}", s).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FACT: &str = "\
+SET r0 3
+SET r1 1
+CALL fact
+HLT
+fact: EQ r0 r1
+JEQ base
+PUSH r0
+SUB r0 r1 r0
+CALL fact
+POP r0
+MUL r0 r2 r2
+RET
+base: SET r2 1
+RET";
+
+    fn debugger() -> Debugger {
+        let program = asm::assemble(FACT).unwrap();
+        Debugger::new(program.clone(), Runtime::new(program)).unwrap()
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut d = debugger();
+        assert_eq!(
+            d.command("break base").unwrap(),
+            "breakpoint at 0x0060 <base>"
+        );
+        assert!(d
+            .command("continue")
+            .unwrap()
+            .starts_with("breakpoint\n0x0060 <base>"));
+        assert_eq!(d.processor().backtrace().len(), 3);
+        assert_eq!(d.command("continue").unwrap(), "halted");
+        assert_eq!(d.processor().registers()[2], 6);
+        assert!(d.command("break 4").is_err());
+        assert!(d.command("break nowhere").is_err());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut d = debugger();
+        d.command("step 2").unwrap();
+        assert!(d.command("next").unwrap().starts_with("0x0018 HLT"));
+        assert_eq!(d.processor().registers()[2], 6);
+        d.command("restart").unwrap();
+        d.command("step 3").unwrap();
+        assert_eq!(d.processor().pc(), 0x20);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut d = debugger();
+        d.command("watch $r2").unwrap();
+        let out = d.command("continue").unwrap();
+        assert!(out.starts_with("watch $r2: 0 -> 1\n"), "{}", out);
+        assert!(d
+            .command("continue")
+            .unwrap()
+            .starts_with("watch $r2: 1 -> 2\n"));
+        d.command("unwatch r2").unwrap();
+        assert_eq!(d.command("continue").unwrap(), "halted");
+        assert!(d.command("watch 8 3").is_err());
+    }
+
+    #[test]
+    fn settings_of_the_runtime() {
+        let program = asm::assemble("SET r1 20\nCALLN double\nSET r2 8\nALLOC r2 r2").unwrap();
+        let mut runtime = Runtime::new(program.clone());
+        runtime
+            .register_native("double", |_, args| Ok(args[0] * 2))
+            .memory_size(4096)
+            .stack_size(1024);
+        let mut d = Debugger::new(program, runtime).unwrap();
+        assert_eq!(d.processor().memory().size(), 4096);
+        d.command("step 2").unwrap();
+        assert_eq!(d.processor().registers()[0], 40);
+        d.command("restart").unwrap();
+        assert_eq!(d.command("continue").unwrap(), "halted");
+        assert_eq!(d.processor().registers()[0], 40);
+        assert!(d.processor().registers()[2] < 4096);
+    }
+}
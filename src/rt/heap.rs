@@ -0,0 +1,672 @@
+//! Objects that tagged values refer to, and the arithmetic of the typed
+//! operations.
+//!
+//! Each processor has its own heap. Its objects are strings, lists of
+//! tagged values and numbers that do not fit a small int: big integers and
+//! ratios. Arithmetic on small ints promotes to a big number instead of
+//! overflowing, and big results that fit a small int again are stored as
+//! one. If either operand is a float, so is the result.
+//!
+//! Objects are freed by a mark-sweep collector. `Processor` collects when
+//! the bytes allocated since the last collection reach the heap's
+//! `Thresholds`, and on `GC`, marking from its registers and every aligned
+//! word of its memory. Any such word that reads as a reference to a live
+//! object keeps it alive; a reference stored at an unaligned address does
+//! not.
+//!
+//! Objects count against the process's memory limit: when the live ones
+//! take more bytes than its memory, the operation that allocated faults.
+use crate::lang::value::{Num, Value as LangValue};
+use crate::rt::value::Value;
+use num::{BigInt, FromPrimitive, ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+use std::{fmt, mem, ops};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Str(String),
+    List(Vec<Value>),
+    /// A big integer or a ratio
+    Num(Num),
+}
+
+/// Why a typed operation failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    DivideByZero,
+    /// A reference to an object that does not exist
+    InvalidRef(usize),
+}
+
+/// Arithmetic done by the typed operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    /// Exact for integers and ratios
+    Div,
+    /// Floored, with the sign of the divisor
+    Mod,
+}
+
+impl Object {
+    /// Approximate bytes used by the object, for collection thresholds and
+    /// statistics
+    pub fn size(&self) -> usize {
+        mem::size_of::<Option<Object>>()
+            + match self {
+                Object::Str(s) => s.len(),
+                Object::List(items) => items.len() * mem::size_of::<Value>(),
+                Object::Num(n) => (n.numer().bits() + n.denom().bits()).div_ceil(8),
+            }
+    }
+}
+
+/// When a heap is collected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Bytes the heap may grow to before it is first collected
+    pub initial: usize,
+    /// After a collection, the next is due when the heap has grown to this
+    /// many times the bytes that survived, or to `initial` if that is more
+    pub growth: f64,
+}
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            initial: 1 << 20,
+            growth: 2.0,
+        }
+    }
+}
+
+/// What the collector of a heap has done
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub collections: u64,
+    pub objects_freed: u64,
+    pub bytes_freed: u64,
+    /// Time spent collecting
+    pub pause: Duration,
+}
+impl ops::AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        self.collections += other.collections;
+        self.objects_freed += other.objects_freed;
+        self.bytes_freed += other.bytes_freed;
+        self.pause += other.pause;
+    }
+}
+/// What happened between two snapshots of the same stats
+impl ops::Sub for Stats {
+    type Output = Self;
+    fn sub(self, earlier: Self) -> Self {
+        Self {
+            collections: self.collections - earlier.collections,
+            objects_freed: self.objects_freed - earlier.objects_freed,
+            bytes_freed: self.bytes_freed - earlier.bytes_freed,
+            pause: self.pause - earlier.pause,
+        }
+    }
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} collection{}, {} objects and {} bytes freed in {:?}",
+            self.collections,
+            if self.collections == 1 { "" } else { "s" },
+            self.objects_freed,
+            self.bytes_freed,
+            self.pause
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    /// Indexes of empty slots in `objects`
+    free: Vec<usize>,
+    /// Bytes used by the objects, counted exactly at each collection and
+    /// grown by allocations in between
+    bytes: usize,
+    /// Value of `bytes` at which a collection is due
+    next_collection: usize,
+    thresholds: Thresholds,
+    stats: Stats,
+}
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Heap {
+    pub fn new() -> Self {
+        Self::with_thresholds(Thresholds::default())
+    }
+
+    pub fn with_thresholds(thresholds: Thresholds) -> Self {
+        Self {
+            objects: vec![],
+            free: vec![],
+            bytes: 0,
+            next_collection: thresholds.initial,
+            thresholds,
+            stats: Stats::default(),
+        }
+    }
+
+    /// A copy of the objects, with no collections yet
+    pub fn fork(&self) -> Self {
+        Self {
+            stats: Stats::default(),
+            ..self.clone()
+        }
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// Change the thresholds, starting over from `initial`
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = thresholds;
+        self.next_collection = thresholds.initial;
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Approximate bytes used by the objects
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether the heap has grown enough to be collected
+    pub fn should_collect(&self) -> bool {
+        self.bytes >= self.next_collection
+    }
+
+    /// Free the objects not reachable from `roots`, words that may hold
+    /// tagged values. Returns the bytes freed.
+    pub fn collect<I: IntoIterator<Item = u64>>(&mut self, roots: I) -> usize {
+        let start = Instant::now();
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending = roots
+            .into_iter()
+            .filter_map(|bits| match Value::from_bits(bits) {
+                Value::Ref(index) => Some(index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        while let Some(index) = pending.pop() {
+            match self.objects.get(index) {
+                Some(Some(object)) if !marked[index] => {
+                    marked[index] = true;
+                    if let Object::List(items) = object {
+                        pending.extend(items.iter().filter_map(|v| match *v {
+                            Value::Ref(index) => Some(index),
+                            _ => None,
+                        }));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let (mut live, mut freed, mut objects) = (0, 0, 0);
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            let size = match slot {
+                Some(object) => object.size(),
+                None => continue,
+            };
+            if marked[index] {
+                live += size;
+            } else {
+                *slot = None;
+                self.free.push(index);
+                freed += size;
+                objects += 1;
+            }
+        }
+        self.bytes = live;
+        let next = (live as f64 * self.thresholds.growth) as usize;
+        self.next_collection = next.max(self.thresholds.initial);
+        self.stats += Stats {
+            collections: 1,
+            objects_freed: objects,
+            bytes_freed: freed as u64,
+            pause: start.elapsed(),
+        };
+        freed
+    }
+
+    /// Store `object` and return a reference to it
+    pub fn alloc(&mut self, object: Object) -> Value {
+        self.bytes += object.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                Value::Ref(index)
+            }
+            None => {
+                self.objects.push(Some(object));
+                Value::Ref(self.objects.len() - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Object> {
+        self.objects.get(index)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Object> {
+        self.objects.get_mut(index)?.as_mut()
+    }
+
+    /// Number of objects
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn object(&self, v: Value) -> Result<Option<&Object>, Error> {
+        match v {
+            Value::Ref(index) => self.get(index).map(Some).ok_or(Error::InvalidRef(index)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Name of the type of `v`, for errors
+    pub fn type_name(&self, v: Value) -> &'static str {
+        match v {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) | Value::Float(_) => "number",
+            Value::Ref(index) => match self.get(index) {
+                Some(Object::Str(_)) => "string",
+                Some(Object::List(_)) => "list",
+                Some(Object::Num(_)) => "number",
+                None => "invalid reference",
+            },
+        }
+    }
+
+    fn wrong_type(&self, expected: &'static str, v: Value) -> Error {
+        match v {
+            Value::Ref(index) if self.get(index).is_none() => Error::InvalidRef(index),
+            _ => Error::WrongType {
+                expected,
+                found: self.type_name(v),
+            },
+        }
+    }
+
+    /// A small int if `n` fits one, else a number on the heap
+    pub fn number(&mut self, n: Num) -> Value {
+        if n.is_integer() {
+            if let Some(v) = n.to_integer().to_i64().and_then(Value::int) {
+                return v;
+            }
+        }
+        self.alloc(Object::Num(n))
+    }
+
+    /// Parse an integer, a ratio such as `1/3`, or a float such as `1.5`
+    pub fn parse_number(&mut self, s: &str) -> Option<Value> {
+        let s = s.trim();
+        if let Some((numer, denom)) = s.split_once('/') {
+            let (numer, denom) = (numer.parse::<BigInt>().ok()?, denom.parse::<BigInt>().ok()?);
+            if denom.is_zero() {
+                return None;
+            }
+            return Some(self.number(Num::new(numer, denom)));
+        }
+        match s.parse::<BigInt>() {
+            Ok(i) => Some(self.number(Num::from_integer(i))),
+            Err(_) => s.parse::<f64>().ok().map(Value::Float),
+        }
+    }
+
+    /// The exact value of a small int or big number
+    fn exact(&self, v: Value) -> Result<Num, Error> {
+        match (v, self.object(v)?) {
+            (Value::Int(i), _) => Ok(Num::from_integer(BigInt::from(i))),
+            (_, Some(Object::Num(n))) => Ok(n.clone()),
+            _ => Err(self.wrong_type("a number", v)),
+        }
+    }
+
+    fn float(&self, v: Value) -> Result<f64, Error> {
+        match v {
+            Value::Float(f) => Ok(f),
+            v => {
+                let n = self.exact(v)?;
+                Ok(n.numer().to_f64().unwrap_or(f64::NAN) / n.denom().to_f64().unwrap_or(f64::NAN))
+            }
+        }
+    }
+
+    /// `a op b`
+    pub fn arith(&mut self, op: Arith, a: Value, b: Value) -> Result<Value, Error> {
+        if let (Value::Int(x), Value::Int(y)) = (a, b) {
+            if y == 0 && (op == Arith::Div || op == Arith::Mod) {
+                return Err(Error::DivideByZero);
+            }
+            // small ints are 48 bits, so none of these overflow an i64
+            let result = match op {
+                Arith::Add => Some(x + y),
+                Arith::Sub => Some(x - y),
+                Arith::Mul => x.checked_mul(y),
+                Arith::Div if x % y == 0 => Some(x / y),
+                Arith::Div => None,
+                Arith::Mod => {
+                    let r = x % y;
+                    Some(if r != 0 && (r < 0) != (y < 0) {
+                        r + y
+                    } else {
+                        r
+                    })
+                }
+            };
+            if let Some(v) = result.and_then(Value::int) {
+                return Ok(v);
+            }
+        }
+        if matches!(a, Value::Float(_)) || matches!(b, Value::Float(_)) {
+            let (x, y) = (self.float(a)?, self.float(b)?);
+            return Ok(Value::Float(match op {
+                Arith::Add => x + y,
+                Arith::Sub => x - y,
+                Arith::Mul => x * y,
+                Arith::Div => x / y,
+                Arith::Mod => x - y * (x / y).floor(),
+            }));
+        }
+        let (x, y) = (self.exact(a)?, self.exact(b)?);
+        if y.is_zero() && (op == Arith::Div || op == Arith::Mod) {
+            return Err(Error::DivideByZero);
+        }
+        let result = match op {
+            Arith::Add => x + y,
+            Arith::Sub => x - y,
+            Arith::Mul => x * y,
+            Arith::Div => x / y,
+            Arith::Mod => &x - &y * (&x / &y).floor(),
+        };
+        Ok(self.number(result))
+    }
+
+    pub fn neg(&mut self, a: Value) -> Result<Value, Error> {
+        match a {
+            Value::Float(f) => Ok(Value::Float(-f)),
+            Value::Int(i) => match Value::int(-i) {
+                Some(v) => Ok(v),
+                None => Ok(self.number(-self.exact(a)?)),
+            },
+            _ => {
+                let n = -self.exact(a)?;
+                Ok(self.number(n))
+            }
+        }
+    }
+
+    /// Compare two numbers. `None` if either is NaN.
+    pub fn compare(&self, a: Value, b: Value) -> Result<Option<Ordering>, Error> {
+        match (a, b) {
+            (Value::Int(x), Value::Int(y)) => Ok(Some(x.cmp(&y))),
+            (Value::Float(_), _) | (_, Value::Float(_)) => {
+                Ok(self.float(a)?.partial_cmp(&self.float(b)?))
+            }
+            _ => Ok(Some(self.exact(a)?.cmp(&self.exact(b)?))),
+        }
+    }
+
+    /// Whether two values are equal: numbers by value, strings and lists
+    /// by contents, everything else by identity
+    pub fn equal(&self, a: Value, b: Value) -> Result<bool, Error> {
+        if a.to_bits() == b.to_bits() && !matches!(a, Value::Float(_)) {
+            return Ok(true);
+        }
+        match (self.object(a)?, self.object(b)?) {
+            (Some(Object::Str(x)), Some(Object::Str(y))) => Ok(x == y),
+            (Some(Object::List(x)), Some(Object::List(y))) => {
+                if x.len() != y.len() {
+                    return Ok(false);
+                }
+                for (&x, &y) in x.iter().zip(y) {
+                    if !self.equal(x, y)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            _ if self.is_number(a) && self.is_number(b) => {
+                Ok(self.compare(a, b)? == Some(Ordering::Equal))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn is_number(&self, v: Value) -> bool {
+        self.type_name(v) == "number"
+    }
+
+    /// The small int `v` as an `i64`, or a big integer that fits one
+    pub fn to_i64(&self, v: Value) -> Result<i64, Error> {
+        match (v, self.object(v)?) {
+            (Value::Int(i), _) => Ok(i),
+            (_, Some(Object::Num(n))) if n.is_integer() => n
+                .to_integer()
+                .to_i64()
+                .ok_or_else(|| self.wrong_type("a 64-bit integer", v)),
+            _ => Err(self.wrong_type("an integer", v)),
+        }
+    }
+
+    /// Length of a list, or characters in a string
+    pub fn count(&self, v: Value) -> Result<usize, Error> {
+        match self.object(v)? {
+            Some(Object::List(items)) => Ok(items.len()),
+            Some(Object::Str(s)) => Ok(s.chars().count()),
+            _ => Err(self.wrong_type("a list or string", v)),
+        }
+    }
+
+    pub fn list(&self, v: Value) -> Result<&Vec<Value>, Error> {
+        match self.object(v)? {
+            Some(Object::List(items)) => Ok(items),
+            _ => Err(self.wrong_type("a list", v)),
+        }
+    }
+
+    /// Append `item` to the list `v`
+    pub fn push(&mut self, v: Value, item: Value) -> Result<(), Error> {
+        if !matches!(self.object(v)?, Some(Object::List(_))) {
+            return Err(self.wrong_type("a list", v));
+        }
+        match self.object_mut(v) {
+            Some(Object::List(items)) => items.push(item),
+            _ => unreachable!(),
+        }
+        self.bytes += mem::size_of::<Value>();
+        Ok(())
+    }
+
+    fn object_mut(&mut self, v: Value) -> Option<&mut Object> {
+        match v {
+            Value::Ref(index) => self.get_mut(index),
+            _ => None,
+        }
+    }
+
+    /// Format `v` as rok does: `readable` quotes strings, as `pr-str` and
+    /// the REPL do, otherwise they print as is, as `println` does
+    pub fn display(&self, v: Value, readable: bool) -> String {
+        match v {
+            Value::Ref(index) => match self.get(index) {
+                Some(Object::Str(s)) if readable => format!("{:?}", s),
+                Some(Object::Str(s)) => s.clone(),
+                Some(Object::List(items)) => {
+                    let items = items
+                        .iter()
+                        .map(|&v| self.display(v, true))
+                        .collect::<Vec<_>>();
+                    format!("[{}]", items.join(" "))
+                }
+                Some(Object::Num(n)) => n.to_string(),
+                None => v.to_string(),
+            },
+            v => v.to_string(),
+        }
+    }
+
+    /// Copy a tagged value into a rok value. Floats become exact numbers,
+    /// or nil if they are not finite.
+    pub fn export(&self, v: Value) -> Option<LangValue> {
+        Some(match v {
+            Value::Nil => LangValue::Nil,
+            Value::Bool(b) => LangValue::Bool(b),
+            Value::Int(i) => LangValue::int(i),
+            Value::Float(f) => Num::from_f64(f).map_or(LangValue::Nil, LangValue::Num),
+            Value::Ref(index) => match self.get(index)? {
+                Object::Str(s) => LangValue::Str(s.clone()),
+                Object::Num(n) => LangValue::Num(n.clone()),
+                Object::List(items) => {
+                    let items = items
+                        .iter()
+                        .map(|&v| self.export(v))
+                        .collect::<Option<Vec<_>>>()?;
+                    LangValue::Vector(items.into())
+                }
+            },
+        })
+    }
+
+    /// Copy a rok value onto the heap. Only nil, bools, numbers, strings,
+    /// lists and vectors have a tagged form.
+    pub fn import(&mut self, v: &LangValue) -> Option<Value> {
+        Some(match v {
+            LangValue::Nil => Value::Nil,
+            LangValue::Bool(b) => Value::Bool(*b),
+            LangValue::Num(n) => self.number(n.clone()),
+            LangValue::Str(s) => self.alloc(Object::Str(s.clone())),
+            LangValue::List(items) | LangValue::Vector(items) => {
+                let items = items
+                    .iter()
+                    .map(|v| self.import(v))
+                    .collect::<Option<Vec<_>>>()?;
+                self.alloc(Object::List(items))
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(heap: &mut Heap, s: &str) -> Value {
+        heap.alloc(Object::Str(s.to_owned()))
+    }
+
+    #[test]
+    fn promotes_and_demotes() {
+        let mut heap = Heap::new();
+        let max = Value::Int(crate::rt::value::INT_MAX);
+        let big = heap.arith(Arith::Add, max, Value::Int(1)).unwrap();
+        assert!(matches!(big, Value::Ref(_)));
+        assert_eq!(heap.display(big, true), "140737488355328");
+        assert_eq!(heap.arith(Arith::Sub, big, Value::Int(1)), Ok(max));
+        let third = heap
+            .arith(Arith::Div, Value::Int(1), Value::Int(3))
+            .unwrap();
+        assert_eq!(heap.display(third, true), "1/3");
+        let one = heap.arith(Arith::Mul, third, Value::Int(3)).unwrap();
+        assert_eq!(one, Value::Int(1));
+        assert_eq!(
+            heap.arith(Arith::Div, Value::Float(1.0), Value::Int(4)),
+            Ok(Value::Float(0.25))
+        );
+        assert_eq!(
+            heap.arith(Arith::Mod, Value::Int(-7), Value::Int(3)),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            heap.arith(Arith::Div, Value::Int(1), Value::Int(0)),
+            Err(Error::DivideByZero)
+        );
+        let s = string(&mut heap, "x");
+        assert_eq!(
+            heap.arith(Arith::Add, s, Value::Int(1)),
+            Err(Error::WrongType {
+                expected: "a number",
+                found: "string"
+            })
+        );
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut heap = Heap::new();
+        string(&mut heap, "garbage");
+        let kept = string(&mut heap, "kept");
+        let list = heap.alloc(Object::List(vec![]));
+        heap.push(list, kept).unwrap();
+        // a cycle is collected once nothing else refers to it
+        let cycle = heap.alloc(Object::List(vec![]));
+        heap.push(cycle, cycle).unwrap();
+        let garbage_size = heap.get(0).unwrap().size() + heap.get(3).unwrap().size();
+
+        let freed = heap.collect(vec![list.to_bits(), Value::Int(1).to_bits()]);
+        assert_eq!(freed, garbage_size);
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.display(list, true), "[\"kept\"]");
+        assert!(heap.get(0).is_none() && heap.get(3).is_none());
+        let stats = heap.stats();
+        assert_eq!((stats.collections, stats.objects_freed), (1, 2));
+        assert_eq!(stats.bytes_freed, freed as u64);
+
+        // freed slots are reused
+        string(&mut heap, "new");
+        assert_eq!(heap.objects.len(), 4);
+        heap.collect(vec![]);
+        assert!(heap.is_empty());
+        assert_eq!(heap.bytes(), 0);
+        assert_eq!(heap.stats().collections, 2);
+    }
+
+    #[test]
+    fn thresholds() {
+        let mut heap = Heap::with_thresholds(Thresholds {
+            initial: 200,
+            growth: 2.0,
+        });
+        let mut kept = vec![];
+        while !heap.should_collect() {
+            kept.push(string(&mut heap, "0123456789").to_bits());
+        }
+        assert!(heap.bytes() >= 200);
+        // everything survives, so the next collection waits for twice as much
+        heap.collect(kept);
+        let live = heap.bytes();
+        assert!(!heap.should_collect());
+        while heap.bytes() < 2 * live {
+            assert!(!heap.should_collect());
+            string(&mut heap, "0123456789");
+        }
+        assert!(heap.should_collect());
+    }
+}
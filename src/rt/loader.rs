@@ -0,0 +1,242 @@
+//! Validating loader for `.rokb` bytecode files, see `asm::object`.
+use crate::asm::object::{
+    self, CODE, DATA, ENTRY_SIZE, HEADER_SIZE, LINES, NATIVES, NO_FILE, SYMBOLS,
+};
+use crate::asm::{LineInfo, Program, Symbol, SymbolKind, OP_SIZE};
+use crate::errors::Result;
+use std::{fs, path};
+
+/// Decode and validate a `.rokb` file
+pub fn load(bytes: &[u8]) -> Result<Program> {
+    if bytes.len() < HEADER_SIZE + 4 || &bytes[..4] != object::MAGIC {
+        return Err(se!("not a rok bytecode file").into());
+    }
+    let (body, sum) = bytes.split_at(bytes.len() - 4);
+    if object::checksum(body) != Reader::new(sum).u32()? {
+        return Err(se!("bytecode checksum mismatch").into());
+    }
+    let mut header = Reader::new(&body[4..]);
+    let version = header.u16()?;
+    if !(object::MIN_VERSION..=object::VERSION).contains(&version) {
+        return Err(se!(
+            "unsupported bytecode version {} (expected {} to {})",
+            version,
+            object::MIN_VERSION,
+            object::VERSION
+        )
+        .into());
+    }
+    let count = header.u16()? as usize;
+    let table_end = HEADER_SIZE + ENTRY_SIZE * count;
+    if table_end > body.len() {
+        return Err(se!("truncated section table").into());
+    }
+
+    let mut program = Program::default();
+    let mut seen = vec![];
+    for _ in 0..count {
+        let kind = header.u16()?;
+        let offset = header.u32()? as usize;
+        let len = header.u32()? as usize;
+        if seen.contains(&kind) {
+            return Err(se!("duplicate section {}", kind).into());
+        }
+        seen.push(kind);
+        let contents = offset
+            .checked_add(len)
+            .filter(|&end| offset >= table_end && end <= body.len())
+            .map(|end| &body[offset..end])
+            .ok_or_else(|| se!("section {} is out of bounds", kind))?;
+        match kind {
+            CODE => {
+                if !contents.len().is_multiple_of(OP_SIZE) {
+                    return Err(se!("code size {} is not a multiple of {}", len, OP_SIZE).into());
+                }
+                program.code = contents.to_vec();
+            }
+            DATA => program.data = contents.to_vec(),
+            SYMBOLS => program.symbols = symbols(contents)?,
+            LINES => program.lines = lines(contents)?,
+            NATIVES if version >= object::NATIVES_VERSION => {
+                let mut r = Reader::new(contents);
+                program.natives = (0..r.u32()?)
+                    .map(|_| r.name())
+                    .collect::<Result<Vec<_>>>()?;
+                r.finish()?;
+            }
+            _ => return Err(se!("unknown section kind {}", kind).into()),
+        }
+    }
+    if !seen.contains(&CODE) {
+        return Err(se!("missing code section").into());
+    }
+    Ok(program)
+}
+
+/// Read and load a `.rokb` file
+pub fn load_file<P: AsRef<path::Path>>(path: P) -> Result<Program> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|e| se!("cannot read {}: {}", path.display(), e))?;
+    load(&bytes)
+}
+
+fn symbols(contents: &[u8]) -> Result<Vec<Symbol>> {
+    let mut r = Reader::new(contents);
+    let count = r.u32()?;
+    let mut symbols = vec![];
+    for _ in 0..count {
+        let kind = match r.u8()? {
+            0 => SymbolKind::Code,
+            1 => SymbolKind::Data,
+            k => return Err(se!("invalid symbol kind {}", k).into()),
+        };
+        let value = r.u64()?;
+        let name = r.name()?;
+        symbols.push(Symbol { name, kind, value });
+    }
+    r.finish()?;
+    Ok(symbols)
+}
+
+fn lines(contents: &[u8]) -> Result<Vec<LineInfo>> {
+    let mut r = Reader::new(contents);
+    let files = (0..r.u16()?)
+        .map(|_| r.name())
+        .collect::<Result<Vec<_>>>()?;
+    let count = r.u32()?;
+    let mut lines = vec![];
+    for _ in 0..count {
+        let offset = r.u32()? as usize;
+        let file = match r.u16()? {
+            NO_FILE => None,
+            i => Some(
+                files
+                    .get(i as usize)
+                    .cloned()
+                    .ok_or_else(|| se!("invalid file index {} in line info", i))?,
+            ),
+        };
+        let line = r.u32()? as usize;
+        lines.push(LineInfo { offset, file, line });
+    }
+    r.finish()?;
+    Ok(lines)
+}
+
+/// Bounds-checked big-endian reads
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.buf.len() {
+            return Err(se!("unexpected end of section").into());
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.uint(8)
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| se!("invalid name in section").into())
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.buf.is_empty() {
+            return Err(se!("{} trailing bytes in section", self.buf.len()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn program() -> Program {
+        asm::assemble(".data\nmsg: .asciz \"hi\"\n.code\nstart: SET r0 msg\nCALLN print\nJMP start")
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        assert_eq!(program.symbols.len(), 2);
+        assert_eq!(program.lines.len(), 3);
+        assert_eq!(program.natives, ["print"]);
+        assert_eq!(load(&object::write(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn rejects_corruption() {
+        let bytes = object::write(&program());
+        let mut flipped = bytes.clone();
+        flipped[HEADER_SIZE + 1] ^= 1;
+        assert!(load(&flipped).unwrap_err().to_string().contains("checksum"));
+        assert!(load(&bytes[..bytes.len() - 1]).is_err());
+        assert!(load(b"ELF\0....").is_err());
+    }
+
+    /// `bytes` with another version and a fixed checksum
+    fn with_version(mut bytes: Vec<u8>, version: u16) -> Vec<u8> {
+        bytes[4..6].copy_from_slice(&version.to_be_bytes());
+        let n = bytes.len() - 4;
+        let sum = object::checksum(&bytes[..n]);
+        bytes[n..].copy_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let bytes = with_version(object::write(&program()), 99);
+        assert_eq!(
+            load(&bytes).unwrap_err().to_string(),
+            "error: unsupported bytecode version 99 (expected 1 to 2)"
+        );
+        assert!(load(&with_version(object::write(&program()), 0)).is_err());
+    }
+
+    #[test]
+    fn reads_version_1() {
+        assert_eq!(&object::write(&program())[4..6], &[0, 2]);
+        let program = asm::assemble(".data\nmsg: .asciz \"hi\"\n.code\nSET r0 msg").unwrap();
+        let bytes = with_version(object::write(&program), 1);
+        assert_eq!(load(&bytes).unwrap(), program);
+        // natives came with version 2
+        let bytes = with_version(object::write(&self::program()), 1);
+        assert_eq!(
+            load(&bytes).unwrap_err().to_string(),
+            "error: unknown section kind 5"
+        );
+    }
+}
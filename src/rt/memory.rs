@@ -0,0 +1,145 @@
+//! Byte-addressable memory for a processor.
+//!
+//! Values wider than a byte are stored big-endian, like operands in the
+//! bytecode. Address 0 is never handed out by the allocator so it can be used
+//! as a null pointer. The top `stack_size` bytes are reserved for the stack,
+//! which grows down from the end of memory.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+pub const DEFAULT_SIZE: usize = 1 << 20;
+pub const DEFAULT_STACK_SIZE: usize = 64 << 10;
+/// Address of the first byte of a program's data section
+pub const DATA_BASE: usize = ALIGN;
+
+/// Allocations are rounded up to, and aligned on, this many bytes
+const ALIGN: usize = 8;
+
+pub struct Memory {
+    bytes: Vec<u8>,
+    /// First address available to the allocator
+    heap_start: usize,
+    /// End of the heap and lowest address of the stack
+    heap_end: usize,
+    /// Live allocations: address -> size
+    blocks: BTreeMap<usize, usize>,
+}
+impl Memory {
+    /// Zeroed memory. The zeroed pages come from the allocator as they
+    /// are, so on most systems they take no space until they are written.
+    pub fn new(size: usize, stack_size: usize) -> Self {
+        Self {
+            bytes: vec![0; size],
+            heap_start: DATA_BASE,
+            heap_end: size.saturating_sub(stack_size),
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// New memory holding `data` at `DATA_BASE`, or `None` if the data does
+    /// not fit below the stack. Unlike `reset` this only writes the data.
+    pub fn with_data(size: usize, stack_size: usize, data: &[u8]) -> Option<Self> {
+        let mut memory = Self::new(size, stack_size);
+        memory.place(data)?;
+        Some(memory)
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Clear memory and place `data` at `DATA_BASE`, with the heap after it.
+    /// Returns `None` if the data does not fit below the stack.
+    pub fn reset(&mut self, data: &[u8]) -> Option<()> {
+        if DATA_BASE.checked_add(data.len())? > self.heap_end {
+            return None;
+        }
+        self.bytes.iter_mut().for_each(|b| *b = 0);
+        self.blocks.clear();
+        self.place(data)
+    }
+
+    /// Write `data` at `DATA_BASE` and start the heap after it
+    fn place(&mut self, data: &[u8]) -> Option<()> {
+        let end = DATA_BASE.checked_add(data.len())?;
+        if end > self.heap_end {
+            return None;
+        }
+        self.bytes[DATA_BASE..end].copy_from_slice(data);
+        self.heap_start = end.div_ceil(ALIGN) * ALIGN;
+        Some(())
+    }
+
+    /// Lowest address the stack may grow down to
+    pub fn stack_base(&self) -> usize {
+        self.heap_end
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Bounds-checked byte range `addr..addr + len`
+    fn range(&self, addr: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(addr).ok()?;
+        let end = start.checked_add(len)?;
+        if end <= self.bytes.len() {
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+
+    /// The `len` bytes at `addr`
+    pub fn read_bytes(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let range = self.range(addr, len)?;
+        Some(&self.bytes[range])
+    }
+
+    /// The `len` bytes at `addr`, for writing
+    pub fn write_bytes(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        let range = self.range(addr, len)?;
+        Some(&mut self.bytes[range])
+    }
+
+    /// Read `width` bytes (1, 2, 4 or 8) at `addr`, zero-extended
+    pub fn read(&self, addr: u64, width: usize) -> Option<u64> {
+        let range = self.range(addr, width)?;
+        Some(
+            self.bytes[range]
+                .iter()
+                .fold(0, |acc, &b| (acc << 8) | b as u64),
+        )
+    }
+
+    /// Write the low `width` bytes of `val` at `addr`
+    pub fn write(&mut self, addr: u64, width: usize, val: u64) -> Option<()> {
+        let range = self.range(addr, width)?;
+        for (i, b) in self.bytes[range].iter_mut().enumerate() {
+            *b = (val >> (8 * (width - 1 - i))) as u8;
+        }
+        Some(())
+    }
+
+    /// Allocate `size` bytes, first fit. Returns `None` when no gap is large enough.
+    pub fn alloc(&mut self, size: u64) -> Option<u64> {
+        let size = usize::try_from(size).ok()?.max(1).checked_add(ALIGN - 1)? / ALIGN * ALIGN;
+        let mut start = self.heap_start;
+        for (&addr, &len) in &self.blocks {
+            if addr - start >= size {
+                break;
+            }
+            start = addr + len;
+        }
+        if start.checked_add(size)? > self.heap_end {
+            return None;
+        }
+        self.blocks.insert(start, size);
+        Some(start as u64)
+    }
+
+    /// Release the allocation starting at `addr`. Returns `None` if there is none.
+    pub fn free(&mut self, addr: u64) -> Option<()> {
+        self.blocks.remove(&usize::try_from(addr).ok()?).map(|_| ())
+    }
+}
@@ -2,124 +2,209 @@ use crate::errors::Result;
 use crate::rt::proc::Processor;
 use rustyline::error::ReadlineError;
 use std::path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub mod proc {
-    use crate::asm::Op;
-    use crate::errors::Result;
-
-    pub struct Processor {
-        registers: [u64; 64],
-        program: Vec<u8>,
-        pc: usize,
-        cond: bool,
-        // chan: std::sync::mpsc
-    }
-    impl Processor {
-        pub fn new<P: Into<Vec<u8>>>(program: P) -> Self {
-            Self {
-                registers: [0; 64],
-                program: program.into(),
-                pc: 0,
-                cond: false,
-            }
-        }
+pub mod debug;
+pub mod heap;
+pub mod loader;
+pub mod memory;
+pub mod native;
+pub mod proc;
+pub mod sched;
+pub mod sys;
+pub mod trace;
+pub mod value;
 
-        fn take_16(buf: &[u8]) -> u16 {
-            let data = (buf[0] as u16) << 8;
-            data | (buf[1] as u16)
+/// Runs a program as process 0, together with the processes it spawns
+pub struct Runtime {
+    /// Every process of the last run, by pid
+    procs: Vec<Processor>,
+    options: sched::Options,
+    timeout: Option<Duration>,
+    memory_size: usize,
+    stack_size: usize,
+    gc: heap::Thresholds,
+    natives: Arc<native::Natives>,
+}
+impl Runtime {
+    pub fn new<P: Into<crate::asm::Program>>(program: P) -> Self {
+        Self {
+            // memory is sized, and the data placed, when the run starts
+            procs: vec![Processor::unplaced(program)],
+            options: sched::Options::default(),
+            timeout: None,
+            memory_size: memory::DEFAULT_SIZE,
+            stack_size: memory::DEFAULT_STACK_SIZE,
+            gc: heap::Thresholds::default(),
+            natives: Arc::new(native::Natives::new()),
         }
+    }
 
-        fn take_32(buf: &[u8]) -> u32 {
-            let data = (Self::take_16(buf) as u32) << 16;
-            data | (Self::take_16(&buf[2..]) as u32)
-        }
+    /// Preempt a process after `n` operations
+    pub fn quantum(&mut self, n: usize) -> &mut Self {
+        self.options.quantum = n;
+        self
+    }
 
-        fn take_64(buf: &[u8]) -> u64 {
-            let data = (Self::take_32(buf) as u64) << 32;
-            data | (Self::take_32(&buf[4..]) as u64)
-        }
+    /// Run processes on a pool of `n` threads instead of the calling thread
+    pub fn threads(&mut self, n: usize) -> &mut Self {
+        self.options.threads = n;
+        self
+    }
 
-        fn exec(&mut self, op: Op, args: [u8; 6]) -> Result<()> {
-            println!("{:?}: {:?}", op, args);
-            use Op::*;
-            match op {
-                HLT => Err(se!("halt"))?,
-                IGL => Err(se!("illegal"))?,
-                Reg => println!("Registers\n{:?}", self.registers),
-                Set => {
-                    let dest = args[0] as usize;
-                    let val = (args[1] as u64) << 40
-                        | (args[2] as u64) << 32
-                        | (args[3] as u64) << 24
-                        | (args[4] as u64) << 16
-                        | (args[5] as u64);
-                    self.registers[dest] = val;
-                }
-                Add => {
-                    let a = args[0] as usize;
-                    let b = args[1] as usize;
-                    let dest = args[2] as usize;
-                    self.registers[dest] = self.registers[a] + self.registers[b];
-                }
-                Sub => (),
-            }
-            Ok(())
-        }
+    /// Stop a run after `n` operations, counted over all processes
+    pub fn max_instructions(&mut self, n: u64) -> &mut Self {
+        self.options.max_instructions = Some(n);
+        self
+    }
 
-        pub fn run_code(&mut self, program: &[u8]) -> Result<()> {
-            println!("prog: {:?}", program);
-            let mut pc = 0;
-            loop {
-                let buf = &program[pc..];
-                if buf.is_empty() {
-                    break;
-                }
-                let two = Self::take_16(&program);
-                let op = Op::from(two);
-                pc += 2;
-                let buf = &program[pc..];
-                let args = [buf[0], buf[1], buf[2], buf[3], buf[4], buf[5]];
-                self.exec(op, args)?;
-                pc += 6;
-            }
-            Ok(())
-        }
-        pub fn run_to_completion(&mut self) -> Result<()> {
-            let prog = self.program.clone();
-            self.run_code(&prog)?;
-            Ok(())
+    /// Fault a `SPAWN` while `n` processes of a run have not halted
+    pub fn max_processes(&mut self, n: usize) -> &mut Self {
+        self.options.max_processes = Some(n);
+        self
+    }
+
+    /// Stop a run that takes longer than `timeout`
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give each process `size` bytes of memory, stack included
+    pub fn memory_size(&mut self, size: usize) -> &mut Self {
+        self.memory_size = size;
+        self
+    }
+
+    /// Give each process a stack of `size` bytes
+    pub fn stack_size(&mut self, size: usize) -> &mut Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// When process heaps are collected, see `rt::heap`
+    pub fn gc_thresholds(&mut self, thresholds: heap::Thresholds) -> &mut Self {
+        self.gc = thresholds;
+        self
+    }
+
+    /// Garbage collection done by the processes of the last run
+    pub fn gc_stats(&self) -> heap::Stats {
+        let mut stats = heap::Stats::default();
+        for proc in &self.procs {
+            stats += proc.heap().stats();
         }
+        stats
     }
-}
 
-pub struct Runtime {
-    procs: Vec<Processor>,
-    code: Vec<u8>,
-}
-impl Runtime {
-    pub fn new<P: Into<Vec<u8>>>(program: P) -> Self {
-        Self {
-            procs: vec![Processor::new(program)],
-            code: vec![],
+    /// The processes of the last run, by pid. Process 0 runs the program.
+    pub fn processes(&self) -> &[Processor] {
+        &self.procs
+    }
+
+    /// Install a tracer on process 0, shared with the processes it spawns,
+    /// see `Processor::set_tracer`. `None` removes it from every process.
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn trace::Tracer + Send>>) {
+        for proc in &mut self.procs[1..] {
+            proc.set_tracer(None);
         }
+        self.procs[0].set_tracer(tracer);
     }
+
+    /// Make a Rust function callable from VM code as `CALLN name`, see
+    /// `rt::native`
+    pub fn register_native<T, F>(&mut self, name: T, func: F) -> &mut Self
+    where
+        T: Into<String>,
+        F: Fn(&mut Processor, &[u64]) -> Result<u64> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.natives).register(name, func);
+        self
+    }
+
+    /// Replace the host used by syscalls, see `rt::sys`. Processes share
+    /// the host of the process that spawned them.
+    pub fn set_host(&mut self, host: Arc<Mutex<dyn sys::Host>>) {
+        self.procs[0].set_host(host);
+    }
+
+    /// A processor for `program` with the memory, natives and collection
+    /// thresholds of this runtime's processes, to step outside a run, see
+    /// `rt::debug`
+    pub fn processor<P: Into<crate::asm::Program>>(&self, program: P) -> Result<Processor> {
+        let mut proc = Processor::with_memory(program, self.memory_size, self.stack_size)?;
+        proc.heap_mut().set_thresholds(self.gc);
+        proc.set_natives(self.natives.clone());
+        Ok(proc)
+    }
+
+    /// The code process 0 passed to the `EXIT` syscall, if any
+    pub fn exit_code(&self) -> Option<i64> {
+        self.procs[0].exit_code()
+    }
+
     pub fn run_to_completion(&mut self) -> Result<()> {
-        let mut p = &mut self.procs[0];
-        p.run_to_completion()
+        self.procs.truncate(1);
+        self.procs[0].reset();
+        self.schedule()
     }
+
+    /// Assemble `asm` and run it on process 0, keeping its registers
     pub fn run_asm(&mut self, asm: &str) -> Result<()> {
-        let asm = crate::asm::parse(&asm)?;
-        let code = crate::asm::translate(&asm)?;
-        let mut p = &mut self.procs[0];
-        p.run_code(&code)
+        let program = crate::asm::assemble(asm)?;
+        self.procs.truncate(1);
+        self.size_memory()?;
+        self.procs[0].load(program)?;
+        self.schedule()
+    }
+
+    /// Give process 0 the configured memory, if it does not have it yet
+    fn size_memory(&mut self) -> Result<()> {
+        let main = &mut self.procs[0];
+        let memory = main.memory();
+        if memory.size() != self.memory_size
+            || memory.size() - memory.stack_base() != self.stack_size
+        {
+            main.set_memory(self.memory_size, self.stack_size)?;
+        }
+        Ok(())
+    }
+
+    /// Run process 0 and everything it spawns. A limit that is exceeded
+    /// fails the run with a `Trap`, see `Trap::limit`; the processes are
+    /// left as they were for inspection.
+    fn schedule(&mut self) -> Result<()> {
+        self.size_memory()?;
+        let main = &mut self.procs[0];
+        if main.heap().thresholds() != self.gc {
+            main.heap_mut().set_thresholds(self.gc);
+        }
+        main.set_natives(self.natives.clone());
+        let main = self.procs.pop().unwrap();
+        let mut options = self.options.clone();
+        options.deadline = self.timeout.map(|t| Instant::now() + t);
+        let (procs, result) = sched::run(main, &options);
+        self.procs = procs;
+        result
     }
 }
 
 pub fn read_eval(s: &str) -> Result<()> {
-    let asm = crate::asm::parse(&s)?;
-    let code = crate::asm::translate(&asm)?;
-    let mut r = crate::rt::Runtime::new(code);
-    Ok(r.run_to_completion()?)
+    let mut r = crate::rt::Runtime::new(crate::asm::assemble(s)?);
+    r.run_to_completion()
+}
+
+/// Load and run a `.rokb` bytecode file
+pub fn run_bytecode<P: AsRef<path::Path>>(path: P) -> Result<()> {
+    let mut r = crate::rt::Runtime::new(loader::load_file(path)?);
+    r.run_to_completion()
+}
+
+/// Assemble and run a file, resolving `.include` relative to it
+pub fn run_file<P: AsRef<path::Path>>(path: P) -> Result<()> {
+    let mut r = crate::rt::Runtime::new(crate::asm::assemble_file(path)?);
+    r.run_to_completion()
 }
 
 pub struct Repl {
@@ -127,6 +212,12 @@ pub struct Repl {
     history_path: Option<path::PathBuf>,
 }
 
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Repl {
     pub fn new() -> Self {
         Self {
@@ -149,24 +240,54 @@ impl Repl {
     }
 
     pub fn run(&self) -> Result<()> {
+        let mut runtime = crate::rt::Runtime::new(vec![]);
+        self.read_lines(">>> ", |line| {
+            let before = runtime.processes()[0].heap().stats();
+            if let Err(e) = runtime.run_asm(line) {
+                println!("{}", e);
+            }
+            let gc = runtime.processes()[0].heap().stats() - before;
+            if gc.collections > 0 {
+                println!("gc: {}", gc);
+            }
+            true
+        })
+    }
+
+    /// Run the debugger on `program`, set up by `runtime`
+    pub fn debug(&self, program: crate::asm::Program, runtime: Runtime) -> Result<()> {
+        let mut debugger = debug::Debugger::new(program, runtime)?;
+        println!("{}", debugger.command("list")?);
+        self.read_lines("(debug) ", |line| {
+            if matches!(line.trim(), "quit" | "q") {
+                return false;
+            }
+            match debugger.command(line) {
+                Ok(out) => {
+                    if !out.is_empty() {
+                        println!("{}", out)
+                    }
+                }
+                Err(e) => println!("{}", e),
+            }
+            true
+        })
+    }
+
+    /// Read lines with history until end of input or until `handle` returns false
+    fn read_lines<F: FnMut(&str) -> bool>(&self, prompt: &str, mut handle: F) -> Result<()> {
         let mut rl = rustyline::Editor::<()>::new();
         if let Some(ref history_path) = self.history_path {
             rl.load_history(history_path).ok();
         }
-        let mut runtime = crate::rt::Runtime::new(vec![]);
         loop {
-            let line = rl.readline(">>> ");
+            let line = rl.readline(prompt);
             match line {
                 Ok(line) => {
                     rl.add_history_entry(line.as_ref());
-                    let res = match runtime.run_asm(&line) {
-                        Err(e) => {
-                            println!("{}", e);
-                            continue;
-                        }
-                        Ok(t) => t,
-                    };
-                    println!("{:?}", res);
+                    if !handle(&line) {
+                        break;
+                    }
                 }
                 Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => {
                     break;
@@ -0,0 +1,96 @@
+//! Native functions: Rust closures that VM code calls with `CALLN name`.
+//!
+//! A native receives the calling processor and the values of `$r1`..`$r6`,
+//! and its result is put in `$r0`. An error from a native stops the
+//! process like a fault.
+use crate::errors::Result;
+use crate::rt::proc::Processor;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Registers passed to a native function, starting at `$r1`
+pub const ARGS: usize = 6;
+
+pub type NativeFn = Arc<dyn Fn(&mut Processor, &[u64]) -> Result<u64> + Send + Sync>;
+
+/// Native functions by name
+#[derive(Clone, Default)]
+pub struct Natives {
+    funcs: HashMap<String, NativeFn>,
+}
+impl Natives {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a native function
+    pub fn register<T, F>(&mut self, name: T, func: F)
+    where
+        T: Into<String>,
+        F: Fn(&mut Processor, &[u64]) -> Result<u64> + Send + Sync + 'static,
+    {
+        self.funcs.insert(name.into(), Arc::new(func));
+    }
+
+    pub fn get(&self, name: &str) -> Option<NativeFn> {
+        self.funcs.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm;
+    use crate::rt::proc::{Fault, Trap};
+    use crate::rt::Runtime;
+
+    #[test]
+    fn call_natives() {
+        let src = "\
+.data
+word:   .word 40
+.code
+        SET r1 1
+        SET r2 2
+        SET r3 3
+        CALLN sum
+        ADD r0 r8 r9
+        SET r1 word
+        CALLN peek
+        ADD r0 r8 r1
+        CALLN sum";
+        let program = asm::assemble(src).unwrap();
+        assert_eq!(program.natives, ["sum", "peek"]);
+        assert!(asm::listing(&program).contains("CALLN peek"));
+        let mut runtime = Runtime::new(program);
+        runtime
+            .register_native("sum", |_, args| Ok(args.iter().sum()))
+            .register_native("peek", |p, args| {
+                p.memory()
+                    .read(args[0], 8)
+                    .ok_or_else(|| se!("bad address").into())
+            });
+        runtime.run_to_completion().unwrap();
+        let registers = runtime.processes()[0].registers();
+        assert_eq!(registers[9], 6);
+        assert_eq!(registers[0], 40 + 2 + 3);
+    }
+
+    #[test]
+    fn errors() {
+        let mut runtime = Runtime::new(asm::assemble("CALLN missing").unwrap());
+        let err = runtime.run_to_completion().unwrap_err();
+        let fault = &err.downcast_ref::<Trap>().unwrap().fault;
+        assert_eq!(
+            *fault,
+            Fault::UnknownNative {
+                pc: 0,
+                name: "missing".to_owned()
+            }
+        );
+
+        runtime.register_native("missing", |_, _| Err(se!("failed").into()));
+        let err = runtime.run_to_completion().unwrap_err();
+        assert_eq!(err.to_string(), "error: failed");
+        assert!(asm::assemble("CALLN 3").is_err());
+    }
+}
@@ -0,0 +1,1810 @@
+use crate::asm::{Op, Program, OP_SIZE};
+use crate::errors::Result;
+use crate::lang::value::Num;
+use crate::rt::heap::{self, Arith, Heap, Object};
+use crate::rt::memory::{self, Memory};
+use crate::rt::native::{self, Natives};
+use crate::rt::sched::{Node, Pid};
+use crate::rt::sys::{self, Host, Mode, StdHost};
+use crate::rt::trace::{Step, Tracer};
+use crate::rt::value::Value;
+use num::BigInt;
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// An error raised by an instruction. `pc` is the offset of the faulting operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    IllegalInstruction {
+        pc: usize,
+    },
+    InvalidJump {
+        pc: usize,
+        target: i64,
+    },
+    DivideByZero {
+        pc: usize,
+    },
+    InvalidRegister {
+        pc: usize,
+        register: u8,
+    },
+    /// `pc` does not point at a whole operation inside the program
+    InvalidPc {
+        pc: usize,
+    },
+    /// `len` bytes at `addr` are not all inside memory
+    OutOfBounds {
+        pc: usize,
+        addr: u64,
+        len: usize,
+    },
+    OutOfMemory {
+        pc: usize,
+        size: u64,
+    },
+    InvalidFree {
+        pc: usize,
+        addr: u64,
+    },
+    StackOverflow {
+        pc: usize,
+    },
+    /// `POP` on an empty stack or `RET` outside of a call
+    StackUnderflow {
+        pc: usize,
+    },
+    /// `SPAWN`, `SEND` or `RECV` on a processor run outside a `Runtime`
+    NoRuntime {
+        pc: usize,
+    },
+    /// `SEND` to a pid that was never spawned
+    NoSuchProcess {
+        pc: usize,
+        pid: u64,
+    },
+    /// `CALLN` of a native function the runtime does not have
+    UnknownNative {
+        pc: usize,
+        name: String,
+    },
+    /// `SYSCALL` with a number missing from the table in `rt::sys`
+    InvalidSyscall {
+        pc: usize,
+        number: u32,
+    },
+    /// A limit exceeded by the operation at `pc`, or by the scheduler
+    /// before running it
+    LimitExceeded {
+        pc: usize,
+        limit: Limit,
+    },
+    /// A typed operation on a value of the wrong type
+    WrongType {
+        pc: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// A tagged reference to a heap object that does not exist
+    InvalidReference {
+        pc: usize,
+        index: usize,
+    },
+    IndexOutOfBounds {
+        pc: usize,
+        index: i64,
+        len: usize,
+    },
+    /// `TNUM` of a string that is not a number
+    InvalidNumber {
+        pc: usize,
+        text: String,
+    },
+    /// `SEND` of a register a typed operation set to a reference: the
+    /// receiver has its own heap
+    SendReference {
+        pc: usize,
+        index: usize,
+    },
+}
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Fault::IllegalInstruction { pc } => {
+                write!(f, "fault: illegal instruction at {:#06x}", pc)
+            }
+            Fault::InvalidJump { pc, target } => {
+                write!(f, "fault: invalid jump target {} at {:#06x}", target, pc)
+            }
+            Fault::DivideByZero { pc } => write!(f, "fault: divide by zero at {:#06x}", pc),
+            Fault::InvalidRegister { pc, register } => {
+                write!(f, "fault: invalid register ${} at {:#06x}", register, pc)
+            }
+            Fault::InvalidPc { pc } => write!(f, "fault: pc {:#06x} is outside the program", pc),
+            Fault::OutOfBounds { pc, addr, len } => write!(
+                f,
+                "fault: {}-byte access at {:#x} out of bounds at {:#06x}",
+                len, addr, pc
+            ),
+            Fault::OutOfMemory { pc, size } => {
+                write!(
+                    f,
+                    "fault: out of memory allocating {} bytes at {:#06x}",
+                    size, pc
+                )
+            }
+            Fault::InvalidFree { pc, addr } => {
+                write!(
+                    f,
+                    "fault: free of unallocated address {:#x} at {:#06x}",
+                    addr, pc
+                )
+            }
+            Fault::StackOverflow { pc } => write!(f, "fault: stack overflow at {:#06x}", pc),
+            Fault::StackUnderflow { pc } => write!(f, "fault: stack underflow at {:#06x}", pc),
+            Fault::NoRuntime { pc } => {
+                write!(f, "fault: processes need a runtime at {:#06x}", pc)
+            }
+            Fault::NoSuchProcess { pc, pid } => {
+                write!(f, "fault: no process {} at {:#06x}", pid, pc)
+            }
+            Fault::UnknownNative { pc, ref name } => {
+                write!(f, "fault: unknown native function {} at {:#06x}", name, pc)
+            }
+            Fault::InvalidSyscall { pc, number } => {
+                write!(f, "fault: invalid syscall {} at {:#06x}", number, pc)
+            }
+            Fault::LimitExceeded { pc, limit } => {
+                write!(f, "fault: {} exceeded at {:#06x}", limit, pc)
+            }
+            Fault::WrongType {
+                pc,
+                expected,
+                found,
+            } => write!(
+                f,
+                "fault: expected {}, found {} at {:#06x}",
+                expected, found, pc
+            ),
+            Fault::InvalidReference { pc, index } => {
+                write!(f, "fault: invalid reference #{} at {:#06x}", index, pc)
+            }
+            Fault::IndexOutOfBounds { pc, index, len } => write!(
+                f,
+                "fault: index {} out of bounds for length {} at {:#06x}",
+                index, len, pc
+            ),
+            Fault::InvalidNumber { pc, ref text } => {
+                write!(f, "fault: invalid number {:?} at {:#06x}", text, pc)
+            }
+            Fault::SendReference { pc, index } => write!(
+                f,
+                "fault: cannot send reference #{} to another process at {:#06x}",
+                index, pc
+            ),
+        }
+    }
+}
+impl std::error::Error for Fault {}
+
+/// A resource limit of a `Runtime`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Operations executed by all processes of a run
+    Instructions,
+    /// Wall-clock time of a run
+    Time,
+    /// Memory of a process, exceeded when an allocation fails or its heap
+    /// objects outgrow it
+    Memory,
+    /// Stack of a process
+    Stack,
+    /// Processes of a run that have not halted
+    Processes,
+}
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Limit::Instructions => "instruction limit",
+            Limit::Time => "time limit",
+            Limit::Memory => "memory limit",
+            Limit::Stack => "stack limit",
+            Limit::Processes => "process limit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A fault together with the return addresses of the calls active when it
+/// was raised, innermost first
+#[derive(Debug)]
+pub struct Trap {
+    pub fault: Fault,
+    pub backtrace: Vec<usize>,
+    /// The faulting process
+    pub pid: Pid,
+}
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // deep recursion would otherwise bury the fault
+        const SHOWN: usize = 16;
+        if self.pid != 0 {
+            write!(f, "process {}: ", self.pid)?;
+        }
+        write!(f, "{}", self.fault)?;
+        for addr in self.backtrace.iter().take(SHOWN) {
+            write!(f, "\n  returning to {:#06x}", addr)?;
+        }
+        if self.backtrace.len() > SHOWN {
+            write!(f, "\n  ... {} more", self.backtrace.len() - SHOWN)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for Trap {}
+impl Trap {
+    /// The limit that was exceeded, if the fault was caused by one
+    pub fn limit(&self) -> Option<Limit> {
+        match self.fault {
+            Fault::LimitExceeded { limit, .. } => Some(limit),
+            Fault::OutOfMemory { .. } => Some(Limit::Memory),
+            Fault::StackOverflow { .. } => Some(Limit::Stack),
+            _ => None,
+        }
+    }
+}
+
+pub const REGISTERS: usize = 64;
+
+/// Whether a processor can execute further operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Waiting in `RECV` for a message. The next step retries the `RECV`.
+    Blocked,
+    /// Stopped by `HLT` or by running off the end of the program
+    Halted,
+}
+
+pub struct Processor {
+    registers: [u64; REGISTERS],
+    /// Registers a typed operation last set to a reference, one bit each
+    refs: u64,
+    program: Vec<u8>,
+    /// Offset of the next operation to fetch
+    pc: usize,
+    cond: bool,
+    state: State,
+    memory: Memory,
+    /// Objects that tagged values refer to
+    heap: Heap,
+    /// Stack pointer: address of the top of the stack, growing down
+    sp: usize,
+    /// Frame pointer: address of the saved frame pointer of the current call
+    fp: usize,
+    /// Shared with the processes this one spawns
+    tracer: Option<Arc<Mutex<Box<dyn Tracer + Send>>>>,
+    /// Data section of the loaded program, for the memory of spawned processes
+    data: Vec<u8>,
+    pid: Pid,
+    /// Operations executed since the last reset
+    executed: u64,
+    /// Set by the `EXIT` syscall
+    exit_code: Option<u64>,
+    /// Shared with the processes this one spawns
+    host: Arc<Mutex<dyn Host>>,
+    /// Native functions called by `CALLN`, by index, from the program
+    native_names: Vec<String>,
+    natives: Arc<Natives>,
+    /// The run this processor belongs to, while scheduled by a `Runtime`
+    node: Option<Arc<Node>>,
+}
+impl Processor {
+    pub fn new<P: Into<Program>>(program: P) -> Result<Self> {
+        Self::with_memory(program, memory::DEFAULT_SIZE, memory::DEFAULT_STACK_SIZE)
+    }
+
+    /// Create a processor with `memory_size` bytes of memory, the top
+    /// `stack_size` of which hold the stack. Fails if the program's data
+    /// does not fit below the stack.
+    pub fn with_memory<P: Into<Program>>(
+        program: P,
+        memory_size: usize,
+        stack_size: usize,
+    ) -> Result<Self> {
+        let mut p = Self::empty(Memory::new(memory_size, stack_size));
+        p.load(program)?;
+        Ok(p)
+    }
+
+    /// A processor for `program` without memory. Its data is placed by
+    /// `set_memory`, once the size of memory is known.
+    pub(crate) fn unplaced<P: Into<Program>>(program: P) -> Self {
+        let mut p = Self::empty(Memory::new(0, 0));
+        p.install(program.into());
+        p
+    }
+
+    fn empty(memory: Memory) -> Self {
+        let size = memory.size();
+        Self {
+            registers: [0; REGISTERS],
+            refs: 0,
+            program: vec![],
+            pc: 0,
+            cond: false,
+            state: State::Running,
+            memory,
+            heap: Heap::new(),
+            sp: size,
+            fp: size,
+            tracer: None,
+            data: vec![],
+            pid: 0,
+            executed: 0,
+            exit_code: None,
+            host: Arc::new(Mutex::new(StdHost::new())),
+            native_names: vec![],
+            natives: Arc::new(Natives::new()),
+            node: None,
+        }
+    }
+
+    /// Replace the program and restart from its first operation with an
+    /// empty stack. Registers are kept. Memory is kept too, unless the
+    /// program has data: then memory is cleared and the data placed at
+    /// `DATA_BASE`.
+    pub fn load<P: Into<Program>>(&mut self, program: P) -> Result<()> {
+        let program = program.into();
+        if !program.data.is_empty() && self.memory.reset(&program.data).is_none() {
+            return Err(se!(
+                "data section of {} bytes does not fit in {} bytes of memory",
+                program.data.len(),
+                self.memory.stack_base()
+            )
+            .into());
+        }
+        self.install(program);
+        Ok(())
+    }
+
+    /// Take the code and data of `program`, leaving memory alone
+    fn install(&mut self, program: Program) {
+        self.program = program.code;
+        self.native_names = program.natives;
+        if !program.data.is_empty() {
+            self.data = program.data;
+        }
+        self.reset();
+    }
+
+    /// Replace memory with `size` bytes, the top `stack_size` of which hold
+    /// the stack, and restart the program
+    pub fn set_memory(&mut self, size: usize, stack_size: usize) -> Result<()> {
+        self.memory = match Memory::with_data(size, stack_size, &self.data) {
+            Some(memory) => memory,
+            None => {
+                return Err(se!(
+                    "{} bytes of memory with a {}-byte stack leave no room for {} bytes of data",
+                    size,
+                    stack_size,
+                    self.data.len()
+                )
+                .into())
+            }
+        };
+        self.reset();
+        Ok(())
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.pc = 0;
+        self.executed = 0;
+        self.exit_code = None;
+        self.state = State::Running;
+        self.sp = self.memory.size();
+        self.fp = self.memory.size();
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn registers(&self) -> &[u64; REGISTERS] {
+        &self.registers
+    }
+
+    pub fn cond(&self) -> bool {
+        self.cond
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// For native functions to create and read objects
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn fp(&self) -> usize {
+        self.fp
+    }
+
+    /// Install a tracer to be called after every operation, or remove it
+    /// with `None`. Processes spawned from then on call the same tracer.
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer + Send>>) {
+        self.tracer = tracer.map(|tracer| Arc::new(Mutex::new(tracer)));
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// The code passed to the `EXIT` syscall, if the program called it
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code.map(|code| code as i64)
+    }
+
+    /// Replace the native functions available to `CALLN`
+    pub fn set_natives(&mut self, natives: Arc<Natives>) {
+        self.natives = natives;
+    }
+
+    /// Replace the host that syscalls use, see `rt::sys`
+    pub fn set_host(&mut self, host: Arc<Mutex<dyn Host>>) {
+        self.host = host;
+    }
+
+    /// A trap for `limit`, raised before the next operation runs
+    pub(crate) fn limit_exceeded(&self, limit: Limit) -> Trap {
+        Trap {
+            fault: Fault::LimitExceeded { pc: self.pc, limit },
+            backtrace: self.backtrace(),
+            pid: self.pid,
+        }
+    }
+
+    pub(crate) fn attach(&mut self, pid: Pid, node: Arc<Node>) {
+        self.pid = pid;
+        self.node = Some(node);
+    }
+
+    pub(crate) fn detach(&mut self) {
+        self.node = None;
+    }
+
+    /// A new process running the same program from `pc`, with a copy of
+    /// the registers and heap, and fresh memory holding the program's data
+    fn fork(&self, pc: usize) -> Self {
+        let size = self.memory.size();
+        // the data already fit in memory of this size
+        let memory = Memory::with_data(size, size - self.memory.stack_base(), &self.data).unwrap();
+        Self {
+            registers: self.registers,
+            refs: self.refs,
+            program: self.program.clone(),
+            pc,
+            cond: false,
+            state: State::Running,
+            memory,
+            heap: self.heap.fork(),
+            sp: size,
+            fp: size,
+            tracer: self.tracer.clone(),
+            data: self.data.clone(),
+            pid: 0,
+            executed: 0,
+            exit_code: None,
+            host: self.host.clone(),
+            native_names: self.native_names.clone(),
+            natives: self.natives.clone(),
+            node: None,
+        }
+    }
+
+    fn node(&self) -> Result<Arc<Node>> {
+        match self.node {
+            Some(ref node) => Ok(node.clone()),
+            None => Err(Fault::NoRuntime { pc: self.op_pc() })?,
+        }
+    }
+
+    /// Return addresses of the active calls, innermost first. Each frame
+    /// holds the caller's frame pointer at `fp` and the return address above it.
+    pub fn backtrace(&self) -> Vec<usize> {
+        let mut addrs = vec![];
+        let mut fp = self.fp;
+        while fp < self.memory.size() {
+            match (
+                self.memory.read(fp as u64, 8),
+                self.memory.read(fp as u64 + 8, 8),
+            ) {
+                (Some(saved), Some(ret)) if saved as usize > fp => {
+                    addrs.push(ret as usize);
+                    fp = saved as usize;
+                }
+                _ => break,
+            }
+        }
+        addrs
+    }
+
+    fn take_16(buf: &[u8]) -> u16 {
+        let data = (buf[0] as u16) << 8;
+        data | (buf[1] as u16)
+    }
+
+    fn take_32(buf: &[u8]) -> u32 {
+        let data = (Self::take_16(buf) as u32) << 16;
+        data | (Self::take_16(&buf[2..]) as u32)
+    }
+
+    /// Offset of the operation being executed
+    fn op_pc(&self) -> usize {
+        self.pc - OP_SIZE
+    }
+
+    fn reg(&self, r: u8) -> Result<u64> {
+        match self.registers.get(r as usize) {
+            Some(&v) => Ok(v),
+            None => Err(Fault::InvalidRegister {
+                pc: self.op_pc(),
+                register: r,
+            })?,
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, v: u64) -> Result<()> {
+        let pc = self.op_pc();
+        match self.registers.get_mut(r as usize) {
+            Some(reg) => *reg = v,
+            None => Err(Fault::InvalidRegister { pc, register: r })?,
+        }
+        self.refs &= !(1 << r);
+        Ok(())
+    }
+
+    fn value(&self, r: u8) -> Result<Value> {
+        self.reg(r).map(Value::from_bits)
+    }
+
+    fn set_value(&mut self, r: u8, v: Value) -> Result<()> {
+        self.set_reg(r, v.to_bits())?;
+        if let Value::Ref(_) = v {
+            self.refs |= 1 << r;
+        }
+        Ok(())
+    }
+
+    /// Raise the fault for a failed typed operation
+    fn typed<T>(&self, result: std::result::Result<T, heap::Error>) -> Result<T> {
+        let pc = self.op_pc();
+        result.map_err(|e| {
+            match e {
+                heap::Error::WrongType { expected, found } => Fault::WrongType {
+                    pc,
+                    expected,
+                    found,
+                },
+                heap::Error::DivideByZero => Fault::DivideByZero { pc },
+                heap::Error::InvalidRef(index) => Fault::InvalidReference { pc, index },
+            }
+            .into()
+        })
+    }
+
+    fn jump(&mut self, target: i64) -> Result<()> {
+        if target < 0
+            || target as usize > self.program.len()
+            || !(target as usize).is_multiple_of(OP_SIZE)
+        {
+            Err(Fault::InvalidJump {
+                pc: self.op_pc(),
+                target,
+            })?
+        }
+        self.pc = target as usize;
+        Ok(())
+    }
+
+    /// Jump by a signed offset from the start of the current operation
+    fn jump_relative(&mut self, offset: u32) -> Result<()> {
+        let target = self.op_pc() as i64 + offset as i32 as i64;
+        self.jump(target)
+    }
+
+    /// Signed division, faulting on a zero divisor. Overflow wraps.
+    fn divide(&self, op: Op, a: u64, b: u64) -> Result<u64> {
+        let (a, b) = (a as i64, b as i64);
+        if b == 0 {
+            Err(Fault::DivideByZero { pc: self.op_pc() })?
+        }
+        Ok(match op {
+            Op::Div => a.wrapping_div(b),
+            _ => a.wrapping_rem(b),
+        } as u64)
+    }
+
+    fn load_mem(&self, addr: u64, len: usize) -> Result<u64> {
+        match self.memory.read(addr, len) {
+            Some(v) => Ok(v),
+            None => Err(Fault::OutOfBounds {
+                pc: self.op_pc(),
+                addr,
+                len,
+            })?,
+        }
+    }
+
+    fn store_mem(&mut self, addr: u64, len: usize, val: u64) -> Result<()> {
+        if self.memory.write(addr, len, val).is_none() {
+            Err(Fault::OutOfBounds {
+                pc: self.op_pc(),
+                addr,
+                len,
+            })?
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, val: u64) -> Result<()> {
+        if self.sp < self.memory.stack_base() + 8 {
+            Err(Fault::StackOverflow { pc: self.op_pc() })?
+        }
+        self.sp -= 8;
+        self.store_mem(self.sp as u64, 8, val)
+    }
+
+    fn pop(&mut self) -> Result<u64> {
+        if self.sp + 8 > self.memory.size() {
+            Err(Fault::StackUnderflow { pc: self.op_pc() })?
+        }
+        let val = self.load_mem(self.sp as u64, 8)?;
+        self.sp += 8;
+        Ok(val)
+    }
+
+    /// Execute one operation. `self.pc` already points at the next operation.
+    fn exec(&mut self, op: Op, args: [u8; 6]) -> Result<()> {
+        use Op::*;
+        match op {
+            HLT => self.state = State::Halted,
+            IGL => Err(Fault::IllegalInstruction { pc: self.op_pc() })?,
+            Reg => {
+                let text = format!("Registers\n{:?}\n", self.registers);
+                let host = self.host.clone();
+                let mut host = host.lock().unwrap_or_else(|e| e.into_inner());
+                // REG has no result to report a failed write in
+                host.write(sys::STDOUT, text.as_bytes()).ok();
+            }
+            // SET sign-extends its 32-bit immediate, SETHI replaces the high half
+            Set => {
+                let val = Self::take_32(&args[2..]) as i32 as i64 as u64;
+                self.set_reg(args[0], val)?;
+            }
+            SetHi => {
+                let hi = (Self::take_32(&args[2..]) as u64) << 32;
+                let lo = self.reg(args[0])? & 0xffff_ffff;
+                self.set_reg(args[0], hi | lo)?;
+            }
+            // arithmetic wraps on overflow
+            Add | Sub | Mul | Div | Mod | And | Or | Xor | Shl | Shr => {
+                let a = self.reg(args[0])?;
+                let b = self.reg(args[1])?;
+                let val = match op {
+                    Add => a.wrapping_add(b),
+                    Sub => a.wrapping_sub(b),
+                    Mul => a.wrapping_mul(b),
+                    Div | Mod => self.divide(op, a, b)?,
+                    And => a & b,
+                    Or => a | b,
+                    Xor => a ^ b,
+                    // shift amounts are taken mod 64, SHR is a logical shift
+                    Shl => a.wrapping_shl(b as u32),
+                    _ => a.wrapping_shr(b as u32),
+                };
+                self.set_reg(args[2], val)?;
+            }
+            Not => self.set_reg(args[1], !self.reg(args[0])?)?,
+            Neg => self.set_reg(args[1], (self.reg(args[0])? as i64).wrapping_neg() as u64)?,
+            Inc => self.set_reg(args[0], self.reg(args[0])?.wrapping_add(1))?,
+            Dec => self.set_reg(args[0], self.reg(args[0])?.wrapping_sub(1))?,
+            Jmp => self.jump(Self::take_32(&args[2..]) as i64)?,
+            Jeq => {
+                if self.cond {
+                    self.jump(Self::take_32(&args[2..]) as i64)?
+                }
+            }
+            Jne => {
+                if !self.cond {
+                    self.jump(Self::take_32(&args[2..]) as i64)?
+                }
+            }
+            Jmpr => self.jump_relative(Self::take_32(&args[2..]))?,
+            Jeqr => {
+                if self.cond {
+                    self.jump_relative(Self::take_32(&args[2..]))?
+                }
+            }
+            Jner => {
+                if !self.cond {
+                    self.jump_relative(Self::take_32(&args[2..]))?
+                }
+            }
+            // comparisons are signed
+            Eq | Neq | Gt | Lt | Gte | Lte => {
+                let a = self.reg(args[0])? as i64;
+                let b = self.reg(args[1])? as i64;
+                self.cond = match op {
+                    Eq => a == b,
+                    Neq => a != b,
+                    Gt => a > b,
+                    Lt => a < b,
+                    Gte => a >= b,
+                    _ => a <= b,
+                };
+            }
+            Cmov => {
+                if self.cond {
+                    self.set_reg(args[1], self.reg(args[0])?)?;
+                }
+            }
+            // LOADn addr dest, zero-extending
+            Load8 | Load16 | Load32 | Load64 => {
+                let len = width(op);
+                let val = self.load_mem(self.reg(args[0])?, len)?;
+                self.set_reg(args[1], val)?;
+            }
+            // STOREn src addr, truncating
+            Store8 | Store16 | Store32 | Store64 => {
+                let len = width(op);
+                let val = self.reg(args[0])?;
+                self.store_mem(self.reg(args[1])?, len, val)?;
+            }
+            Alloc => {
+                let size = self.reg(args[0])?;
+                let addr = match self.memory.alloc(size) {
+                    Some(addr) => addr,
+                    None => Err(Fault::OutOfMemory {
+                        pc: self.op_pc(),
+                        size,
+                    })?,
+                };
+                self.set_reg(args[1], addr)?;
+            }
+            Push => self.push(self.reg(args[0])?)?,
+            Pop => {
+                let val = self.pop()?;
+                self.set_reg(args[0], val)?;
+            }
+            // CALL pushes the return address and the caller's frame pointer
+            Call => {
+                self.push(self.pc as u64)?;
+                self.push(self.fp as u64)?;
+                self.fp = self.sp;
+                self.jump(Self::take_32(&args[2..]) as i64)?
+            }
+            // RET discards the callee's frame, including anything it left pushed
+            Ret => {
+                if self.fp >= self.memory.size() {
+                    Err(Fault::StackUnderflow { pc: self.op_pc() })?
+                }
+                self.sp = self.fp;
+                self.fp = self.pop()? as usize;
+                let ret = self.pop()?;
+                self.jump(ret as i64)?
+            }
+            Ldsp => self.set_reg(args[0], self.sp as u64)?,
+            Ldfp => self.set_reg(args[0], self.fp as u64)?,
+            // SPAWN dest target
+            Spawn => {
+                let node = self.node()?;
+                let pc = self.pc;
+                self.jump(Self::take_32(&args[2..]) as i64)?;
+                let child = self.fork(self.pc);
+                self.pc = pc;
+                let pid = match node.spawn(child) {
+                    Some(pid) => pid,
+                    None => Err(Fault::LimitExceeded {
+                        pc: self.op_pc(),
+                        limit: Limit::Processes,
+                    })?,
+                };
+                self.set_reg(args[0], pid)?;
+            }
+            // SEND pid val
+            Send => {
+                let (pid, val) = (self.reg(args[0])?, self.reg(args[1])?);
+                // raw words that happen to look like references are sent as they are
+                if self.refs & (1 << args[1]) != 0 {
+                    if let Value::Ref(index) = Value::from_bits(val) {
+                        Err(Fault::SendReference {
+                            pc: self.op_pc(),
+                            index,
+                        })?
+                    }
+                }
+                if !self.node()?.send(pid, val) {
+                    Err(Fault::NoSuchProcess {
+                        pc: self.op_pc(),
+                        pid,
+                    })?
+                }
+            }
+            Recv => match self.node()?.receive(self.pid) {
+                Some(val) => self.set_reg(args[0], val)?,
+                None => {
+                    self.pc = self.op_pc();
+                    self.state = State::Blocked;
+                }
+            },
+            Pid => self.set_reg(args[0], self.pid)?,
+            Calln => {
+                let pc = self.op_pc();
+                let func = match self.native_names.get(Self::take_32(&args[2..]) as usize) {
+                    Some(name) => match self.natives.get(name) {
+                        Some(func) => func,
+                        None => Err(Fault::UnknownNative {
+                            pc,
+                            name: name.clone(),
+                        })?,
+                    },
+                    None => Err(Fault::IllegalInstruction { pc })?,
+                };
+                let mut call_args = [0; native::ARGS];
+                call_args.copy_from_slice(&self.registers[1..=native::ARGS]);
+                let result = func(self, &call_args)?;
+                self.set_reg(0, result)?;
+            }
+            Syscall => {
+                let result = self.syscall(Self::take_32(&args[2..]))?;
+                self.set_reg(0, result)?;
+            }
+            // typed arithmetic promotes instead of wrapping, see `rt::heap`
+            Tadd | Tsub | Tmul | Tdiv | Tmod => {
+                let (a, b) = (self.value(args[0])?, self.value(args[1])?);
+                let op = match op {
+                    Tadd => Arith::Add,
+                    Tsub => Arith::Sub,
+                    Tmul => Arith::Mul,
+                    Tdiv => Arith::Div,
+                    _ => Arith::Mod,
+                };
+                let result = self.heap.arith(op, a, b);
+                let v = self.typed(result)?;
+                self.set_value(args[2], v)?;
+            }
+            Tneg => {
+                let result = self.heap.neg(self.value(args[0])?);
+                let v = self.typed(result)?;
+                self.set_value(args[1], v)?;
+            }
+            Teq => {
+                let result = self.heap.equal(self.value(args[0])?, self.value(args[1])?);
+                self.cond = self.typed(result)?;
+            }
+            // comparisons with NaN are false
+            Tlt | Tgt | Tlte | Tgte => {
+                let result = self
+                    .heap
+                    .compare(self.value(args[0])?, self.value(args[1])?);
+                self.cond = match self.typed(result)? {
+                    Some(ord) => match op {
+                        Tlt => ord == Ordering::Less,
+                        Tgt => ord == Ordering::Greater,
+                        Tlte => ord != Ordering::Greater,
+                        _ => ord != Ordering::Less,
+                    },
+                    None => false,
+                };
+            }
+            Ttest => self.cond = self.value(args[0])?.is_truthy(),
+            Tbox => {
+                let n = self.reg(args[0])? as i64;
+                let v = match Value::int(n) {
+                    Some(v) => v,
+                    None => self.heap.number(Num::from_integer(BigInt::from(n))),
+                };
+                self.set_value(args[1], v)?;
+            }
+            Tunbox => {
+                let result = self.heap.to_i64(self.value(args[0])?);
+                let n = self.typed(result)?;
+                self.set_reg(args[1], n as u64)?;
+            }
+            Tnum | Tstr => {
+                let text = String::from_utf8_lossy(self.string(self.reg(args[0])?)?).into_owned();
+                let v = if op == Tstr {
+                    self.heap.alloc(Object::Str(text))
+                } else {
+                    match self.heap.parse_number(&text) {
+                        Some(v) => v,
+                        None => Err(Fault::InvalidNumber {
+                            pc: self.op_pc(),
+                            text,
+                        })?,
+                    }
+                };
+                self.set_value(args[1], v)?;
+            }
+            Tlist => {
+                let v = self.heap.alloc(Object::List(vec![]));
+                self.set_value(args[0], v)?;
+            }
+            Tpush => {
+                let (list, v) = (self.value(args[0])?, self.value(args[1])?);
+                let result = self.heap.push(list, v);
+                self.typed(result)?;
+            }
+            Tget => {
+                let (list, index) = (self.value(args[0])?, self.value(args[1])?);
+                let result = self.heap.to_i64(index);
+                let index = self.typed(result)?;
+                let result = self.heap.list(list).map(|items| {
+                    let item = usize::try_from(index).ok().and_then(|i| items.get(i));
+                    (item.copied(), items.len())
+                });
+                match self.typed(result)? {
+                    (Some(v), _) => self.set_value(args[2], v)?,
+                    (None, len) => Err(Fault::IndexOutOfBounds {
+                        pc: self.op_pc(),
+                        index,
+                        len,
+                    })?,
+                }
+            }
+            Tlen => {
+                let result = self.heap.count(self.value(args[0])?);
+                let n = self.typed(result)?;
+                self.set_value(args[1], Value::Int(n as i64))?;
+            }
+            Gc => {
+                self.collect_garbage();
+            }
+            Free => {
+                let addr = self.reg(args[0])?;
+                if self.memory.free(addr).is_none() {
+                    Err(Fault::InvalidFree {
+                        pc: self.op_pc(),
+                        addr,
+                    })?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a system call. I/O errors return -1, bad buffers fault.
+    fn syscall(&mut self, number: u32) -> Result<u64> {
+        let [a, b, c] = [self.registers[1], self.registers[2], self.registers[3]];
+        let host = self.host.clone();
+        let mut host = host.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match number {
+            sys::EXIT => {
+                self.exit_code = Some(a);
+                self.state = State::Halted;
+                Ok(0)
+            }
+            sys::PRINT_INT => host.write(sys::STDOUT, (a as i64).to_string().as_bytes()),
+            sys::PRINT_STR => host.write(sys::STDOUT, self.string(a)?),
+            sys::PRINT_VAL => {
+                let s = self.heap.display(Value::from_bits(a), b != 0);
+                host.write(sys::STDOUT, s.as_bytes())
+            }
+            // no room for even the NUL
+            sys::READ_LINE if b == 0 => Err(io::ErrorKind::InvalidInput.into()),
+            sys::READ_LINE => match host.read_line() {
+                Ok(Some(line)) => {
+                    // keep room for the NUL
+                    let len = line.len().min((b as usize).saturating_sub(1));
+                    let buf = self.bytes_mut(a, len + 1)?;
+                    buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+                    buf[len] = 0;
+                    Ok(len)
+                }
+                Ok(None) => Err(io::ErrorKind::UnexpectedEof.into()),
+                Err(e) => Err(e),
+            },
+            sys::OPEN => {
+                let path = String::from_utf8_lossy(self.string(a)?).into_owned();
+                match Mode::from_u64(b) {
+                    Some(mode) => host.open(&path, mode).map(|fd| fd as usize),
+                    None => Err(io::ErrorKind::InvalidInput.into()),
+                }
+            }
+            sys::READ => host.read(a, self.bytes_mut(b, c as usize)?),
+            sys::WRITE => host.write(a, self.bytes(b, c as usize)?),
+            sys::CLOSE => host.close(a).map(|_| 0),
+            _ => Err(Fault::InvalidSyscall {
+                pc: self.op_pc(),
+                number,
+            })?,
+        };
+        Ok(result.map_or(u64::MAX, |n| n as u64))
+    }
+
+    fn bytes(&self, addr: u64, len: usize) -> Result<&[u8]> {
+        match self.memory.read_bytes(addr, len) {
+            Some(bytes) => Ok(bytes),
+            None => Err(Fault::OutOfBounds {
+                pc: self.op_pc(),
+                addr,
+                len,
+            })?,
+        }
+    }
+
+    fn bytes_mut(&mut self, addr: u64, len: usize) -> Result<&mut [u8]> {
+        let pc = self.op_pc();
+        match self.memory.write_bytes(addr, len) {
+            Some(bytes) => Ok(bytes),
+            None => Err(Fault::OutOfBounds { pc, addr, len })?,
+        }
+    }
+
+    /// The NUL-terminated string at `addr`, without the NUL
+    fn string(&self, addr: u64) -> Result<&[u8]> {
+        // checks that `addr` is inside memory
+        self.bytes(addr, 0)?;
+        let rest = &self.memory.bytes()[addr as usize..];
+        match rest.iter().position(|&b| b == 0) {
+            Some(len) => Ok(&rest[..len]),
+            None => Err(Fault::OutOfBounds {
+                pc: self.op_pc(),
+                addr,
+                len: rest.len() + 1,
+            })?,
+        }
+    }
+
+    /// Decode the operation at `self.pc`
+    fn fetch(&self) -> Result<(Op, [u8; 6])> {
+        let buf = match self.program.get(self.pc..self.pc + OP_SIZE) {
+            Some(buf) => buf,
+            None => Err(Fault::InvalidPc { pc: self.pc })?,
+        };
+        let op = Op::from(Self::take_16(buf));
+        let args = [buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]];
+        Ok((op, args))
+    }
+
+    /// Execute a single operation. Running off the end of the program halts.
+    /// On a fault `pc` is left pointing after the faulting operation.
+    pub fn step(&mut self) -> Result<State> {
+        if self.state == State::Halted {
+            return Ok(State::Halted);
+        }
+        if self.pc == self.program.len() {
+            self.state = State::Halted;
+            return Ok(State::Halted);
+        }
+        self.state = State::Running;
+        let (op, args) = self.fetch()?;
+        let pc = self.pc;
+        self.pc += OP_SIZE;
+        self.executed += 1;
+        let result = match self.tracer.clone() {
+            Some(tracer) => {
+                let before = self.registers;
+                let result = self.exec(op, args);
+                let step = Step {
+                    pc,
+                    op,
+                    args,
+                    before: &before,
+                };
+                let mut tracer = tracer.lock().unwrap_or_else(|e| e.into_inner());
+                tracer.trace(&step, self);
+                result
+            }
+            None => self.exec(op, args),
+        };
+        let result = result.and_then(|()| self.manage_heap(pc));
+        if let Err(e) = result {
+            return Err(match e.downcast::<Fault>() {
+                Ok(fault) => Box::new(Trap {
+                    fault: *fault,
+                    backtrace: self.backtrace(),
+                    pid: self.pid,
+                }),
+                Err(e) => e,
+            });
+        }
+        Ok(self.state)
+    }
+
+    /// Collect garbage when it is due, or when the heap has outgrown
+    /// memory. Heap objects count against the memory limit: if the live
+    /// ones still take more bytes than memory, the operation at `pc` faults.
+    fn manage_heap(&mut self, pc: usize) -> Result<()> {
+        let limit = self.memory.size();
+        if self.heap.should_collect() || self.heap.bytes() > limit {
+            self.collect_garbage();
+        }
+        if self.heap.bytes() > limit {
+            Err(Fault::LimitExceeded {
+                pc,
+                limit: Limit::Memory,
+            })?
+        }
+        Ok(())
+    }
+
+    /// Free the heap objects that no register or aligned word of memory
+    /// refers to. Returns the bytes freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        let words = self
+            .memory
+            .bytes()
+            .chunks_exact(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap()));
+        self.heap
+            .collect(self.registers.iter().copied().chain(words))
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        while self.step()? == State::Running {}
+        Ok(())
+    }
+
+    pub fn run_code(&mut self, program: &[u8]) -> Result<()> {
+        self.load(program)?;
+        self.run()
+    }
+
+    /// Run the loaded program from the start
+    pub fn run_to_completion(&mut self) -> Result<()> {
+        self.reset();
+        self.run()
+    }
+}
+
+/// Access width in bytes of a load or store
+fn width(op: Op) -> usize {
+    match op {
+        Op::Load8 | Op::Store8 => 1,
+        Op::Load16 | Op::Store16 => 2,
+        Op::Load32 | Op::Store32 => 4,
+        _ => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn run(src: &str) -> Processor {
+        let mut p = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        p.run().unwrap();
+        p
+    }
+
+    #[test]
+    fn set_small_immediates() {
+        let p = run("SET 0 7\nSET 1 2147483647\nSET 2 -1\nSET 3 -2147483648");
+        assert_eq!(p.registers()[0], 7);
+        assert_eq!(p.registers()[1], i32::MAX as u64);
+        assert_eq!(p.registers()[2] as i64, -1);
+        assert_eq!(p.registers()[3] as i64, i32::MIN as i64);
+    }
+
+    #[test]
+    fn set_wide_immediates() {
+        let p = run(concat!(
+            "SET 0 4294967295\n",
+            "SET 1 -9223372036854775808\n",
+            "SET 2 18446744073709551615\n",
+            "SET 3 81985529216486895\n",
+            "SET 4 -4294967297\n",
+        ));
+        assert_eq!(p.registers()[0], u32::MAX as u64);
+        assert_eq!(p.registers()[1] as i64, i64::MIN);
+        assert_eq!(p.registers()[2], u64::MAX);
+        assert_eq!(p.registers()[3], 0x0123_4567_89ab_cdef);
+        assert_eq!(p.registers()[4] as i64, -4294967297);
+    }
+
+    #[test]
+    fn sethi_keeps_low_half() {
+        let p = run("SET 0 5\nSETHI 0 1");
+        assert_eq!(p.registers()[0], (1 << 32) | 5);
+    }
+
+    #[test]
+    fn set_after_other_operations() {
+        // operand bytes of earlier instructions must not leak into SET
+        let p = run("SET 0 3\nSET 1 4\nADD 0 1 2\nSET 3 9");
+        assert_eq!(p.registers()[2], 7);
+        assert_eq!(p.registers()[3], 9);
+    }
+
+    #[test]
+    fn labels_after_wide_set() {
+        let p = run("SET 0 4294967296\nJMP end\nSET 1 1\nend:\nSET 2 2");
+        assert_eq!(p.registers()[0], 1 << 32);
+        assert_eq!(p.registers()[1], 0);
+        assert_eq!(p.registers()[2], 2);
+    }
+
+    fn fault(src: &str) -> String {
+        let mut p = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let err = p.run().unwrap_err();
+        err.downcast_ref::<Trap>().unwrap().fault.to_string()
+    }
+
+    #[test]
+    fn backward_and_relative_jumps() {
+        // sum 1..=10 in a loop
+        let p = run(concat!(
+            "SET r1 10\n",
+            "SET r2 0\n",
+            "loop: INC r2\n",
+            "ADD r0 r2 r0\n",
+            "EQ r2 r1\n",
+            "JNE loop\n",
+        ));
+        assert_eq!(p.registers()[0], 55);
+        let p = run(concat!(
+            "JMPR 16\n",
+            "SET r0 1\n",
+            "EQ r1 r1\n",
+            "JEQR 16\n",
+            "SET r2 1\n",
+            "NEQ r1 r1\n",
+            "JNER 16\n",
+            "SET r3 1\n",
+            "HLT\n",
+        ));
+        assert_eq!(&p.registers()[..4], &[0, 0, 0, 0]);
+        // a relative loop counting down
+        let p = run("SET r0 5\nDEC r0\nINC r1\nNEQ r0 r2\nJEQR -24");
+        assert_eq!(p.registers()[1], 5);
+    }
+
+    #[test]
+    fn invalid_jumps() {
+        assert_eq!(fault("JMP 4"), "fault: invalid jump target 4 at 0x0000");
+        assert_eq!(
+            fault("JMPR 8\nJMPR -16"),
+            "fault: invalid jump target -8 at 0x0008"
+        );
+        assert_eq!(fault("JMP 64"), "fault: invalid jump target 64 at 0x0000");
+        // the end of the program is a valid target and halts
+        assert_eq!(run("JMP 16\nSET r0 1").registers()[0], 0);
+    }
+
+    #[test]
+    fn comparisons() {
+        // cond after `OP a b` for (a, b) of (1, 2), (2, 2) and (-1, 1)
+        let cases = [
+            ("EQ", [false, true, false]),
+            ("NEQ", [true, false, true]),
+            ("GT", [false, false, false]),
+            ("LT", [true, false, true]),
+            ("GTE", [false, true, false]),
+            ("LTE", [true, true, true]),
+        ];
+        for (op, expected) in cases.iter() {
+            for (i, (a, b)) in [(1, 2), (2, 2), (-1, 1)].iter().enumerate() {
+                let p = run(&format!("SET r0 {}\nSET r1 {}\n{} r0 r1", a, b, op));
+                assert_eq!(p.cond(), expected[i], "{} {} {}", op, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_move() {
+        let p = run(concat!(
+            "SET r0 7\n",
+            "EQ r0 r0\n",
+            "CMOV r0 r1\n",
+            "NEQ r0 r0\n",
+            "CMOV r0 r2\n",
+        ));
+        assert_eq!(p.registers()[1], 7);
+        assert_eq!(p.registers()[2], 0);
+        assert!(!p.cond());
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let p = run(concat!(
+            "SET r0 -1\n",
+            "SET r1 1\n",
+            "ADD r0 r1 r2\n",
+            "SET r3 -9223372036854775808\n",
+            "NEG r3 r4\n",
+            "SET r5 2\n",
+            "MUL r3 r5 r6\n",
+            "SUB r2 r1 r7\n",
+            "SET r8 -1\n",
+            "DIV r3 r8 r9\n",
+            "MOD r3 r8 r10\n",
+            "SET r11 -7\n",
+            "SET r12 2\n",
+            "DIV r11 r12 r13\n",
+            "MOD r11 r12 r14\n",
+            "INC r0\n",
+            "DEC r1\n",
+        ));
+        let r = p.registers();
+        assert_eq!(r[2], 0);
+        assert_eq!(r[4] as i64, i64::MIN);
+        assert_eq!(r[6], 0);
+        assert_eq!(r[7], u64::MAX);
+        assert_eq!(r[9] as i64, i64::MIN);
+        assert_eq!(r[10], 0);
+        assert_eq!((r[13] as i64, r[14] as i64), (-3, -1));
+        assert_eq!((r[0], r[1]), (0, 0));
+    }
+
+    #[test]
+    fn bitwise_and_shifts() {
+        let p = run(concat!(
+            "SET r0 12\n",
+            "SET r1 10\n",
+            "AND r0 r1 r2\n",
+            "OR r0 r1 r3\n",
+            "XOR r0 r1 r4\n",
+            "NOT r0 r5\n",
+            "SET r6 -1\n",
+            "SET r7 63\n",
+            "SHL r1 r7 r8\n",
+            "SHR r6 r7 r9\n",
+            // shift amounts are taken mod 64
+            "SET r7 64\n",
+            "SHL r1 r7 r10\n",
+            "SHR r6 r7 r11\n",
+            "SET r7 65\n",
+            "SHL r1 r7 r12\n",
+            "SHR r1 r7 r13\n",
+        ));
+        let r = p.registers();
+        assert_eq!((r[2], r[3], r[4]), (8, 14, 6));
+        assert_eq!(r[5], !12);
+        assert_eq!(r[8], 0);
+        // SHR is logical
+        assert_eq!(r[9], 1);
+        assert_eq!((r[10], r[11]), (10, u64::MAX));
+        assert_eq!((r[12], r[13]), (20, 5));
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        assert_eq!(
+            fault("SET r0 1\nDIV r0 r1 r2"),
+            "fault: divide by zero at 0x0008"
+        );
+        assert_eq!(
+            fault("SET r0 1\nSET r1 2\nMOD r0 r2 r3"),
+            "fault: divide by zero at 0x0010"
+        );
+    }
+
+    #[test]
+    fn single_steps() {
+        let mut p =
+            Processor::new(asm::assemble("SET r0 1\nJMP 24\nHLT\nINC r0").unwrap()).unwrap();
+        assert_eq!(p.step().unwrap(), State::Running);
+        assert_eq!((p.pc(), p.registers()[0]), (8, 1));
+        assert_eq!(p.step().unwrap(), State::Running);
+        assert_eq!(p.pc(), 24);
+        assert_eq!(p.step().unwrap(), State::Running);
+        assert_eq!(p.registers()[0], 2);
+        // running off the end halts, and stays halted
+        assert_eq!(p.step().unwrap(), State::Halted);
+        assert_eq!(p.step().unwrap(), State::Halted);
+        assert_eq!(p.executed(), 3);
+
+        let mut p = Processor::new(asm::assemble("HLT\nINC r0").unwrap()).unwrap();
+        assert_eq!(p.step().unwrap(), State::Halted);
+        assert_eq!(p.state(), State::Halted);
+        assert_eq!(p.pc(), 8);
+        assert_eq!(p.registers()[0], 0);
+    }
+
+    #[test]
+    fn truncated_program() {
+        let mut code = asm::assemble("INC r0\nINC r0").unwrap().code;
+        code.truncate(12);
+        let mut p = Processor::new(code).unwrap();
+        let err = p.run().unwrap_err();
+        assert_eq!(err.to_string(), "fault: pc 0x0008 is outside the program");
+        assert_eq!(p.registers()[0], 1);
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let p = run(concat!(
+            "SET r0 32\n",
+            "ALLOC r0 r1\n",
+            "SET r2 0x0102030405060708\n",
+            "STORE64 r2 r1\n",
+            "LOAD8 r1 r3\n",
+            "LOAD16 r1 r4\n",
+            "LOAD32 r1 r5\n",
+            "LOAD64 r1 r6\n",
+            // stores truncate, loads zero-extend
+            "SET r7 -1\n",
+            "SET r8 8\n",
+            "ADD r1 r8 r9\n",
+            "STORE8 r7 r9\n",
+            "LOAD64 r9 r10\n",
+            "STORE16 r7 r9\n",
+            "LOAD64 r9 r11\n",
+            "STORE32 r7 r9\n",
+            "LOAD64 r9 r12\n",
+            "LOAD32 r9 r13\n",
+        ));
+        let r = p.registers();
+        assert_eq!((r[3], r[4], r[5]), (0x01, 0x0102, 0x0102_0304));
+        assert_eq!(r[6], 0x0102_0304_0506_0708);
+        assert_eq!(r[10], 0xff00_0000_0000_0000);
+        assert_eq!(r[11], 0xffff_0000_0000_0000);
+        assert_eq!(r[12], 0xffff_ffff_0000_0000);
+        assert_eq!(r[13], 0xffff_ffff);
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        assert_eq!(
+            fault("SET r0 1048575\nLOAD8 r0 r1\nLOAD16 r0 r1"),
+            "fault: 2-byte access at 0xfffff out of bounds at 0x0010"
+        );
+        assert_eq!(
+            fault("SET r0 1048572\nSTORE32 r1 r0\nSTORE64 r1 r0"),
+            "fault: 8-byte access at 0xffffc out of bounds at 0x0010"
+        );
+        assert_eq!(
+            fault("SET r0 -1\nLOAD64 r0 r1"),
+            "fault: 8-byte access at 0xffffffffffffffff out of bounds at 0x0008"
+        );
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let p = run(concat!(
+            "SET r0 1\n",
+            "ALLOC r0 r1\n",
+            "SET r0 16\n",
+            "ALLOC r0 r2\n",
+            "FREE r1\n",
+            // the freed block is reused, sizes round up to 8
+            "SET r0 5\n",
+            "ALLOC r0 r3\n",
+            "ALLOC r0 r4\n",
+        ));
+        let r = p.registers();
+        assert_eq!((r[1], r[2], r[3], r[4]), (8, 16, 8, 32));
+        assert_eq!(
+            fault("SET r0 8\nALLOC r0 r1\nFREE r1\nFREE r1"),
+            "fault: free of unallocated address 0x8 at 0x0018"
+        );
+        assert_eq!(
+            fault("SET r0 8\nALLOC r0 r1\nINC r1\nFREE r1"),
+            "fault: free of unallocated address 0x9 at 0x0018"
+        );
+        let mut p = Processor::with_memory(
+            asm::assemble("SET r0 40\nALLOC r0 r1\nALLOC r0 r2").unwrap(),
+            128,
+            64,
+        )
+        .unwrap();
+        let err = p.run().unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(
+            trap.fault.to_string(),
+            "fault: out of memory allocating 40 bytes at 0x0010"
+        );
+        assert_eq!(trap.limit(), Some(Limit::Memory));
+        assert_eq!(p.registers()[1], 8);
+    }
+
+    #[test]
+    fn calls_and_frames() {
+        let p = run(concat!(
+            "SET r0 3\n",
+            "PUSH r0\n",
+            "CALL double\n",
+            "POP r2\n",
+            "HLT\n",
+            "double: LDFP r3\n",
+            "LDSP r4\n",
+            // the frame holds the caller's fp and the return address
+            "LOAD64 r3 r5\n",
+            "SET r6 8\n",
+            "ADD r3 r6 r6\n",
+            "LOAD64 r6 r7\n",
+            "ADD r0 r0 r1\n",
+            // left on the stack, discarded by RET
+            "PUSH r1\n",
+            "RET\n",
+        ));
+        let size = memory::DEFAULT_SIZE as u64;
+        let r = p.registers();
+        assert_eq!((r[1], r[2]), (6, 3));
+        assert_eq!((r[3], r[4]), (size - 24, size - 24));
+        assert_eq!((r[5], r[7]), (size, 24));
+        assert_eq!((p.sp(), p.fp()), (size as usize, size as usize));
+        assert_eq!(fault("RET"), "fault: stack underflow at 0x0000");
+        assert_eq!(
+            fault("PUSH r0\nPOP r0\nPOP r0"),
+            "fault: stack underflow at 0x0010"
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let src = "SET r0 1\nf: CALL g\nHLT\ng: PUSH r0\nCALL f";
+        let mut p = Processor::with_memory(asm::assemble(src).unwrap(), 1024, 128).unwrap();
+        let err = p.run().unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.limit(), Some(Limit::Stack));
+        // each round trip through f and g takes 40 bytes
+        assert_eq!(trap.fault.to_string(), "fault: stack overflow at 0x0008");
+        assert_eq!(trap.backtrace, vec![40, 16, 40, 16, 40, 16]);
+        assert_eq!(p.memory().stack_base(), 1024 - 128);
+        assert!(p.sp() >= p.memory().stack_base());
+    }
+
+    #[test]
+    fn backtraces() {
+        let mut p = Processor::new(
+            asm::assemble("CALL a\nHLT\na: CALL b\nRET\nb: SET r0 0\nDIV r0 r0 r0").unwrap(),
+        )
+        .unwrap();
+        let err = p.run().unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.backtrace, vec![24, 8]);
+        assert_eq!(
+            err.to_string(),
+            "fault: divide by zero at 0x0028\n  returning to 0x0018\n  returning to 0x0008"
+        );
+        assert_eq!(p.backtrace(), trap.backtrace);
+    }
+
+    #[test]
+    fn invalid_immediate() {
+        assert!(asm::assemble("SET 0 x").is_err());
+        assert!(asm::parse("SET 0 18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn data_is_placed_in_memory() {
+        let p = run(concat!(
+            ".data\n",
+            "msg: .asciz \"hi\"\n",
+            ".align 8\n",
+            "big: .word 0x0102030405060708\n",
+            ".code\n",
+            "SET r0 msg\n",
+            "LOAD8 r0 r1\n",
+            "SET r2 big\n",
+            "LOAD64 r2 r3\n",
+            "SET r4 8\n",
+            "ALLOC r4 r5\n",
+        ));
+        assert_eq!(p.registers()[1], b'h' as u64);
+        assert_eq!(p.registers()[3], 0x0102_0304_0506_0708);
+        // the heap starts after the data
+        assert_eq!(p.registers()[5], 24);
+    }
+
+    #[test]
+    fn data_must_fit_in_memory() {
+        let src =
+            ".data\nbuf: .byte 1\n.align 1048576\n.byte 2\n.code\nSET r0 1048576\nLOAD8 r0 r1";
+        let program = asm::assemble(src).unwrap();
+        let err = Processor::new(program.clone()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "error: data section of 1048569 bytes does not fit in 983040 bytes of memory"
+        );
+        // the runtime places the data once memory is sized
+        let mut runtime = crate::rt::Runtime::new(program.clone());
+        runtime.memory_size(1 << 21).run_to_completion().unwrap();
+        assert_eq!(runtime.processes()[0].registers()[1], 2);
+        let mut runtime = crate::rt::Runtime::new(program);
+        let err = runtime.run_to_completion().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("leave no room for 1048569 bytes of data"));
+    }
+
+    fn value(p: &Processor, r: usize) -> String {
+        p.heap().display(Value::from_bits(p.registers()[r]), true)
+    }
+
+    #[test]
+    fn typed_arithmetic_promotes() {
+        let p = run(concat!(
+            ".data\n",
+            "third: .asciz \"1/3\"\n",
+            "half: .asciz \"0.5\"\n",
+            ".code\n",
+            "SET r1 140737488355327\n",
+            "TBOX r1 r1\n",
+            "SET r2 1\n",
+            "TBOX r2 r2\n",
+            // past the largest small int
+            "TADD r1 r2 r3\n",
+            "TMUL r3 r3 r4\n",
+            // and back
+            "TSUB r3 r2 r5\n",
+            "TUNBOX r5 r6\n",
+            "SET r7 third\n",
+            "TNUM r7 r7\n",
+            "TADD r7 r7 r8\n",
+            "TDIV r2 r8 r9\n",
+            "SET r10 half\n",
+            "TNUM r10 r10\n",
+            "TADD r10 r7 r11\n",
+            "TMOD r1 r1 r12\n",
+        ));
+        assert_eq!(value(&p, 3), "140737488355328");
+        assert_eq!(value(&p, 4), "19807040628566084398385987584");
+        assert_eq!(
+            Value::from_bits(p.registers()[5]),
+            Value::Int(140737488355327)
+        );
+        assert_eq!(p.registers()[6], 140737488355327);
+        assert_eq!(value(&p, 8), "2/3");
+        assert_eq!(value(&p, 9), "3/2");
+        assert_eq!(
+            Value::from_bits(p.registers()[11]),
+            Value::Float(0.5 + 1.0 / 3.0)
+        );
+        assert_eq!(Value::from_bits(p.registers()[12]), Value::Int(0));
+    }
+
+    #[test]
+    fn typed_comparisons_and_lists() {
+        let p = run(concat!(
+            ".data\n",
+            "s: .asciz \"hi\"\n",
+            ".code\n",
+            "TLIST r1\n",
+            "SET r2 s\n",
+            "TSTR r2 r2\n",
+            "TPUSH r1 r2\n",
+            "TPUSH r1 r1\n",
+            "TLEN r1 r3\n",
+            "SET r4 0\n",
+            "TBOX r4 r4\n",
+            "TGET r1 r4 r5\n",
+            "SET r6 s\n",
+            "TSTR r6 r6\n",
+            "SET r7 0\n",
+            "TEQ r5 r6\n",
+            "JNE end\n",
+            "TLT r4 r3\n",
+            "JNE end\n",
+            "TTEST r4\n",
+            "JNE end\n",
+            "SET r7 1\n",
+            "end: HLT",
+        ));
+        assert_eq!(Value::from_bits(p.registers()[3]), Value::Int(2));
+        assert_eq!(value(&p, 5), "\"hi\"");
+        assert_eq!(p.registers()[7], 1);
+        assert_eq!(p.heap().len(), 3);
+    }
+
+    #[test]
+    fn typed_faults() {
+        assert_eq!(
+            fault("SET r1 1\nTBOX r1 r1\nTLIST r2\nTADD r1 r2 r3"),
+            "fault: expected a number, found list at 0x0018"
+        );
+        assert_eq!(
+            fault("SET r1 1\nTBOX r1 r1\nSET r2 0\nTBOX r2 r2\nTDIV r1 r2 r3"),
+            "fault: divide by zero at 0x0020"
+        );
+        assert_eq!(
+            fault("TLIST r1\nSET r2 0\nTBOX r2 r2\nTGET r1 r2 r3"),
+            "fault: index 0 out of bounds for length 0 at 0x0018"
+        );
+        assert_eq!(
+            fault(".data\ns: .asciz \"x1\"\n.code\nSET r1 s\nTNUM r1 r1"),
+            "fault: invalid number \"x1\" at 0x0008"
+        );
+    }
+
+    #[test]
+    fn garbage_collection_roots() {
+        let p = run(concat!(
+            ".data\n",
+            "s: .asciz \"x\"\n",
+            ".align 8\n",
+            "g: .word 0\n",
+            ".code\n",
+            "SET r1 s\n",
+            "TSTR r1 r2\n",
+            "TSTR r1 r3\n",
+            "PUSH r3\n",
+            "SET r3 0\n",
+            "TSTR r1 r4\n",
+            "SET r5 g\n",
+            "STORE64 r4 r5\n",
+            "SET r4 0\n",
+            "SET r5 8\n",
+            "ALLOC r5 r5\n",
+            "TSTR r1 r6\n",
+            "STORE64 r6 r5\n",
+            "TSTR r1 r6\n",
+            "SET r6 0\n",
+            "TSTR r1 r7\n",
+            "SET r8 0x80000\n",
+            "STORE64 r7 r8\n",
+            "TSTR r1 r7\n",
+            "SET r8 0x80011\n",
+            "STORE64 r7 r8\n",
+            "SET r7 0\n",
+            "GC\n",
+        ));
+        // in a register, on the stack, in the data, in allocated memory and
+        // anywhere else in memory, but only at an aligned address
+        assert_eq!(p.heap().len(), 5);
+        assert_eq!(p.heap().stats().collections, 1);
+        assert_eq!(p.heap().stats().objects_freed, 2);
+    }
+
+    #[test]
+    fn heap_objects_count_against_memory() {
+        let src = "TLIST r1\nloop: TPUSH r1 r1\nJMP loop";
+        let mut p = Processor::with_memory(asm::assemble(src).unwrap(), 4096, 1024).unwrap();
+        let err = p.run().unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.limit(), Some(Limit::Memory));
+        assert_eq!(
+            trap.fault.to_string(),
+            "fault: memory limit exceeded at 0x0008"
+        );
+        assert!(p.heap().bytes() > 4096);
+        // garbage does not count
+        let src = ".data\ns: .asciz \"x\"\n.code\nSET r1 s\nSET r3 1000\nloop: TSTR r1 r2\nINC r4\nLT r4 r3\nJEQ loop";
+        let mut p = Processor::with_memory(asm::assemble(src).unwrap(), 4096, 1024).unwrap();
+        p.run().unwrap();
+        assert!(p.heap().stats().collections > 0);
+    }
+
+    #[test]
+    fn collects_when_the_heap_grows() {
+        let src = ".data\ns: .asciz \"x\"\n.code\nSET r1 s\nSET r3 1000\nloop: TSTR r1 r2\nINC r4\nLT r4 r3\nJEQ loop";
+        let mut p = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        p.heap_mut().set_thresholds(heap::Thresholds {
+            initial: 1000,
+            growth: 2.0,
+        });
+        p.run().unwrap();
+        let stats = p.heap().stats();
+        assert!(stats.collections > 1, "{}", stats);
+        assert_eq!(p.heap().len() as u64 + stats.objects_freed, 1000);
+        assert!(p.heap().bytes() < 1000);
+    }
+}
@@ -0,0 +1,442 @@
+//! Preemptive scheduling of many processors with message passing.
+//!
+//! `SPAWN dest label` starts a process at `label` with a copy of the
+//! spawner's registers and a fresh memory holding the program's data, and
+//! puts its pid in `dest`. `SEND pid val` appends `val` to the mailbox of
+//! `pid`, and `RECV dest` takes the oldest message from the process's own
+//! mailbox, blocking while it is empty. `PID dest` gives a process its own
+//! pid; the first process is 0.
+//!
+//! Heap objects stay with their process: `SEND` of a register that a typed
+//! operation set to a reference faults. Any other word is sent as it is,
+//! even one with the bits of a reference.
+//!
+//! Each process runs for at most a quantum of operations before the next
+//! ready one gets its turn. The same queues serve one thread or a pool of
+//! worker threads. The instruction limit and deadline are checked before
+//! each quantum, so no process runs past either.
+//! The process limit is checked by `SPAWN`.
+use crate::errors::{Error, Result, StringError};
+use crate::rt::proc::{Limit, Processor, State, Trap};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+pub type Pid = u64;
+
+/// Operations a process runs before it is preempted
+pub const DEFAULT_QUANTUM: usize = 1000;
+
+/// How `run` schedules processes and when it stops them
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Operations a process runs before it is preempted
+    pub quantum: usize,
+    pub threads: usize,
+    /// Operations all processes together may execute
+    pub max_instructions: Option<u64>,
+    /// Processes that may be running, blocked or ready at once. A `SPAWN`
+    /// past this faults.
+    pub max_processes: Option<usize>,
+    pub deadline: Option<Instant>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            quantum: DEFAULT_QUANTUM,
+            threads: 1,
+            max_instructions: None,
+            max_processes: None,
+            deadline: None,
+        }
+    }
+}
+
+/// The state shared by the processes of one run
+pub(crate) struct Node {
+    queues: Mutex<Queues>,
+    /// Signalled when a process becomes ready or the run ends
+    wake: Condvar,
+    max_processes: Option<usize>,
+}
+
+#[derive(Default)]
+struct Queues {
+    ready: VecDeque<Processor>,
+    /// Processes blocked in `RECV`
+    waiting: HashMap<Pid, Processor>,
+    /// Mailboxes of the processes that have not halted
+    mailboxes: HashMap<Pid, VecDeque<u64>>,
+    done: Vec<Processor>,
+    /// Processes currently held by a worker
+    running: usize,
+    next_pid: Pid,
+    /// Operations executed so far, plus those granted to running quanta
+    executed: u64,
+    error: Option<Box<dyn std::error::Error + Send>>,
+}
+
+impl Node {
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        // a worker only panics on a bug, the queues are still consistent
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue a new process and return its pid, or `None` if as many
+    /// processes as `Options::max_processes` have not halted yet
+    pub(crate) fn spawn(self: &Arc<Self>, proc: Processor) -> Option<Pid> {
+        let q = self.lock();
+        if self
+            .max_processes
+            .is_some_and(|max| q.mailboxes.len() >= max)
+        {
+            return None;
+        }
+        Some(self.add(q, proc))
+    }
+
+    fn add(self: &Arc<Self>, mut q: MutexGuard<'_, Queues>, mut proc: Processor) -> Pid {
+        let pid = q.next_pid;
+        q.next_pid += 1;
+        proc.attach(pid, self.clone());
+        q.mailboxes.insert(pid, VecDeque::new());
+        q.ready.push_back(proc);
+        self.wake.notify_one();
+        pid
+    }
+
+    /// Deliver a message, waking the receiver if it is blocked. Messages to
+    /// halted processes are dropped. Returns false if `pid` never existed.
+    pub(crate) fn send(&self, pid: Pid, val: u64) -> bool {
+        let mut q = self.lock();
+        if pid >= q.next_pid {
+            return false;
+        }
+        if let Some(mailbox) = q.mailboxes.get_mut(&pid) {
+            mailbox.push_back(val);
+            if let Some(proc) = q.waiting.remove(&pid) {
+                q.ready.push_back(proc);
+                self.wake.notify_one();
+            }
+        }
+        true
+    }
+
+    /// Take the oldest message for `pid`, if any
+    pub(crate) fn receive(&self, pid: Pid) -> Option<u64> {
+        self.lock().mailboxes.get_mut(&pid)?.pop_front()
+    }
+
+    /// Take ready processes until none can run, one fails or a limit is hit
+    fn work(&self, options: &Options) {
+        let mut q = self.lock();
+        while q.error.is_none() {
+            let mut proc = match q.ready.pop_front() {
+                Some(proc) => proc,
+                None if q.running == 0 => break,
+                None => {
+                    q = self.wake.wait(q).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+            };
+            let left = options
+                .max_instructions
+                .map_or(u64::MAX, |max| max.saturating_sub(q.executed));
+            let limit = if options.deadline.is_some_and(|d| Instant::now() >= d) {
+                Some(Limit::Time)
+            } else if left == 0 {
+                Some(Limit::Instructions)
+            } else {
+                None
+            };
+            if let Some(limit) = limit {
+                q.error = Some(Box::new(proc.limit_exceeded(limit)));
+                q.ready.push_front(proc);
+                break;
+            }
+            let quantum = options.quantum.max(1).min(left as usize);
+            q.executed += quantum as u64;
+            q.running += 1;
+            drop(q);
+            let before = proc.executed();
+            let result = slice(&mut proc, quantum);
+            let unused = quantum as u64 - (proc.executed() - before);
+            q = self.lock();
+            q.executed -= unused;
+            q.running -= 1;
+            let pid = proc.pid();
+            match result {
+                Ok(State::Running) => q.ready.push_back(proc),
+                // a message may have arrived after RECV found the mailbox empty
+                Ok(State::Blocked) if q.mailboxes[&pid].is_empty() => {
+                    q.waiting.insert(pid, proc);
+                }
+                Ok(State::Blocked) => q.ready.push_back(proc),
+                Ok(State::Halted) => {
+                    q.mailboxes.remove(&pid);
+                    q.done.push(proc);
+                }
+                Err(e) => {
+                    q.error = Some(sendable(e));
+                    q.done.push(proc);
+                }
+            }
+        }
+        // let idle workers see that the run is over
+        self.wake.notify_all();
+    }
+}
+
+/// Run `proc` for up to `quantum` operations, until it blocks, halts or faults
+fn slice(proc: &mut Processor, quantum: usize) -> Result<State> {
+    let mut state = State::Running;
+    for _ in 0..quantum {
+        state = proc.step()?;
+        if state != State::Running {
+            break;
+        }
+    }
+    Ok(state)
+}
+
+/// Errors cross threads as traps or as their message
+fn sendable(e: Error) -> Box<dyn std::error::Error + Send> {
+    let e = match e.downcast::<Trap>() {
+        Ok(trap) => return trap,
+        Err(e) => e,
+    };
+    match e.downcast::<StringError>() {
+        Ok(e) => e,
+        Err(e) => Box::new(StringError(e.to_string())),
+    }
+}
+
+/// Run `main` as pid 0 together with every process it spawns. Returns all
+/// processes ordered by pid, whether or not the run succeeded.
+///
+/// The run ends when no process can continue. It fails if a process faults
+/// or exceeds a limit, or if the main process is left blocked in `RECV`.
+pub fn run(main: Processor, options: &Options) -> (Vec<Processor>, Result<()>) {
+    let node = Arc::new(Node {
+        queues: Mutex::new(Queues::default()),
+        wake: Condvar::new(),
+        max_processes: options.max_processes,
+    });
+    node.add(node.lock(), main);
+    if options.threads <= 1 {
+        node.work(options);
+    } else {
+        thread::scope(|s| {
+            for _ in 0..options.threads {
+                s.spawn(|| node.work(options));
+            }
+        });
+    }
+
+    let mut q = node.lock();
+    let mut procs = q.done.drain(..).collect::<Vec<_>>();
+    procs.extend(q.ready.drain(..));
+    procs.extend(q.waiting.drain().map(|(_, proc)| proc));
+    procs.sort_by_key(|p| p.pid());
+    let result = match q.error.take() {
+        Some(e) => Err(e as Error),
+        None if procs[0].state() == State::Blocked => {
+            let blocked = procs
+                .iter()
+                .filter(|p| p.state() == State::Blocked)
+                .map(|p| p.pid().to_string())
+                .collect::<Vec<_>>();
+            Err(se!("deadlock: blocked in RECV: {}", blocked.join(", ")).into())
+        }
+        None => Ok(()),
+    };
+    drop(q);
+    for proc in &mut procs {
+        proc.detach();
+    }
+    (procs, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// Workers each send their pid squared to the main process, which sums
+    /// the replies into r3
+    const SQUARES: &str = "\
+        PID r9
+        SET r1 1
+        SET r2 5
+        SET r3 0
+spawn:  SPAWN r4 worker
+        INC r1
+        GT r1 r2
+        JNE spawn
+        SET r1 0
+gather: RECV r5
+        ADD r3 r5 r3
+        INC r1
+        EQ r1 r2
+        JNE gather
+        HLT
+worker: PID r5
+        SET r6 0
+spin:   INC r6
+        LT r6 r2
+        JEQ spin
+        MUL r5 r5 r5
+        SEND r9 r5";
+
+    fn run_squares(quantum: usize, threads: usize) -> Vec<Processor> {
+        let main = Processor::new(asm::assemble(SQUARES).unwrap()).unwrap();
+        let options = Options {
+            quantum,
+            threads,
+            ..Options::default()
+        };
+        let (procs, result) = run(main, &options);
+        result.unwrap();
+        procs
+    }
+
+    #[test]
+    fn message_passing() {
+        for &(quantum, threads) in &[(1, 1), (3, 1), (1000, 1), (1, 4), (7, 3)] {
+            let procs = run_squares(quantum, threads);
+            assert_eq!(procs.len(), 6);
+            assert_eq!(procs[0].registers()[3], 1 + 4 + 9 + 16 + 25);
+            assert!(procs.iter().all(|p| p.state() == State::Halted));
+        }
+    }
+
+    #[test]
+    fn deadlock() {
+        let main =
+            Processor::new(asm::assemble("SPAWN r0 child\nRECV r1\nchild: RECV r1").unwrap())
+                .unwrap();
+        let (procs, result) = run(main, &Options::default());
+        assert_eq!(procs.len(), 2);
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("blocked in RECV: 0, 1"), "{}", err);
+    }
+
+    #[test]
+    fn faults_stop_the_run() {
+        let src = "SPAWN r0 child\nloop: JMP loop\nchild: DIV r1 r2 r3";
+        let options = Options {
+            quantum: 5,
+            threads: 2,
+            ..Options::default()
+        };
+        let (procs, result) = run(
+            Processor::new(asm::assemble(src).unwrap()).unwrap(),
+            &options,
+        );
+        let err = result.unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.pid, 1);
+        assert_eq!(procs.len(), 2);
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let program = asm::assemble("SET r1 1\nloop: ADD r0 r1 r0\nJMP loop").unwrap();
+        for &threads in &[1, 3] {
+            let options = Options {
+                quantum: 7,
+                threads,
+                max_instructions: Some(100),
+                ..Options::default()
+            };
+            let (procs, result) = run(Processor::new(program.clone()).unwrap(), &options);
+            let err = result.unwrap_err();
+            let trap = err.downcast_ref::<Trap>().unwrap();
+            assert_eq!(trap.limit(), Some(Limit::Instructions));
+            assert_eq!(procs[0].executed(), 100);
+            assert_eq!(procs[0].registers()[0], 50);
+        }
+        // a program that fits the budget exactly completes
+        let options = Options {
+            max_instructions: Some(3),
+            ..Options::default()
+        };
+        let main = Processor::new(asm::assemble("SET r0 1\nSET r1 2\nHLT").unwrap()).unwrap();
+        run(main, &options).1.unwrap();
+    }
+
+    #[test]
+    fn process_limit() {
+        let src = "SET r1 1\nloop: SPAWN r2 child\nINC r0\nJMP loop\nchild: RECV r3";
+        let options = Options {
+            max_processes: Some(4),
+            ..Options::default()
+        };
+        let main = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let (procs, result) = run(main, &options);
+        let err = result.unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.limit(), Some(Limit::Processes));
+        assert_eq!(trap.to_string(), "fault: process limit exceeded at 0x0008");
+        assert_eq!(procs.len(), 4);
+        assert_eq!(procs[0].registers()[0], 3);
+        // halted processes do not count
+        let src = "SET r1 10\nloop: SPAWN r2 child\nINC r0\nLT r0 r1\nJEQ loop\nHLT\nchild: HLT";
+        let options = Options {
+            quantum: 1,
+            max_processes: Some(2),
+            ..Options::default()
+        };
+        let main = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let (procs, result) = run(main, &options);
+        result.unwrap();
+        assert_eq!(procs.len(), 11);
+    }
+
+    #[test]
+    fn references_are_not_sent() {
+        let src = "PID r1\nSPAWN r2 child\nRECV r3\nHLT\nchild: TLIST r4\nSEND r1 r4";
+        let main = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let (_, result) = run(main, &Options::default());
+        let err = result.unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.pid, 1);
+        assert_eq!(
+            trap.fault.to_string(),
+            "fault: cannot send reference #0 to another process at 0x0028"
+        );
+        // small values are fine
+        let src = "PID r1\nSPAWN r2 child\nRECV r3\nHLT\nchild: SET r4 7\nTBOX r4 r4\nSEND r1 r4";
+        let main = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let (procs, result) = run(main, &Options::default());
+        result.unwrap();
+        assert_eq!(
+            crate::rt::value::Value::from_bits(procs[0].registers()[3]),
+            crate::rt::value::Value::Int(7)
+        );
+        // so are raw words, even with the bits of a live reference
+        let src = "PID r1\nSPAWN r2 child\nRECV r3\nHLT\n\
+                   child: TLIST r4\nSET r5 0xfffc000000000000\nSEND r1 r5";
+        let main = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        let (procs, result) = run(main, &Options::default());
+        result.unwrap();
+        assert_eq!(procs[0].registers()[3], 0xfffc_0000_0000_0000);
+    }
+
+    #[test]
+    fn deadline() {
+        let options = Options {
+            deadline: Some(Instant::now() + std::time::Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let main = Processor::new(asm::assemble("loop: JMP loop").unwrap()).unwrap();
+        let (procs, result) = run(main, &options);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Trap>().unwrap().limit(),
+            Some(Limit::Time)
+        );
+        assert!(procs[0].executed() > 0);
+    }
+}
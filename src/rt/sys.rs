@@ -0,0 +1,326 @@
+//! System calls. `SYSCALL n` runs entry `n` of the table below with its
+//! arguments in `$r1`..`$r3` and puts the result in `$r0`. Failed calls
+//! return -1. Strings in memory are NUL-terminated.
+//!
+//! ```text
+//! 0  EXIT        r1 code                   halt the process with an exit code
+//! 1  PRINT_INT   r1 value                  print a signed integer
+//! 2  PRINT_STR   r1 addr                   print a string
+//! 3  READ_LINE   r1 buf, r2 size           read a line from stdin without its
+//!                                          newline, -1 at end of input or if
+//!                                          size is 0
+//! 4  OPEN        r1 path, r2 mode          open a file: 0 read, 1 write, 2 append
+//! 5  READ        r1 fd, r2 buf, r3 size    read up to size bytes
+//! 6  WRITE       r1 fd, r2 buf, r3 size    write size bytes
+//! 7  CLOSE       r1 fd                     close a file
+//! 8  PRINT_VAL   r1 value, r2 readable     print a tagged value, quoting
+//!                                          strings if readable is nonzero
+//! ```
+//!
+//! Files 0, 1 and 2 are stdin, stdout and stderr. All I/O goes through a
+//! `Host` shared by the processes of a run.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+
+pub const EXIT: u32 = 0;
+pub const PRINT_INT: u32 = 1;
+pub const PRINT_STR: u32 = 2;
+pub const READ_LINE: u32 = 3;
+pub const OPEN: u32 = 4;
+pub const READ: u32 = 5;
+pub const WRITE: u32 = 6;
+pub const CLOSE: u32 = 7;
+pub const PRINT_VAL: u32 = 8;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// How `OPEN` opens a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Read,
+    /// Create or truncate
+    Write,
+    /// Create or append
+    Append,
+}
+impl Mode {
+    pub fn from_u64(mode: u64) -> Option<Self> {
+        match mode {
+            0 => Some(Mode::Read),
+            1 => Some(Mode::Write),
+            2 => Some(Mode::Append),
+            _ => None,
+        }
+    }
+}
+
+/// The outside world as seen by VM programs. Replace it to sandbox or
+/// capture a program's I/O, see `Runtime::set_host`.
+pub trait Host: Send {
+    fn read(&mut self, fd: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize>;
+    /// The next line of stdin without its line ending, or `None` at the end
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+    /// Open a file and return its descriptor
+    fn open(&mut self, path: &str, mode: Mode) -> io::Result<u64>;
+    fn close(&mut self, fd: u64) -> io::Result<()>;
+}
+
+/// The process's real stdin, stdout, stderr and file system
+pub struct StdHost {
+    files: HashMap<u64, fs::File>,
+    next_fd: u64,
+}
+impl StdHost {
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            next_fd: STDERR + 1,
+        }
+    }
+
+    fn file(&mut self, fd: u64) -> io::Result<&mut fs::File> {
+        self.files.get_mut(&fd).ok_or_else(|| bad_fd(fd))
+    }
+}
+impl Default for StdHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Host for StdHost {
+    fn read(&mut self, fd: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match fd {
+            STDIN => io::stdin().read(buf),
+            _ => self.file(fd)?.read(buf),
+        }
+    }
+
+    fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize> {
+        match fd {
+            // flushed so output interleaves with prompts and the REPL
+            STDOUT => {
+                let mut out = io::stdout();
+                out.write_all(buf)?;
+                out.flush()?;
+            }
+            STDERR => io::stderr().write_all(buf)?,
+            _ => self.file(fd)?.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(Some(line))
+    }
+
+    fn open(&mut self, path: &str, mode: Mode) -> io::Result<u64> {
+        let file = match mode {
+            Mode::Read => fs::File::open(path)?,
+            Mode::Write => fs::File::create(path)?,
+            Mode::Append => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        };
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: u64) -> io::Result<()> {
+        self.files.remove(&fd).map(|_| ()).ok_or_else(|| bad_fd(fd))
+    }
+}
+
+fn bad_fd(fd: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("bad file descriptor {}", fd),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::rt::proc::{Fault, Trap};
+    use crate::rt::Runtime;
+    use std::sync::{Arc, Mutex};
+
+    /// Reads stdin from `input` and collects stdout, without files
+    #[derive(Default)]
+    struct Capture {
+        input: Vec<String>,
+        output: Vec<u8>,
+    }
+    impl Host for Capture {
+        fn read(&mut self, _: u64, _: &mut [u8]) -> io::Result<usize> {
+            Err(bad_fd(0))
+        }
+        fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize> {
+            match fd {
+                STDOUT => self.output.write(buf),
+                _ => Err(bad_fd(fd)),
+            }
+        }
+        fn read_line(&mut self) -> io::Result<Option<String>> {
+            Ok(if self.input.is_empty() {
+                None
+            } else {
+                Some(self.input.remove(0))
+            })
+        }
+        fn open(&mut self, _: &str, _: Mode) -> io::Result<u64> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+        fn close(&mut self, fd: u64) -> io::Result<()> {
+            Err(bad_fd(fd))
+        }
+    }
+
+    fn run(src: &str, input: &[&str]) -> (Runtime, String) {
+        let host = Arc::new(Mutex::new(Capture {
+            input: input.iter().map(|s| s.to_string()).collect(),
+            output: vec![],
+        }));
+        let mut runtime = Runtime::new(asm::assemble(src).unwrap());
+        runtime.set_host(host.clone());
+        runtime.run_to_completion().unwrap();
+        let output = String::from_utf8(host.lock().unwrap().output.clone()).unwrap();
+        (runtime, output)
+    }
+
+    #[test]
+    fn print_and_exit() {
+        let src = "\
+.data
+hello: .asciz \"hello \"
+.code
+        SET r1 hello
+        SYSCALL 2
+        SET r1 -42
+        SYSCALL 1
+        SET r1 3
+        SYSCALL 0
+        SYSCALL 1";
+        let (runtime, output) = run(src, &[]);
+        assert_eq!(output, "hello -42");
+        assert_eq!(runtime.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn read_lines() {
+        let src = "\
+.data
+buf:    .byte 0, 0, 0, 0, 0
+.code
+loop:   SET r1 buf
+        SET r2 5
+        SYSCALL 3
+        SET r5 -1
+        EQ r0 r5
+        JEQ done
+        SYSCALL 2
+        JMP loop
+done:   SET r1 0
+        SYSCALL 4";
+        let (runtime, output) = run(src, &["ab", "truncated"]);
+        assert_eq!(output, "abtrun");
+        // no files with this host
+        assert_eq!(runtime.processes()[0].registers()[0], u64::MAX);
+    }
+
+    #[test]
+    fn read_line_into_an_empty_buffer() {
+        let src = "\
+.data
+buf:    .byte 7
+.code
+        SET r1 buf
+        SYSCALL 3
+        ADD r0 r8 r9
+        LOAD8 r1 r10
+        SET r2 2
+        SYSCALL 3
+        LOAD8 r1 r11";
+        let (runtime, _) = run(src, &["ab"]);
+        let r = runtime.processes()[0].registers();
+        // nothing is written or consumed
+        assert_eq!((r[9], r[10]), (u64::MAX, 7));
+        assert_eq!((r[0], r[11]), (1, b'a' as u64));
+    }
+
+    #[test]
+    fn registers_are_printed_to_the_host() {
+        let (_, output) = run("SET r0 5\nSET r63 -1\nREG", &[]);
+        let mut expected = vec![0i64; 64];
+        expected[0] = 5;
+        expected[63] = -1;
+        let expected = expected.iter().map(|&r| r as u64).collect::<Vec<_>>();
+        assert_eq!(output, format!("Registers\n{:?}\n", expected));
+    }
+
+    #[test]
+    fn files() {
+        let path = std::env::temp_dir().join(format!("rok-sys-{}", std::process::id()));
+        let src = format!(
+            "\
+.data
+path:   .asciz \"{}\"
+text:   .asciz \"data\"
+buf:    .word 0
+.code
+        SET r1 path
+        SET r2 1
+        SYSCALL 4
+        ADD r0 r8 r1
+        SET r2 text
+        SET r3 4
+        SYSCALL 6
+        SYSCALL 7
+        SET r1 path
+        SET r2 0
+        SYSCALL 4
+        ADD r0 r8 r1
+        SET r2 buf
+        SET r3 8
+        SYSCALL 5
+        ADD r0 r8 r9
+        SYSCALL 7",
+            path.display()
+        );
+        let program = asm::assemble(&src).unwrap();
+        let buf = program
+            .symbols
+            .iter()
+            .find(|s| s.name == "buf")
+            .unwrap()
+            .value;
+        let mut p = crate::rt::proc::Processor::new(program).unwrap();
+        p.run().unwrap();
+        let buf = p.memory().read_bytes(buf, 4).unwrap().to_vec();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(p.registers()[9], 4);
+        assert_eq!(p.registers()[0], 0);
+        assert_eq!(buf, b"data");
+    }
+
+    #[test]
+    fn invalid_syscall() {
+        let mut p = crate::rt::proc::Processor::new(asm::assemble("SYSCALL 99").unwrap()).unwrap();
+        let err = p.run().unwrap_err();
+        let trap = err.downcast_ref::<Trap>().unwrap();
+        assert_eq!(trap.fault, Fault::InvalidSyscall { pc: 0, number: 99 });
+    }
+}
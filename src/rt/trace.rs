@@ -0,0 +1,197 @@
+//! Hooks for observing execution. A `Processor` has no tracer by default;
+//! see `Processor::set_tracer`.
+use crate::asm::Op;
+use crate::rt::proc::{Processor, REGISTERS};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write;
+
+use std::sync::{Arc, Mutex};
+
+/// An executed operation
+pub struct Step<'a> {
+    /// Offset of the operation
+    pub pc: usize,
+    pub op: Op,
+    pub args: [u8; 6],
+    /// Registers before the operation ran
+    pub before: &'a [u64; REGISTERS],
+}
+
+/// Called after every operation a processor executes, including one that
+/// faults
+pub trait Tracer {
+    fn trace(&mut self, step: &Step<'_>, proc: &Processor);
+}
+
+/// Keeps a handle on a tracer while the processor owns it
+impl<T: Tracer> Tracer for Arc<Mutex<T>> {
+    fn trace(&mut self, step: &Step<'_>, proc: &Processor) {
+        if let Ok(mut tracer) = self.lock() {
+            tracer.trace(step, proc)
+        }
+    }
+}
+
+/// Runs several tracers in order
+impl Tracer for Vec<Box<dyn Tracer + Send>> {
+    fn trace(&mut self, step: &Step<'_>, proc: &Processor) {
+        for tracer in self {
+            tracer.trace(step, proc);
+        }
+    }
+}
+
+/// Writes one JSON object per operation with its offset, mnemonic and the
+/// registers it changed, e.g. `{"pc":8,"op":"ADD","regs":{"r2":7}}`.
+/// Operations of spawned processes also give the pid, as in
+/// `{"pid":2,"pc":8,...}`. Write errors stop the trace rather than the program.
+pub struct JsonTrace<W: Write> {
+    out: Option<W>,
+}
+impl<W: Write> JsonTrace<W> {
+    pub fn new(out: W) -> Self {
+        Self { out: Some(out) }
+    }
+}
+impl<W: Write> Tracer for JsonTrace<W> {
+    fn trace(&mut self, step: &Step<'_>, proc: &Processor) {
+        if let Some(ref mut out) = self.out {
+            if writeln!(out, "{}", json_line(step, proc)).is_err() {
+                self.out = None;
+            }
+        }
+    }
+}
+
+fn json_line(step: &Step<'_>, proc: &Processor) -> String {
+    let changed = step
+        .before
+        .iter()
+        .zip(proc.registers().iter())
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        .map(|(i, (_, after))| format!("\"r{}\":{}", i, after))
+        .collect::<Vec<_>>();
+    let mut line = String::from("{");
+    if proc.pid() != 0 {
+        write!(line, "\"pid\":{},", proc.pid()).unwrap();
+    }
+    write!(line, "\"pc\":{},\"op\":\"{}\"", step.pc, step.op).unwrap();
+    if !changed.is_empty() {
+        write!(line, ",\"regs\":{{{}}}", changed.join(",")).unwrap();
+    }
+    line.push('}');
+    line
+}
+
+/// Counts executed operations by opcode
+#[derive(Debug, Default)]
+pub struct Profile {
+    counts: HashMap<Op, u64>,
+}
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self, op: Op) -> u64 {
+        self.counts.get(&op).cloned().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+impl Tracer for Profile {
+    fn trace(&mut self, step: &Step<'_>, _: &Processor) {
+        *self.counts.entry(step.op).or_insert(0) += 1;
+    }
+}
+/// A table of counts, most executed first
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut counts = self.counts.iter().collect::<Vec<_>>();
+        counts.sort_by_key(|&(op, n)| (std::cmp::Reverse(*n), op.to_string()));
+        let total = self.total();
+        writeln!(f, "{:<8} {:>12} {:>7}", "op", "count", "%")?;
+        for (op, n) in counts {
+            let share = *n as f64 * 100.0 / total as f64;
+            writeln!(f, "{:<8} {:>12} {:>6.1}%", op.to_string(), n, share)?;
+        }
+        write!(f, "{:<8} {:>12}", "total", total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::io;
+
+    #[test]
+    fn json_lines() {
+        let out = Arc::new(Mutex::new(vec![]));
+        let mut p = Processor::new(asm::assemble("SET r0 2\nADD r0 r0 r1\nHLT").unwrap()).unwrap();
+        p.set_tracer(Some(Box::new(JsonTrace::new(Shared(out.clone())))));
+        p.run().unwrap();
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "{\"pc\":0,\"op\":\"SET\",\"regs\":{\"r0\":2}}\n",
+                "{\"pc\":8,\"op\":\"ADD\",\"regs\":{\"r1\":4}}\n",
+                "{\"pc\":16,\"op\":\"HLT\"}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn counts_operations() {
+        let profile = Arc::new(Mutex::new(Profile::new()));
+        let src = "SET r0 3\nSET r1 1\nloop: SUB r0 r1 r0\nNEQ r0 r2\nJEQ loop";
+        let mut p = Processor::new(asm::assemble(src).unwrap()).unwrap();
+        p.set_tracer(Some(Box::new(profile.clone())));
+        p.run().unwrap();
+        let profile = profile.lock().unwrap();
+        assert_eq!(profile.count(Op::Set), 2);
+        assert_eq!(profile.count(Op::Sub), 3);
+        assert_eq!(profile.count(Op::Jeq), 3);
+        assert_eq!(profile.count(Op::HLT), 0);
+        assert_eq!(profile.total(), 11);
+        assert!(profile.to_string().starts_with("op "));
+    }
+
+    #[test]
+    fn spawned_processes_share_the_tracer() {
+        let src = "SPAWN r0 child\nSPAWN r0 child\nHLT\nchild: SET r1 1\nADD r1 r1 r1";
+        let mut runtime = crate::rt::Runtime::new(asm::assemble(src).unwrap());
+        let out = Arc::new(Mutex::new(vec![]));
+        let profile = Arc::new(Mutex::new(Profile::new()));
+        let tracers: Vec<Box<dyn Tracer + Send>> = vec![
+            Box::new(JsonTrace::new(Shared(out.clone()))),
+            Box::new(profile.clone()),
+        ];
+        runtime.set_tracer(Some(Box::new(tracers)));
+        runtime.run_to_completion().unwrap();
+        assert_eq!(profile.lock().unwrap().count(Op::Add), 2);
+        assert_eq!(profile.lock().unwrap().total(), 7);
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("{\"pid\":1,\"pc\":32,\"op\":\"ADD\",\"regs\":{\"r1\":2}}\n"));
+        assert!(text.contains("{\"pid\":2,\"pc\":24,\"op\":\"SET\""));
+        assert!(text.starts_with("{\"pc\":0,\"op\":\"SPAWN\""));
+        // removing the tracer removes it from every process
+        runtime.set_tracer(None);
+        assert_eq!(Arc::strong_count(&out), 1);
+    }
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
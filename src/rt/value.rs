@@ -0,0 +1,141 @@
+//! Tagged values, the operands of the typed operations `TADD`, `TLT` and
+//! the rest.
+//!
+//! A tagged value is NaN-boxed in a 64-bit register. Every double except the
+//! negative quiet NaNs stands for itself; NaN results are stored as the
+//! positive quiet NaN. The negative quiet NaNs carry a 3-bit tag in bits
+//! 48..51 and a 48-bit payload:
+//!
+//! ```text
+//! 0xfff9 0000 0000 0000    nil
+//! 0xfffa 0000 0000 000b    bool b
+//! 0xfffb iiii iiii iiii    small int i, 48-bit two's complement
+//! 0xfffc nnnn nnnn nnnn    reference to heap object n, see `rt::heap`
+//! ```
+//!
+//! Other words, such as raw integers, read as doubles. Numbers outside the
+//! small int range and ratios live on the heap.
+use std::fmt;
+
+const TAGGED: u64 = 0xfff8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+const NIL: u64 = 1;
+const BOOL: u64 = 2;
+const INT: u64 = 3;
+const REF: u64 = 4;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+/// Smallest small int
+pub const INT_MIN: i64 = -(1 << 47);
+/// Largest small int
+pub const INT_MAX: i64 = (1 << 47) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    /// A small int, between `INT_MIN` and `INT_MAX`
+    Int(i64),
+    Float(f64),
+    /// Index of an object on the heap
+    Ref(usize),
+}
+impl Value {
+    /// A small int, if `i` is in range
+    pub fn int(i: i64) -> Option<Self> {
+        if (INT_MIN..=INT_MAX).contains(&i) {
+            Some(Value::Int(i))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bits(self) -> u64 {
+        let tagged = |tag: u64, payload: u64| TAGGED | tag << TAG_SHIFT | (payload & PAYLOAD);
+        match self {
+            Value::Float(f) if f.is_nan() => CANONICAL_NAN,
+            Value::Float(f) => f.to_bits(),
+            Value::Nil => tagged(NIL, 0),
+            Value::Bool(b) => tagged(BOOL, b as u64),
+            Value::Int(i) => tagged(INT, i as u64),
+            Value::Ref(index) => tagged(REF, index as u64),
+        }
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        if bits & TAGGED != TAGGED {
+            return Value::Float(f64::from_bits(bits));
+        }
+        let payload = bits & PAYLOAD;
+        match (bits >> TAG_SHIFT) & 7 {
+            NIL => Value::Nil,
+            BOOL => Value::Bool(payload != 0),
+            // sign-extend the payload
+            INT => Value::Int(((bits << 16) as i64) >> 16),
+            REF => Value::Ref(payload as usize),
+            _ => Value::Float(f64::from_bits(bits)),
+        }
+    }
+
+    /// Everything but `nil` and `false` is truthy
+    pub fn is_truthy(self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+impl From<u64> for Value {
+    fn from(bits: u64) -> Self {
+        Value::from_bits(bits)
+    }
+}
+impl From<Value> for u64 {
+    fn from(v: Value) -> Self {
+        v.to_bits()
+    }
+}
+/// Heap objects show as `#<ref n>`, see `Heap::display` for their contents
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Ref(index) => write!(f, "#<ref {}>", index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let values = [
+            Value::Nil,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Int(0),
+            Value::Int(-1),
+            Value::Int(INT_MIN),
+            Value::Int(INT_MAX),
+            Value::Float(0.0),
+            Value::Float(-2.5),
+            Value::Float(f64::INFINITY),
+            Value::Float(f64::NEG_INFINITY),
+            Value::Ref(0),
+            Value::Ref(12345),
+        ];
+        for &v in &values {
+            assert_eq!(Value::from_bits(v.to_bits()), v, "{:?}", v);
+        }
+        // every NaN reads back as a NaN, never as a tagged value
+        let nan = Value::from_bits(Value::Float(-f64::NAN).to_bits());
+        assert!(matches!(nan, Value::Float(f) if f.is_nan()));
+        assert_eq!(Value::int(INT_MAX + 1), None);
+        assert_eq!(Value::from_bits(0), Value::Float(0.0));
+        assert!(Value::Int(0).is_truthy());
+        assert!(!Value::Nil.is_truthy());
+    }
+}